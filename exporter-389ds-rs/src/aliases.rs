@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+
+/// Normalize metric name to the form exposed by the prometheus exporter
+fn exposed_name(name: &str) -> String {
+    name.replace('.', "_")
+}
+
+fn is_valid_prometheus_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => {}
+        _ => return false,
+    }
+
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Source metric name -> alias. Source names may be given either in the internal form
+/// (`custom_query.duration_ms`) or as exposed by the exporter (`custom_query_duration_ms`)
+#[derive(Debug, Clone, Default)]
+pub struct MetricAliases {
+    aliases: HashMap<String, String>,
+}
+
+impl MetricAliases {
+    pub fn new(aliases: &HashMap<String, String>) -> Result<Self> {
+        let mut normalized: HashMap<String, String> = HashMap::new();
+
+        for (source, alias) in aliases {
+            if !is_valid_prometheus_name(alias) {
+                return Err(anyhow!(
+                    "Invalid alias {alias:?} for metric {source:?}: not a valid prometheus metric name"
+                ));
+            }
+
+            if let Some(previous) = normalized.insert(exposed_name(source), alias.clone()) {
+                tracing::warn!(
+                    "Metric {} has multiple aliases defined ({} and {})",
+                    source,
+                    previous,
+                    alias
+                );
+            }
+        }
+
+        let mut targets: HashMap<&str, &str> = HashMap::new();
+        for (source, alias) in &normalized {
+            if let Some(other) = targets.insert(alias, source) {
+                tracing::warn!(
+                    "Metrics {} and {} are both aliased to {}",
+                    other,
+                    source,
+                    alias
+                );
+            }
+
+            if alias != source && normalized.contains_key(alias) {
+                tracing::warn!(
+                    "Alias {} of the metric {} collides with another aliased metric",
+                    alias,
+                    source
+                );
+            }
+        }
+
+        Ok(Self {
+            aliases: normalized,
+        })
+    }
+
+    /// Get the name under which metric should be registered
+    pub fn metric_name(&self, name: &str) -> Option<&str> {
+        if self.aliases.is_empty() {
+            return None;
+        }
+
+        self.aliases.get(&exposed_name(name)).map(String::as_str)
+    }
+
+    fn key(&self, key: &Key) -> Option<Key> {
+        self.metric_name(key.name())
+            .map(|alias| Key::from_parts(alias.to_string(), key.labels()))
+    }
+
+    fn key_name(&self, key_name: KeyName) -> KeyName {
+        match self.metric_name(key_name.as_str()) {
+            Some(alias) => KeyName::from(alias.to_string()),
+            None => key_name,
+        }
+    }
+
+    pub fn wrap<R: Recorder>(self, inner: R) -> AliasedRecorder<R> {
+        AliasedRecorder {
+            aliases: self,
+            inner,
+        }
+    }
+}
+
+/// Recorder renaming metrics according to the `MetricAliases` before registering them
+#[derive(Debug)]
+pub struct AliasedRecorder<R> {
+    aliases: MetricAliases,
+    inner: R,
+}
+
+impl<R: Recorder> Recorder for AliasedRecorder<R> {
+    fn describe_counter(&self, key_name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner
+            .describe_counter(self.aliases.key_name(key_name), unit, description)
+    }
+
+    fn describe_gauge(&self, key_name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner
+            .describe_gauge(self.aliases.key_name(key_name), unit, description)
+    }
+
+    fn describe_histogram(&self, key_name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner
+            .describe_histogram(self.aliases.key_name(key_name), unit, description)
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        match self.aliases.key(key) {
+            Some(key) => self.inner.register_counter(&key, metadata),
+            None => self.inner.register_counter(key, metadata),
+        }
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        match self.aliases.key(key) {
+            Some(key) => self.inner.register_gauge(&key, metadata),
+            None => self.inner.register_gauge(key, metadata),
+        }
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        match self.aliases.key(key) {
+            Some(key) => self.inner.register_histogram(&key, metadata),
+            None => self.inner.register_histogram(key, metadata),
+        }
+    }
+}
@@ -1,25 +1,66 @@
+pub mod aliases;
 pub mod monitor;
 pub mod replica;
-
-use std::{net::SocketAddr, time::Instant};
-
-use crate::monitor::{get_ldap_metrics, MetricsCommonData};
+pub mod server;
+pub mod target;
+pub mod trigger;
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use crate::aliases::MetricAliases;
+use crate::monitor::{
+    get_ldap_database_metrics, get_ldap_metrics, get_readiness, DatabaseCommonData,
+    MetricsCommonData,
+};
 use anyhow::Result;
 use clap::{ArgGroup, Parser};
-use internal::{cli::CommandConfig, query::CustomQuery, Bind, LdapConfig};
-use metrics::{counter, describe_counter, describe_gauge, gauge};
+use internal::{
+    batch::NamedFilter, cli::CommandConfig, error::ErrorKind, query::CustomQuery,
+    replica::AgreementFilter, session::LdapSession, BindMethod, LdapConfig,
+};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
 use metrics_exporter_prometheus::PrometheusBuilder;
+use metrics_util::MetricKindMask;
 use replica::{get_ldap_replica_metrics, ReplicationCommonData};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use server::{MetricsAuth, MetricsServer};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
-use tokio::select;
+use target::{TargetRecorder, TargetTracker};
+use tokio::{select, sync::watch};
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use trigger::ScrapeTrigger;
 
 #[derive(Default)]
 pub struct DsctlCommonData {
     /// DSLE of the all known healthchecks
     pub healthchecks: HashSet<internal::cli::HealthcheckEntry>,
+
+    /// Backends reported with indexes pending reindex
+    pub reindex_backends: HashSet<String>,
+}
+
+/// Labels of the `dsctl.healthcheck.error` gauge
+fn healthcheck_labels(
+    cmd_cfg: &CommandConfig,
+    healthcheck: &internal::cli::HealthcheckEntry,
+) -> Vec<(&'static str, String)> {
+    let mut labels = vec![
+        ("instance", cmd_cfg.instance_name.clone()),
+        ("severity", healthcheck.severity.to_string()),
+        ("dsle", healthcheck.dsle.clone()),
+    ];
+    if cmd_cfg.description_label {
+        labels.push(("description", healthcheck.short_description()));
+    }
+    labels
 }
 
 async fn get_dsctl_metrics(
@@ -43,19 +84,26 @@ async fn get_dsctl_metrics(
     {
         let g = gauge!(
             "dsctl.healthcheck.error",
-            "instance" => cmd_cfg.instance_name.clone(),
-            "severity" => outdated_check.severity.to_string(),
-            "dsle" => outdated_check.dsle.clone()
+            &healthcheck_labels(cmd_cfg, outdated_check)
         );
         g.set(0_f64);
     }
 
+    // Backends without findings are reported only if they needed reindex before
+    let mut pending_reindex = internal::cli::pending_reindex(&healthchecks);
+    for backend in &common_data.reindex_backends {
+        pending_reindex.entry(backend.clone()).or_insert(0);
+    }
+    for (backend, count) in pending_reindex {
+        let g = gauge!("backend.indexes_pending_reindex", "backend" => backend.clone());
+        g.set(count as f64);
+        common_data.reindex_backends.insert(backend);
+    }
+
     for healthcheck in healthchecks {
         let g = gauge!(
             "dsctl.healthcheck.error",
-            "instance" => cmd_cfg.instance_name.clone(),
-            "severity" => healthcheck.severity.to_string(),
-            "dsle" => healthcheck.dsle.clone()
+            &healthcheck_labels(cmd_cfg, &healthcheck)
         );
         g.set(1_f64);
 
@@ -66,18 +114,147 @@ async fn get_dsctl_metrics(
     Ok(())
 }
 
-pub async fn get_gids_metrics(ldap_config: &LdapConfig) -> Result<()> {
+pub async fn get_batch_count_metrics(
+    ldap_config: &LdapConfig,
+    filters: &[NamedFilter],
+) -> Result<()> {
+    for (name, count) in internal::batch::batch_count(ldap_config, filters).await? {
+        let health_gauge = gauge!("internal.health.batch_count", "name" => name.clone());
+
+        match count {
+            Ok(count) => {
+                gauge!("inventory.count", "name" => name).set(count as f64);
+                health_gauge.set(1);
+            }
+            Err(error) => {
+                tracing::error!("Error counting {}: {}", name, error);
+                health_gauge.set(0);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn get_write_probe_metrics(ldap_config: &LdapConfig, dn: &str) -> Result<()> {
+    const PREFIX: &str = "write_probe.";
+
+    let mut ldap = ldap_config.connect().await?;
+    let result = internal::probe::WriteProbe::new(dn.to_string())
+        .run(&mut ldap)
+        .await;
+
+    let gauge = gauge!(format!("{PREFIX}success"));
+    gauge.set(if result.is_ok() { 1 } else { 0 });
+
+    let result = result?;
+    for (operation, duration) in [
+        ("add", result.add),
+        ("modify", result.modify),
+        ("delete", result.delete),
+        ("total", result.total()),
+    ] {
+        let gauge = gauge!(format!("{PREFIX}duration_ms"), "operation" => operation);
+        gauge.set(duration.as_millis() as f64);
+    }
+
+    Ok(())
+}
+
+pub async fn get_gids_metrics(
+    ldap_config: &LdapConfig,
+    gids_config: &internal::gids::GidsConfig,
+) -> Result<()> {
     const PREFIX: &str = "query.gids.";
 
-    for (account, number) in internal::gids::missing_gids_to_uid_mapping(ldap_config).await? {
+    let pages = Arc::new(AtomicU64::new(0));
+
+    let report = internal::gids::gids_report_counted(
+        ldap_config,
+        gids_config,
+        &pages,
+        gids_config.export_uids,
+    )
+    .await?;
+
+    for (gid, uids) in report.missing_uids {
+        for uid in uids {
+            let gauge = gauge!(
+                format!("{PREFIX}unresolvable_uid"),
+                "gid" => gid.to_string(),
+                "uid" => uid
+            );
+            gauge.set(1);
+        }
+    }
+
+    let gauge = gauge!(format!("{PREFIX}low_number_count"));
+    describe_gauge!(
+        format!("{PREFIX}low_number_count"),
+        "Accounts with the primary gid below low_gid_threshold (system range)"
+    );
+    gauge.set(report.low_number_count as f64);
+
+    for (account, number) in report.missing {
         let account = account.to_string();
         let gauge = gauge!(format!("{PREFIX}unresolvable_count"), "gid" => account);
         gauge.set(number as f64);
     }
 
+    counter!(format!("{PREFIX}pages_fetched")).increment(pages.load(Ordering::Relaxed));
+
     Ok(())
 }
 
+/// Set `internal.health.error_kind{scraper, kind}` to 1 for the kind of the last error (others are
+/// reset to 0). All kinds are 0 after successful scrape
+fn set_error_kind(scraper: &str, error: Option<&anyhow::Error>) {
+    report_error_kind(scraper, error.map(ErrorKind::classify));
+}
+
+/// Same as `set_error_kind`, for failures without the error (e.g. server not ready)
+fn report_error_kind(scraper: &str, current: Option<ErrorKind>) {
+    describe_gauge!(
+        "internal.health.error_kind",
+        "Kind of the last scrape error: timeout, connect, bind, parse, permission, other"
+    );
+    for kind in ErrorKind::ALL {
+        gauge!(
+            "internal.health.error_kind",
+            "scraper" => scraper.to_string(),
+            "kind" => kind.name()
+        )
+        .set((current == Some(kind)) as u8);
+    }
+}
+
+/// Await the scrape, recording `internal.scrape.duration_seconds{scraper}` and
+/// `internal.scrape.errors_total{scraper}`. The scrape fails after `timeout` seconds (if set)
+async fn timed_scrape<F: std::future::Future<Output = Result<()>>>(
+    scraper: &str,
+    timeout: Option<u64>,
+    scrape: F,
+) -> Result<()> {
+    describe_histogram!(
+        "internal.scrape.duration_seconds",
+        "Duration of the scrape, including failed ones"
+    );
+    describe_counter!(
+        "internal.scrape.errors_total",
+        "Number of the failed scrapes"
+    );
+
+    let start = Instant::now();
+    let result = internal::with_timeout(timeout, &format!("{scraper} scrape"), scrape).await;
+
+    histogram!("internal.scrape.duration_seconds", "scraper" => scraper.to_string())
+        .record(start.elapsed().as_secs_f64());
+    counter!("internal.scrape.errors_total", "scraper" => scraper.to_string())
+        .increment(result.is_err() as u64);
+
+    result
+}
+
 fn default_true() -> bool {
     true
 }
@@ -86,6 +263,10 @@ fn default_scrape_interval_seconds() -> u64 {
     5
 }
 
+fn default_scrape_trigger_min_interval_seconds() -> u64 {
+    30
+}
+
 fn default_expose_port() -> u16 {
     9100
 }
@@ -94,6 +275,13 @@ fn default_expose_address() -> String {
     "0.0.0.0".to_string()
 }
 
+fn default_privileged_dns() -> Vec<String> {
+    internal::monitor::DEFAULT_PRIVILEGED_DNS
+        .iter()
+        .map(|x| x.to_string())
+        .collect()
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct ExporterQuery {
     name: String,
@@ -113,6 +301,26 @@ pub struct ExporterConfig {
     #[serde(default = "default_expose_address")]
     pub expose_address: String,
 
+    /// PEM certificate (chain) of the metrics endpoint. Metrics are served over HTTPS when set
+    /// together with `tls_key`
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the `tls_cert`
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+
+    /// Bearer token required by the metrics endpoint
+    #[serde(default)]
+    pub metrics_token: Option<String>,
+
+    /// Basic auth username required by the metrics endpoint (with `metrics_password`)
+    #[serde(default)]
+    pub metrics_username: Option<String>,
+
+    #[serde(default)]
+    pub metrics_password: Option<String>,
+
     #[serde(default = "default_scrape_interval_seconds")]
     pub scrape_interval_seconds: u64,
 
@@ -121,6 +329,50 @@ pub struct ExporterConfig {
 
     #[serde(default)]
     pub query: Vec<ExporterQuery>,
+
+    /// Rename emitted metrics: source name -> alias
+    #[serde(default)]
+    pub metric_aliases: HashMap<String, String>,
+
+    /// DNs that should not be used by the clients to bind
+    #[serde(default = "default_privileged_dns")]
+    pub privileged_dns: Vec<String>,
+
+    /// Connection DNs and IPs not seen for this many scrapes are no longer tracked. Gauges not
+    /// updated for that many scrape intervals are removed from the output
+    #[serde(default)]
+    pub stale_connection_scrapes: Option<u64>,
+
+    /// Periodically add, modify and delete the `write_probe_dn` entry to check the write path.
+    /// WARNING: writes to the directory
+    #[serde(default)]
+    pub enable_write_probe: bool,
+
+    /// Dedicated DN of the probe entry, e.g. cn=o11y-probe,ou=monitoring,dc=example,dc=com
+    #[serde(default)]
+    pub write_probe_dn: Option<String>,
+
+    /// Directory servers scraped by this process. Metrics get the `ldap_uri` label of their
+    /// target. If empty, the top level LDAP config is the single target
+    #[serde(default)]
+    pub targets: Vec<LdapConfig>,
+
+    /// Agreements reported by the replication scraper. All of them by default
+    #[serde(default)]
+    pub agreements: AgreementFilter,
+
+    /// Named filters counted over a single connection (`inventory.count{name}`)
+    #[serde(default)]
+    pub batch_counts: Vec<NamedFilter>,
+
+    /// Bearer token required by the `POST /scrape` endpoint triggering immediate scrape.
+    /// Disabled if not set
+    #[serde(default)]
+    pub scrape_trigger_token: Option<String>,
+
+    /// Minimal number of seconds between the triggered scrapes
+    #[serde(default = "default_scrape_trigger_min_interval_seconds")]
+    pub scrape_trigger_min_interval_seconds: u64,
 }
 
 impl Default for ExporterConfig {
@@ -128,9 +380,24 @@ impl Default for ExporterConfig {
         Self {
             expose_port: default_expose_port(),
             expose_address: default_expose_address(),
+            tls_cert: None,
+            tls_key: None,
+            metrics_token: None,
+            metrics_username: None,
+            metrics_password: None,
             scrape_interval_seconds: default_scrape_interval_seconds(),
             scrape_flags: Default::default(),
             query: Default::default(),
+            metric_aliases: Default::default(),
+            privileged_dns: default_privileged_dns(),
+            stale_connection_scrapes: None,
+            enable_write_probe: false,
+            write_probe_dn: None,
+            targets: Default::default(),
+            agreements: Default::default(),
+            batch_counts: Default::default(),
+            scrape_trigger_token: None,
+            scrape_trigger_min_interval_seconds: default_scrape_trigger_min_interval_seconds(),
         }
     }
 }
@@ -144,6 +411,50 @@ pub struct Config {
     pub common: internal::config::CommonConfig,
 }
 
+impl Config {
+    /// Problems which are not caught by the deserialization. Empty if the config is valid
+    pub fn validate(&self) -> Vec<String> {
+        let exporter = &self.exporter;
+        let mut problems = self.common.validate();
+
+        problems.extend(self.common.validate_query_refs(
+            "exporter.query",
+            exporter.query.iter().map(|query| query.name.as_str()),
+        ));
+
+        for (idx, target) in exporter.targets.iter().enumerate() {
+            problems.extend(
+                target
+                    .validate()
+                    .into_iter()
+                    .map(|problem| format!("exporter.targets[{idx}]: {problem}")),
+            );
+        }
+
+        if exporter.tls_cert.is_some() != exporter.tls_key.is_some() {
+            problems.push("tls_cert and tls_key have to be set together".to_string());
+        }
+
+        if exporter.metrics_username.is_some() != exporter.metrics_password.is_some() {
+            problems
+                .push("metrics_username and metrics_password have to be set together".to_string());
+        }
+
+        if exporter.enable_write_probe && exporter.write_probe_dn.is_none() {
+            problems.push("enable_write_probe requires write_probe_dn to be set".to_string());
+        }
+
+        if exporter.stale_connection_scrapes == Some(0) {
+            problems.push(
+                "stale_connection_scrapes has to be positive (unset keeps DNs and IPs forever)"
+                    .to_string(),
+            );
+        }
+
+        problems
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ScrapeFlags {
     #[serde(default = "default_true")]
@@ -154,6 +465,10 @@ pub struct ScrapeFlags {
     /// Check replication status using ldapsearch
     pub replication_status: bool,
 
+    #[serde(default)]
+    /// Use cn=database,cn=monitor,cn=ldbm database to gather database (BDB/LMDB) metrics
+    pub database_monitoring: bool,
+
     #[serde(default)]
     /// Count unresolvable primary gids of posixUser; count low number gids
     pub gids_info: bool,
@@ -163,11 +478,25 @@ pub struct ScrapeFlags {
     pub dsctl: bool,
 }
 
+impl ScrapeFlags {
+    /// Scraper names with their enabled state
+    pub fn scrapers(&self) -> [(&'static str, bool); 5] {
+        [
+            ("ldap_monitoring", self.ldap_monitoring),
+            ("replication_status", self.replication_status),
+            ("database_monitoring", self.database_monitoring),
+            ("gids_info", self.gids_info),
+            ("dsctl", self.dsctl),
+        ]
+    }
+}
+
 impl Default for ScrapeFlags {
     fn default() -> Self {
         Self {
             ldap_monitoring: true,
             replication_status: true,
+            database_monitoring: false,
             gids_info: false,
             dsctl: false,
         }
@@ -182,6 +511,9 @@ pub enum ArgFlag {
     /// Parse monitoring entry
     LdapMonitor,
 
+    /// Parse database monitoring entry (BDB/LMDB statistics)
+    DatabaseMonitor,
+
     /// Count unresolvable primary gids of posixUser
     GidsInfo,
 
@@ -204,6 +536,10 @@ pub struct Args {
     #[clap(short = 'C', long, default_value_t = false)]
     skip_cert_verification: bool,
 
+    /// Upgrade ldap:// connection with StartTLS before bind
+    #[clap(long, default_value_t = false)]
+    start_tls: bool,
+
     #[clap(short = 'a', long)]
     expose_address: Option<String>,
 
@@ -234,12 +570,75 @@ pub struct Args {
     #[clap(short = 'd', long)]
     #[clap(value_enum)]
     disable_flags: Vec<ArgFlag>,
+
+    /// Validate the configuration, print the problems and exit without connecting
+    #[clap(long, default_value_t = false)]
+    check_config: bool,
+}
+
+impl Args {
+    /// Apply the environment and then the command line LDAP options to the `ldap_config`
+    fn apply_ldap_overrides(&self, ldap_config: &mut LdapConfig) -> Result<()> {
+        ldap_config.apply_env()?;
+
+        if let Some(page_size) = self.page_size {
+            ldap_config.page_size = page_size;
+        }
+
+        if let Some(dn) = &self.binddn {
+            let pass = self.bindpass.clone().unwrap();
+            ldap_config.bind = Some(BindMethod::Simple {
+                dn: dn.clone(),
+                pass: pass.into(),
+            });
+        }
+
+        if let Some(host) = &self.host {
+            ldap_config.uri = host.clone();
+        }
+
+        if let Some(basedn) = &self.basedn {
+            ldap_config.default_base = basedn.clone();
+        }
+
+        if self.skip_cert_verification {
+            ldap_config.verify_certs = false;
+        }
+
+        if self.start_tls {
+            ldap_config.start_tls = true;
+        }
+
+        Ok(())
+    }
+
+    /// Server specific options (URI and base) would make every target the same server
+    fn check_targets_overrides(&self) -> Result<()> {
+        let env_set = |name| std::env::var(name).is_ok_and(|x| !x.is_empty());
+
+        if self.host.is_some() || env_set(internal::ENV_URI) {
+            anyhow::bail!(
+                "--host and {} cannot be used with exporter.targets",
+                internal::ENV_URI
+            );
+        }
+
+        if self.basedn.is_some() || env_set(internal::ENV_BASEDN) {
+            anyhow::bail!(
+                "--basedn and {} cannot be used with exporter.targets",
+                internal::ENV_BASEDN
+            );
+        }
+
+        Ok(())
+    }
 }
 
 async fn setup_query_checks(
     cancel_token: CancellationToken,
+    scrape_trigger: watch::Receiver<u64>,
     config: Config,
-    tracker: &TaskTracker,
+    tracker: &TargetTracker,
 ) -> Result<()> {
     let queries = config.exporter.query.iter().filter_map(|exporter_query| {
         if let Some(query_def) = config
@@ -252,9 +651,25 @@ async fn setup_query_checks(
             Some((exporter_query.clone(), query_def.clone()))
         } else {
             tracing::error!("Query {} not found", exporter_query.name);
-            let health_gauge =
-                gauge!("internal.health.query_not_found", "name" => exporter_query.name.clone());
-            health_gauge.set(0);
+            // Registered in the target task, so it is labeled with the target ldap_uri. Refreshed,
+            // so it is not removed as idle (`stale_connection_scrapes`)
+            let name = exporter_query.name.clone();
+            let interval = exporter_query.scrape_interval_seconds;
+            let cancel_token = cancel_token.clone();
+            tracker.spawn(async move {
+                loop {
+                    gauge!("internal.health.query_not_found", "name" => name.clone()).set(0);
+
+                    select! {
+                        _ = tokio::time::sleep(tokio::time::Duration::from_secs(interval)) => {
+
+                        },
+                        _ = cancel_token.cancelled() => {
+                            break
+                        }
+                    }
+                }
+            });
             None
         }
     });
@@ -263,6 +678,7 @@ async fn setup_query_checks(
 
     for mut query in queries {
         let cancel_token = cancel_token.clone();
+        let mut scrape_trigger = scrape_trigger.clone();
         let config = config.clone();
 
         if let Some(max_entries) = query.0.max_entries {
@@ -272,13 +688,23 @@ async fn setup_query_checks(
         tracker.spawn(async move {
             query.1.ldap_config = Some(config.common.ldap_config.clone());
             let health_gauge = gauge!("internal.health.query", "name" => query.1.name.clone());
+            let mut last_code_text = None;
 
             loop {
-                if let Err(e) = handle_query(query.1.clone()).await {
+                let scraper = format!("query:{}", query.1.name);
+                if let Err(e) = timed_scrape(
+                    &scraper,
+                    config.common.ldap_config.operation_timeout_seconds,
+                    handle_query(query.1.clone(), &mut last_code_text),
+                )
+                .await
+                {
                     tracing::error!("Error: {}", e);
+                    set_error_kind(&scraper, Some(&e));
                     health_gauge.set(0);
                 } else {
                     health_gauge.set(1);
+                    set_error_kind(&scraper, None);
                 }
 
                 select! {
@@ -286,6 +712,9 @@ async fn setup_query_checks(
                         query.0.scrape_interval_seconds,
                     )) => {
 
+                    },
+                    Ok(()) = scrape_trigger.changed() => {
+
                     },
                     _ = cancel_token.cancelled() => {
                         break
@@ -298,128 +727,76 @@ async fn setup_query_checks(
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-
-    tracing_subscriber::fmt::init();
-
-    let mut config: Config = if let Some(conf) = &args.config {
-        let file = String::from_utf8(std::fs::read(conf)?)?;
-        toml::from_str(&file)?
-    } else {
-        Default::default()
-    };
-
-    if let Some(page_size) = args.page_size {
-        config.common.ldap_config.page_size = page_size;
-    }
-
-    if let Some(dn) = args.binddn {
-        let pass = args.bindpass.unwrap();
-        let bind = Bind { dn, pass };
-        config.common.ldap_config.bind = Some(bind);
-    }
-
-    if let Some(host) = args.host {
-        config.common.ldap_config.uri = host;
-    }
-
-    if let Some(scrape_interval_seconds) = args.scrape_interval_seconds {
-        config.exporter.scrape_interval_seconds = scrape_interval_seconds;
-    }
-
-    if let Some(expose_address) = args.expose_address {
-        config.exporter.expose_address = expose_address;
-    }
-
-    if let Some(expose_port) = args.expose_port {
-        config.exporter.expose_port = expose_port;
-    }
-
-    if let Some(basedn) = args.basedn {
-        config.common.ldap_config.default_base = basedn;
-    }
-
-    if args.skip_cert_verification {
-        config.common.ldap_config.verify_certs = false;
-    }
-
-    if config.common.ldap_config.default_base.is_empty() {
-        config.common.ldap_config.detect_base().await?;
-        tracing::info!("Set base to the {}", config.common.ldap_config.default_base);
-    }
-
-    for disable_flag in args.disable_flags {
-        match disable_flag {
-            ArgFlag::Replication => config.exporter.scrape_flags.replication_status = false,
-            ArgFlag::LdapMonitor => config.exporter.scrape_flags.ldap_monitoring = false,
-            ArgFlag::GidsInfo => config.exporter.scrape_flags.gids_info = false,
-            ArgFlag::Dsctl => config.exporter.scrape_flags.dsctl = false,
-        }
-    }
+/// Set `default_base` of the target, if empty, to the detected one. Failed detection is retried
+/// every scrape interval, without affecting the other targets. None if cancelled meanwhile
+async fn detect_target_base(
+    mut config: Config,
+    cancel_token: &CancellationToken,
+) -> Option<Config> {
+    let ldap_config = &mut config.common.ldap_config;
+    while ldap_config.default_base.is_empty() {
+        match ldap_config.detect_base().await {
+            Ok(detected) => {
+                if detected.ambiguous {
+                    tracing::warn!(
+                        "Multiple naming contexts found on {} ({}). Set default_query_base or base_hint to pick the right one",
+                        ldap_config.uri,
+                        detected.contexts.join("; ")
+                    );
+                }
+                tracing::info!(
+                    "Set base of {} to the {}",
+                    ldap_config.uri,
+                    ldap_config.default_base
+                );
+            }
+            Err(error) => {
+                tracing::error!("Cannot detect base of {}: {}", ldap_config.uri, error);
+                select! {
+                    _ = tokio::time::sleep(tokio::time::Duration::from_secs(
+                        config.exporter.scrape_interval_seconds,
+                    )) => {
 
-    for enable_flags in args.enable_flags {
-        match enable_flags {
-            ArgFlag::Replication => config.exporter.scrape_flags.replication_status = true,
-            ArgFlag::LdapMonitor => config.exporter.scrape_flags.ldap_monitoring = true,
-            ArgFlag::GidsInfo => config.exporter.scrape_flags.gids_info = true,
-            ArgFlag::Dsctl => config.exporter.scrape_flags.dsctl = true,
+                    },
+                    _ = cancel_token.cancelled() => {
+                        return None
+                    }
+                }
+            }
         }
     }
 
-    let builder = PrometheusBuilder::new()
-        .with_http_listener(
-            format!(
-                "{}:{}",
-                config.exporter.expose_address, config.exporter.expose_port
-            )
-            .parse::<SocketAddr>()?,
-        )
-        .add_global_label("ldap_uri", config.common.ldap_config.uri.clone());
-    builder.install()?;
-
-    let program_start_timestamp = Instant::now();
-
-    let tracker = TaskTracker::new();
-    let cancel_token_orig = CancellationToken::new();
-
-    let cancel_token = cancel_token_orig.clone();
-    tracker.spawn(async move {
-        if let Err(e) = tokio::signal::ctrl_c().await {
-            tracing::error!("Failed to register ctrl-c handler: {}", e);
-            tracing::warn!("Program will work. But killing it can be hard");
-            return;
-        };
-        tracing::info!("Received ctrl-c");
+    Some(config)
+}
 
-        tracing::info!("Shutting down");
-        cancel_token.cancel();
-    });
+/// Spawn the scrapers of the single directory server (`config.common.ldap_config`). Write probe
+/// is spawned only if `write_probe` is set, so the probe entry is written on a single server
+async fn spawn_target_scrapers(
+    config: Config,
+    cancel_token_orig: CancellationToken,
+    scrape_trigger_orig: watch::Receiver<u64>,
+    tracker: TargetTracker,
+    write_probe: bool,
+) -> Result<()> {
+    let Some(config) = detect_target_base(config, &cancel_token_orig).await else {
+        return Ok(());
+    };
 
     let cancel_token = cancel_token_orig.clone();
+    let ldap_config = config.common.ldap_config.clone();
+    let scrape_interval_seconds = config.exporter.scrape_interval_seconds;
     tracker.spawn(async move {
         loop {
-            counter!("internal.runtime.seconds_active")
-                .absolute(program_start_timestamp.elapsed().as_secs());
-
-            describe_counter!(
-                "internal.runtime.seconds_active",
-                "How long o11y-389ds-rs daemon has been already running"
-            );
-
-            gauge!("internal.scrape_interval_seconds")
-                .set(config.exporter.scrape_interval_seconds as f64);
+            gauge!("internal.config.page_size").set(ldap_config.page_size as f64);
             gauge!(
-                "internal.exporter_info",
-                "version" => env!("CARGO_PKG_VERSION"),
-                "name" => env!("CARGO_PKG_NAME")
+                "internal.config.base",
+                "base" => ldap_config.default_base.clone()
             )
             .set(1);
 
             select! {
                 _ = tokio::time::sleep(tokio::time::Duration::from_secs(
-                    config.exporter.scrape_interval_seconds,
+                    scrape_interval_seconds,
                 )) => {
 
                 },
@@ -431,23 +808,45 @@ async fn main() -> Result<()> {
     });
 
     let cancel_token = cancel_token_orig.clone();
+    let mut scrape_trigger = scrape_trigger_orig.clone();
     let config_clone = config.clone();
     if config.exporter.scrape_flags.ldap_monitoring {
         tracker.spawn(async move {
-            let mut common_data = MetricsCommonData::default();
+            let mut common_data = MetricsCommonData {
+                privileged_dns: config_clone.exporter.privileged_dns.clone(),
+                stale_after_scrapes: config_clone.exporter.stale_connection_scrapes,
+                ..Default::default()
+            };
+            let mut session = LdapSession::new(config_clone.common.ldap_config.clone());
             loop {
                 let health_gauge = gauge!("internal.health.ldap_monitoring",);
                 describe_gauge!(
                     "internal.health.ldap_monitoring",
                     "LDAP cn=monitor scraper status"
                 );
-                if let Err(error) =
-                    get_ldap_metrics(&config_clone.common.ldap_config, &mut common_data).await
+                let readiness = get_readiness(&config_clone.common.ldap_config).await;
+                if let Some(reason) = readiness.reason() {
+                    tracing::warn!(
+                        "Skipping monitor scrape, server {}: {}",
+                        readiness.name(),
+                        reason
+                    );
+                    // Server is down or still starting up, so it can't be connected to yet
+                    report_error_kind("ldap_monitoring", Some(ErrorKind::Connect));
+                    health_gauge.set(0);
+                } else if let Err(error) = timed_scrape(
+                    "ldap_monitoring",
+                    config_clone.common.ldap_config.operation_timeout_seconds,
+                    get_ldap_metrics(&mut session, &mut common_data),
+                )
+                .await
                 {
                     tracing::error!("Error: {}", error);
+                    set_error_kind("ldap_monitoring", Some(&error));
                     health_gauge.set(0);
                 } else {
                     health_gauge.set(1);
+                    set_error_kind("ldap_monitoring", None);
                 }
 
                 select! {
@@ -455,6 +854,9 @@ async fn main() -> Result<()> {
                         config.exporter.scrape_interval_seconds,
                     )) => {
 
+                    },
+                    Ok(()) = scrape_trigger.changed() => {
+
                     },
                     _ = cancel_token.cancelled() => {
                         break
@@ -469,6 +871,153 @@ async fn main() -> Result<()> {
     };
 
     let cancel_token = cancel_token_orig.clone();
+    let mut scrape_trigger = scrape_trigger_orig.clone();
+    let config_clone = config.clone();
+    if !config.exporter.batch_counts.is_empty() {
+        tracker.spawn(async move {
+            loop {
+                let health_gauge = gauge!("internal.health.batch_counts",);
+                describe_gauge!(
+                    "internal.health.batch_counts",
+                    "Batch counts scraper status"
+                );
+                describe_gauge!(
+                    "internal.health.batch_count",
+                    "Batch count status of the single filter"
+                );
+
+                if let Err(error) = timed_scrape(
+                    "batch_counts",
+                    config_clone.common.ldap_config.operation_timeout_seconds,
+                    get_batch_count_metrics(
+                        &config_clone.common.ldap_config,
+                        &config_clone.exporter.batch_counts,
+                    ),
+                )
+                .await
+                {
+                    tracing::error!("Error: {}", error);
+                    set_error_kind("batch_counts", Some(&error));
+                    health_gauge.set(0);
+                } else {
+                    health_gauge.set(1);
+                    set_error_kind("batch_counts", None);
+                }
+
+                select! {
+                    _ = tokio::time::sleep(tokio::time::Duration::from_secs(
+                        config_clone.exporter.scrape_interval_seconds,
+                    )) => {
+
+                    },
+                    Ok(()) = scrape_trigger.changed() => {
+
+                    },
+                    _ = cancel_token.cancelled() => {
+                        break
+                    }
+                }
+            }
+        });
+    }
+
+    let cancel_token = cancel_token_orig.clone();
+    let config_clone = config.clone();
+    if write_probe && config.exporter.enable_write_probe {
+        let dn = config
+            .exporter
+            .write_probe_dn
+            .clone()
+            .ok_or(anyhow::anyhow!(
+                "enable_write_probe requires write_probe_dn to be set"
+            ))?;
+        tracing::warn!("Write probe enabled. {} will be periodically written", dn);
+
+        tracker.spawn(async move {
+            loop {
+                let health_gauge = gauge!("internal.health.write_probe",);
+                describe_gauge!("internal.health.write_probe", "Write probe status");
+
+                if let Err(error) = timed_scrape(
+                    "write_probe",
+                    config_clone.common.ldap_config.operation_timeout_seconds,
+                    get_write_probe_metrics(&config_clone.common.ldap_config, &dn),
+                )
+                .await
+                {
+                    tracing::error!("Error: {}", error);
+                    set_error_kind("write_probe", Some(&error));
+                    health_gauge.set(0);
+                } else {
+                    health_gauge.set(1);
+                    set_error_kind("write_probe", None);
+                }
+
+                select! {
+                    _ = tokio::time::sleep(tokio::time::Duration::from_secs(
+                        config_clone.exporter.scrape_interval_seconds,
+                    )) => {
+
+                    },
+                    _ = cancel_token.cancelled() => {
+                        break
+                    }
+                }
+            }
+        });
+    }
+
+    let cancel_token = cancel_token_orig.clone();
+    let mut scrape_trigger = scrape_trigger_orig.clone();
+    let config_clone = config.clone();
+    if config.exporter.scrape_flags.database_monitoring {
+        tracker.spawn(async move {
+            let mut common_data = DatabaseCommonData::default();
+            loop {
+                let health_gauge = gauge!("internal.health.database_monitoring",);
+                describe_gauge!(
+                    "internal.health.database_monitoring",
+                    "LDAP database monitor scraper status"
+                );
+
+                if let Err(error) = timed_scrape(
+                    "database_monitoring",
+                    config_clone.common.ldap_config.operation_timeout_seconds,
+                    get_ldap_database_metrics(&config_clone.common.ldap_config, &mut common_data),
+                )
+                .await
+                {
+                    tracing::error!("Error: {}", error);
+                    set_error_kind("database_monitoring", Some(&error));
+                    health_gauge.set(0);
+                } else {
+                    health_gauge.set(1);
+                    set_error_kind("database_monitoring", None);
+                }
+
+                select! {
+                    _ = tokio::time::sleep(tokio::time::Duration::from_secs(
+                        config.exporter.scrape_interval_seconds,
+                    )) => {
+
+                    },
+                    Ok(()) = scrape_trigger.changed() => {
+
+                    },
+                    _ = cancel_token.cancelled() => {
+                        break
+                    }
+                }
+            }
+        })
+    } else {
+        tracker.spawn(async move {
+            tracing::info!("LDAP database monitor parsing disabled");
+        })
+    };
+
+    let cancel_token = cancel_token_orig.clone();
+    let mut scrape_trigger = scrape_trigger_orig.clone();
     let config_clone = config.clone();
     if config.exporter.scrape_flags.gids_info {
         tracker.spawn(async move {
@@ -476,11 +1025,22 @@ async fn main() -> Result<()> {
                 let health_gauge = gauge!("internal.health.gids",);
                 describe_gauge!("internal.health.gids", "GIDs scraper status");
 
-                if let Err(error) = get_gids_metrics(&config_clone.common.ldap_config).await {
+                if let Err(error) = timed_scrape(
+                    "gids",
+                    config_clone.common.ldap_config.operation_timeout_seconds,
+                    get_gids_metrics(
+                        &config_clone.common.ldap_config,
+                        &config_clone.common.scrapers.gids,
+                    ),
+                )
+                .await
+                {
                     tracing::error!("Error: {}", error);
+                    set_error_kind("gids", Some(&error));
                     health_gauge.set(0);
                 } else {
                     health_gauge.set(1);
+                    set_error_kind("gids", None);
                 }
 
                 select! {
@@ -488,6 +1048,9 @@ async fn main() -> Result<()> {
                         config.exporter.scrape_interval_seconds,
                     )) => {
 
+                    },
+                    Ok(()) = scrape_trigger.changed() => {
+
                     },
                     _ = cancel_token.cancelled() => {
                         break
@@ -502,22 +1065,31 @@ async fn main() -> Result<()> {
     };
 
     let cancel_token = cancel_token_orig.clone();
+    let mut scrape_trigger = scrape_trigger_orig.clone();
     let config_clone = config.clone();
     if config.exporter.scrape_flags.replication_status {
         tracker.spawn(async move {
-            let mut common_data = ReplicationCommonData::default();
+            let mut common_data = ReplicationCommonData {
+                agreement_filter: config_clone.exporter.agreements.clone(),
+                ..Default::default()
+            };
             let health_gauge = gauge!("internal.health.replication",);
             describe_gauge!("internal.health.replication", "Replica scraper status");
 
             loop {
-                if let Err(error) =
-                    get_ldap_replica_metrics(&config_clone.common.ldap_config, &mut common_data)
-                        .await
+                if let Err(error) = timed_scrape(
+                    "replication",
+                    config_clone.common.ldap_config.operation_timeout_seconds,
+                    get_ldap_replica_metrics(&config_clone.common.ldap_config, &mut common_data),
+                )
+                .await
                 {
                     tracing::error!("Error: {}", error);
+                    set_error_kind("replication", Some(&error));
                     health_gauge.set(0);
                 } else {
                     health_gauge.set(1);
+                    set_error_kind("replication", None);
                 }
 
                 select! {
@@ -525,6 +1097,9 @@ async fn main() -> Result<()> {
                         config.exporter.scrape_interval_seconds,
                     )) => {
 
+                    },
+                    Ok(()) = scrape_trigger.changed() => {
+
                     },
                     _ = cancel_token.cancelled() => {
                         break
@@ -538,7 +1113,250 @@ async fn main() -> Result<()> {
         })
     };
 
+    setup_query_checks(
+        cancel_token_orig.clone(),
+        scrape_trigger_orig.clone(),
+        config.clone(),
+        &tracker,
+    )
+    .await?;
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+
+    tracing_subscriber::fmt::init();
+
+    let mut config: Config = if let Some(conf) = &args.config {
+        let file = String::from_utf8(std::fs::read(conf)?)?;
+        toml::from_str(&file)?
+    } else {
+        Default::default()
+    };
+
+    if !config.exporter.targets.is_empty() {
+        args.check_targets_overrides()?;
+    }
+    args.apply_ldap_overrides(&mut config.common.ldap_config)?;
+    for target in &mut config.exporter.targets {
+        args.apply_ldap_overrides(target)?;
+    }
+
+    if let Some(scrape_interval_seconds) = args.scrape_interval_seconds {
+        config.exporter.scrape_interval_seconds = scrape_interval_seconds;
+    }
+
+    if let Some(expose_address) = args.expose_address {
+        config.exporter.expose_address = expose_address;
+    }
+
+    if let Some(expose_port) = args.expose_port {
+        config.exporter.expose_port = expose_port;
+    }
+
+    for disable_flag in args.disable_flags {
+        match disable_flag {
+            ArgFlag::Replication => config.exporter.scrape_flags.replication_status = false,
+            ArgFlag::LdapMonitor => config.exporter.scrape_flags.ldap_monitoring = false,
+            ArgFlag::DatabaseMonitor => config.exporter.scrape_flags.database_monitoring = false,
+            ArgFlag::GidsInfo => config.exporter.scrape_flags.gids_info = false,
+            ArgFlag::Dsctl => config.exporter.scrape_flags.dsctl = false,
+        }
+    }
+
+    for enable_flags in args.enable_flags {
+        match enable_flags {
+            ArgFlag::Replication => config.exporter.scrape_flags.replication_status = true,
+            ArgFlag::LdapMonitor => config.exporter.scrape_flags.ldap_monitoring = true,
+            ArgFlag::DatabaseMonitor => config.exporter.scrape_flags.database_monitoring = true,
+            ArgFlag::GidsInfo => config.exporter.scrape_flags.gids_info = true,
+            ArgFlag::Dsctl => config.exporter.scrape_flags.dsctl = true,
+        }
+    }
+
+    let problems = config.validate();
+    if args.check_config {
+        internal::config::exit_with_problems(&problems);
+    }
+    for problem in &problems {
+        tracing::error!("Config problem: {}", problem);
+    }
+
+    // Single target keeps `ldap_uri` as a global label, multiple ones are labeled per metric
+    let multi_target = !config.exporter.targets.is_empty();
+    let mut target_configs = Vec::new();
+    if multi_target {
+        for target in &config.exporter.targets {
+            let mut target_config = config.clone();
+            target_config.common.ldap_config = target.clone();
+            target_configs.push(target_config);
+        }
+    } else {
+        target_configs.push(config.clone());
+    }
+
+    let expose_addr = format!(
+        "{}:{}",
+        config.exporter.expose_address, config.exporter.expose_port
+    )
+    .parse::<SocketAddr>()?;
+    let mut builder = PrometheusBuilder::new();
+    if !multi_target {
+        builder = builder.add_global_label("ldap_uri", config.common.ldap_config.uri.clone());
+    }
+    if let Some(stale_scrapes) = config.exporter.stale_connection_scrapes {
+        // Forgotten DNs and IPs are no longer updated. Every other gauge is set on each scrape, so
+        // it stays, unless its scraper is stuck
+        let max_interval = config
+            .exporter
+            .query
+            .iter()
+            .map(|query| query.scrape_interval_seconds)
+            .chain([config.exporter.scrape_interval_seconds])
+            .max()
+            .unwrap_or_default();
+        let operation_timeout = target_configs
+            .iter()
+            .filter_map(|target| target.common.ldap_config.operation_timeout_seconds)
+            .max()
+            .unwrap_or_default();
+        builder = builder.idle_timeout(
+            MetricKindMask::GAUGE,
+            Some(tokio::time::Duration::from_secs(
+                (stale_scrapes + 1) * max_interval + operation_timeout,
+            )),
+        );
+    }
+
+    let aliases = MetricAliases::new(&config.exporter.metric_aliases)?;
+    let tls = match (&config.exporter.tls_cert, &config.exporter.tls_key) {
+        (Some(cert), Some(key)) => Some(server::tls_config(cert, key)?),
+        (None, None) => None,
+        _ => anyhow::bail!("tls_cert and tls_key have to be set together"),
+    };
+    let auth = MetricsAuth {
+        token: config.exporter.metrics_token.clone(),
+        basic: match (
+            &config.exporter.metrics_username,
+            &config.exporter.metrics_password,
+        ) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            (None, None) => None,
+            _ => anyhow::bail!("metrics_username and metrics_password have to be set together"),
+        },
+    };
+
+    let (scrape_trigger_sender, scrape_trigger_orig) = watch::channel(0);
+    let trigger = config.exporter.scrape_trigger_token.clone().map(|token| {
+        ScrapeTrigger::new(
+            token,
+            std::time::Duration::from_secs(config.exporter.scrape_trigger_min_interval_seconds),
+            scrape_trigger_sender,
+        )
+    });
+
+    let recorder = builder.build_recorder();
+    let metrics_server = MetricsServer {
+        handle: recorder.handle(),
+        tls,
+        auth,
+        trigger,
+    };
+    metrics::set_global_recorder(TargetRecorder::new(aliases.wrap(recorder)))?;
+
+    let program_start_timestamp = Instant::now();
+
+    let tracker = TaskTracker::new();
+    let cancel_token_orig = CancellationToken::new();
+
+    let cancel_token = cancel_token_orig.clone();
+    tracker.spawn(async move {
+        match internal::shutdown_signal().await {
+            Err(e) => {
+                tracing::error!("Failed to register signal handlers: {}", e);
+                tracing::warn!("Program will work. But killing it can be hard");
+                return;
+            }
+            Ok(signal) => tracing::info!("Received {}", signal),
+        };
+
+        tracing::info!("Shutting down");
+        cancel_token.cancel();
+    });
+
+    let cancel_token = cancel_token_orig.clone();
+    tracker.spawn(async move {
+        if let Err(error) = metrics_server.serve(expose_addr, cancel_token).await {
+            tracing::error!("Metrics endpoint error: {}", error);
+        }
+    });
+
+    let cancel_token = cancel_token_orig.clone();
+    let scrapers = config.exporter.scrape_flags.scrapers();
+    tracker.spawn(async move {
+        loop {
+            counter!("internal.runtime.seconds_active")
+                .absolute(program_start_timestamp.elapsed().as_secs());
+
+            describe_counter!(
+                "internal.runtime.seconds_active",
+                "How long o11y-389ds-rs daemon has been already running"
+            );
+
+            gauge!("internal.scrape_interval_seconds")
+                .set(config.exporter.scrape_interval_seconds as f64);
+            for (scraper, enabled) in scrapers {
+                gauge!("internal.scraper.enabled", "scraper" => scraper).set(enabled as u8);
+            }
+            describe_gauge!(
+                "internal.scraper.enabled",
+                "Scraper is enabled (1) or intentionally disabled (0)"
+            );
+            gauge!(
+                "internal.exporter_info",
+                "version" => env!("CARGO_PKG_VERSION"),
+                "name" => env!("CARGO_PKG_NAME")
+            )
+            .set(1);
+
+            select! {
+                _ = tokio::time::sleep(tokio::time::Duration::from_secs(
+                    config.exporter.scrape_interval_seconds,
+                )) => {
+
+                },
+                _ = cancel_token.cancelled() => {
+                    break
+                }
+            }
+        }
+    });
+
+    for (index, target_config) in target_configs.into_iter().enumerate() {
+        let uri = target_config.common.ldap_config.uri.clone();
+        let target_tracker = TargetTracker::new(tracker.clone(), multi_target.then(|| uri.clone()));
+        let cancel_token = cancel_token_orig.clone();
+        let scrape_trigger = scrape_trigger_orig.clone();
+        target_tracker.clone().spawn(async move {
+            if let Err(error) = spawn_target_scrapers(
+                target_config,
+                cancel_token,
+                scrape_trigger,
+                target_tracker,
+                index == 0,
+            )
+            .await
+            {
+                tracing::error!("Cannot start the scrapers of {}: {}", uri, error);
+            }
+        });
+    }
+
     let cancel_token = cancel_token_orig.clone();
+    let mut scrape_trigger = scrape_trigger_orig.clone();
     let config_clone = config.clone();
     if config.exporter.scrape_flags.dsctl {
         tracker.spawn(async move {
@@ -546,13 +1364,19 @@ async fn main() -> Result<()> {
             let health_gauge = gauge!("internal.health.dsctl",);
             describe_gauge!("internal.health.dsctl", "cli scraper status");
             loop {
-                if let Err(error) =
-                    get_dsctl_metrics(&config_clone.common.scrapers.dsctl, &mut common_data).await
+                if let Err(error) = timed_scrape(
+                    "dsctl",
+                    None,
+                    get_dsctl_metrics(&config_clone.common.scrapers.dsctl, &mut common_data),
+                )
+                .await
                 {
                     tracing::error!("Error: {}", error);
+                    set_error_kind("dsctl", Some(&error));
                     health_gauge.set(0);
                 } else {
                     health_gauge.set(1);
+                    set_error_kind("dsctl", None);
                 }
 
                 select! {
@@ -560,6 +1384,9 @@ async fn main() -> Result<()> {
                         config.exporter.scrape_interval_seconds,
                     )) => {
 
+                    },
+                    Ok(()) = scrape_trigger.changed() => {
+
                     },
                     _ = cancel_token.cancelled() => {
                         break
@@ -573,18 +1400,17 @@ async fn main() -> Result<()> {
         })
     };
 
-    setup_query_checks(cancel_token_orig.clone(), config.clone(), &tracker).await?;
-
     tracker.close();
     tracker.wait().await;
 
     Ok(())
 }
 
-async fn handle_query(query: CustomQuery) -> Result<()> {
+/// `last_code_text` is the LDAP code name reported by the previous run, reset when it changes
+async fn handle_query(query: CustomQuery, last_code_text: &mut Option<&'static str>) -> Result<()> {
     let metrics = query.get_metrics().await?;
 
-    let labels = vec![("query", query.name)];
+    let labels = vec![("query", query.name.clone())];
 
     let g = gauge!("custom_query.duration_ms", &labels);
     g.set(metrics.query_time.as_millis() as f64);
@@ -598,5 +1424,66 @@ async fn handle_query(query: CustomQuery) -> Result<()> {
     let g = gauge!("custom_query.ldap_code", &labels);
     g.set(metrics.ldap_code as f64);
 
+    if let Some(previous) = last_code_text.filter(|x| *x != metrics.ldap_code_text) {
+        let g = gauge!("custom_query.ldap_code_info", "query" => query.name.clone(), "code" => previous);
+        g.set(0);
+    }
+    let g = gauge!("custom_query.ldap_code_info", "query" => query.name.clone(), "code" => metrics.ldap_code_text);
+    describe_gauge!(
+        "custom_query.ldap_code_info",
+        "Name of the LDAP result code (e.g. sizeLimitExceeded) of the last query run"
+    );
+    g.set(1);
+    *last_code_text = Some(metrics.ldap_code_text);
+
+    let g = gauge!("custom_query.ldap_code_ok", &labels);
+    g.set(if query.is_ok_code(metrics.ldap_code) {
+        1
+    } else {
+        0
+    });
+
+    let g = gauge!("custom_query.truncated", &labels);
+    g.set(metrics.truncated as u8);
+
+    let c = counter!("custom_query.pages_fetched", &labels);
+    c.increment(metrics.pages_fetched);
+
+    if let Some(value) = metrics.value {
+        let g = gauge!("custom_query.value", &labels);
+        g.set(value);
+    }
+
+    for (attr, present) in metrics.attrs_present {
+        let mut attr_labels = vec![("attr", attr)];
+        attr_labels.extend(labels.clone());
+
+        let g = gauge!("custom_query.attr_present", &attr_labels);
+        g.set(present as f64);
+
+        let g = gauge!("custom_query.attr_absent", &attr_labels);
+        g.set((metrics.object_count - present) as f64);
+    }
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_connection_scrapes_is_positive() {
+        let mut config = Config::default();
+        assert!(config.validate().is_empty(), "{:?}", config.validate());
+
+        config.exporter.stale_connection_scrapes = Some(0);
+        assert!(config
+            .validate()
+            .iter()
+            .any(|x| x.contains("stale_connection_scrapes")));
+
+        config.exporter.stale_connection_scrapes = Some(3);
+        assert!(config.validate().is_empty());
+    }
+}
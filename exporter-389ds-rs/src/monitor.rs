@@ -1,23 +1,48 @@
 use std::collections::{HashMap, HashSet};
 
 use anyhow::Result;
-use internal::LdapConfig;
+use internal::{monitor::Readiness, session::LdapSession, LdapConfig};
 use ldap3::Ldap;
 use metrics::{counter, describe_counter, describe_gauge, gauge};
 
+/// Connections of the single DN or IP, accumulated over the scrapes
+#[derive(Debug, Default, Clone)]
+pub struct ConnectionsSeen {
+    /// Sum of the connections from every scrape
+    pub sum: u64,
+
+    /// Number of the scrapes since the DN or IP was first seen
+    pub scrapes: u64,
+
+    /// Number of the consecutive scrapes without connections
+    pub idle_scrapes: u64,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct MetricsCommonData {
     ///  List of the used connection dns over duration of the exporter process
-    pub connections_dns: HashMap<String, u64>,
+    pub connections_dns: HashMap<String, ConnectionsSeen>,
 
     ///  List of the used connection dns over duration of the exporter process
-    pub connections_ips: HashMap<String, u64>,
+    pub connections_ips: HashMap<String, ConnectionsSeen>,
 
     /// Set of already recorded versions
     pub version: HashSet<String>,
 
     /// Number of scrapes
     pub scrapes: u64,
+
+    /// DNs reserved for the administration. Connections bound as them are reported separately
+    pub privileged_dns: Vec<String>,
+
+    /// DNs and IPs without connections for this many scrapes are forgotten. Never if None
+    pub stale_after_scrapes: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DatabaseCommonData {
+    /// Backends (name -> suffix) seen over duration of the exporter process
+    pub backends: HashMap<String, String>,
 }
 
 fn count_scrapes(prefix: &str, to_inc: Option<&mut u64>) {
@@ -34,6 +59,66 @@ fn count_scrapes(prefix: &str, to_inc: Option<&mut u64>) {
     }
 }
 
+/// Add `current` connections to the `known` ones. Returns the connections and the average of
+/// every known DN or IP. Known values absent from `current` count as 0, until they are idle for
+/// `stale_after_scrapes`: then they are forgotten and their average is None
+fn update_connections(
+    mut current: HashMap<String, u64>,
+    known: &mut HashMap<String, ConnectionsSeen>,
+    stale_after_scrapes: Option<u64>,
+) -> Vec<(String, u64, Option<f64>)> {
+    for key in known.keys() {
+        current.entry(key.clone()).or_insert(0);
+    }
+
+    current
+        .into_iter()
+        .map(|(key, value)| {
+            let seen = known.entry(key.clone()).or_default();
+            seen.sum += value;
+            seen.scrapes += 1;
+            seen.idle_scrapes = if value == 0 { seen.idle_scrapes + 1 } else { 0 };
+
+            if stale_after_scrapes.is_some_and(|x| seen.idle_scrapes >= x) {
+                known.remove(&key);
+                (key, value, None)
+            } else {
+                let average = (seen.sum as f64) / (seen.scrapes as f64);
+                (key, value, Some(average))
+            }
+        })
+        .collect()
+}
+
+/// Set `connection.by_<label>` and `connection.avg.by_<label>` gauges. Series of the forgotten
+/// DNs and IPs are no longer updated, so the recorder drops them after its idle timeout
+fn record_connections(
+    label: &'static str,
+    current: HashMap<String, u64>,
+    known: &mut HashMap<String, ConnectionsSeen>,
+    stale_after_scrapes: Option<u64>,
+) {
+    const PREFIX: &str = "monitor.";
+
+    describe_gauge!(
+        format!("{PREFIX}connection.avg.by_{label}"),
+        format!("Average value of {PREFIX}connection.by_{label} since the {label} was first seen")
+    );
+
+    for (key, value, average) in update_connections(current, known, stale_after_scrapes) {
+        let Some(average) = average else {
+            tracing::debug!("Forgetting connections of the stale {} {}", label, key);
+            continue;
+        };
+
+        let gauge = gauge!(format!("{PREFIX}connection.by_{label}"), label => key.clone());
+        gauge.set(value as f64);
+
+        let gauge = gauge!(format!("{PREFIX}connection.avg.by_{label}"), label => key);
+        gauge.set(average);
+    }
+}
+
 async fn get_root_metrics(ldap: &mut Ldap, common_data: &mut MetricsCommonData) -> Result<()> {
     const PREFIX: &str = "monitor.";
 
@@ -57,49 +142,51 @@ async fn get_root_metrics(ldap: &mut Ldap, common_data: &mut MetricsCommonData)
     let gauge = gauge!(format!("{PREFIX}connection.count"));
     gauge.set(scraped.connections.count() as f64);
 
-    // Add known dns from the previous runs
-    let mut by_dn = scraped.connections.group_by_dn();
-    for common_dn in common_data.connections_dns.keys() {
-        by_dn.entry(common_dn.clone()).or_insert(0);
-    }
-    for (key, value) in by_dn {
-        let sum = common_data.connections_dns.entry(key.clone()).or_insert(0);
-        *sum += value;
-
-        let gauge = gauge!(format!("{PREFIX}connection.by_dn"), "dn" => key.clone());
-        gauge.set(value as f64);
+    record_connections(
+        "dn",
+        scraped.connections.group_by_dn(),
+        &mut common_data.connections_dns,
+        common_data.stale_after_scrapes,
+    );
 
-        let gauge = gauge!(format!("{PREFIX}connection.avg.by_dn"), "dn" => key.clone());
+    for (dn, value) in scraped
+        .connections
+        .group_by_privileged_dn(&common_data.privileged_dns)
+    {
+        let gauge = gauge!(format!("{PREFIX}connection.privileged"), "dn" => dn);
         describe_gauge!(
-            format!("{PREFIX}connection.avg.by_dn"),
-            format!("Average value of {PREFIX}connection.by_dn from every scrape")
+            format!("{PREFIX}connection.privileged"),
+            "Number of connections bound as privileged DN"
         );
-        gauge.set((*sum as f64) / (common_data.scrapes as f64));
+        gauge.set(value as f64);
     }
 
-    // Add known ips from the previous runs
-    let mut by_ip = scraped.connections.group_by_ip();
-    for common_ip in common_data.connections_ips.keys() {
-        by_ip.entry(common_ip.clone()).or_insert(0);
+    record_connections(
+        "ip",
+        scraped.connections.group_by_ip(),
+        &mut common_data.connections_ips,
+        common_data.stale_after_scrapes,
+    );
+
+    for (attr, value) in scraped.int_metrics {
+        let gauge = gauge!(format!("{PREFIX}{attr}"));
+        gauge.set(value as f64)
     }
-    for (key, value) in by_ip {
-        let sum = common_data.connections_ips.entry(key.clone()).or_insert(0);
-        *sum += value;
 
-        let gauge = gauge!(format!("{PREFIX}connection.by_ip"), "ip" => key.clone());
-        gauge.set(value as f64);
+    if let Some(starttime) = scraped.date_metrics.get("starttime") {
+        // Server time is preferred, as the exporter clock may differ
+        let currenttime = scraped
+            .date_metrics
+            .get("currenttime")
+            .map(|x| x.and_utc().timestamp())
+            .unwrap_or_else(|| chrono::Utc::now().timestamp());
 
-        let gauge = gauge!(format!("{PREFIX}connection.avg.by_ip"), "ip" => key.clone());
+        let gauge = gauge!(format!("{PREFIX}uptime_seconds"));
         describe_gauge!(
-            format!("{PREFIX}connection.avg.by_ip"),
-            format!("Average value of {PREFIX}connection.by_ip from every scrape")
+            format!("{PREFIX}uptime_seconds"),
+            "Seconds since the daemon start (currenttime - starttime)"
         );
-        gauge.set((*sum as f64) / (common_data.scrapes as f64));
-    }
-
-    for (attr, value) in scraped.int_metrics {
-        let gauge = gauge!(format!("{PREFIX}{attr}"));
-        gauge.set(value as f64)
+        gauge.set((currenttime - starttime.and_utc().timestamp()) as f64);
     }
 
     for (attr, value) in scraped.date_metrics {
@@ -134,22 +221,259 @@ async fn get_ldap_snmp_metrics(ldap: &mut Ldap) -> Result<()> {
     count_scrapes(PREFIX, None);
 
     for (attr, value) in scraped.int_metrics {
-        let gauge = gauge!(format!("{PREFIX}{attr}"));
-        gauge.set(value as f64);
+        if internal::monitor::SNMP_METRICS_COUNTER.contains(&attr.as_str()) {
+            counter!(format!("{PREFIX}{attr}")).absolute(value);
+        } else {
+            gauge!(format!("{PREFIX}{attr}")).set(value as f64);
+        }
+    }
+
+    Ok(())
+}
+
+/// BDB reports most of the statistics as values accumulated since the start (even the "-rate"
+/// ones). LMDB reports accumulated numbers of the committed/aborted transactions
+fn is_database_counter(attr: &str) -> bool {
+    attr.ends_with("-rate")
+        || attr.ends_with("-hit")
+        || attr.ends_with("-try")
+        || attr.starts_with("abort")
+        || attr.starts_with("commit")
+}
+
+async fn get_database_metrics(ldap: &mut Ldap) -> Result<()> {
+    const PREFIX: &str = "monitor.database.";
+
+    let scraped = internal::monitor::LdapDatabase::scrape(ldap).await?;
+    count_scrapes(PREFIX, None);
+
+    if let Some(ratio) = scraped.cache_hit_ratio() {
+        let gauge = gauge!(format!("{PREFIX}cache_hit_ratio"));
+        gauge.set(ratio);
+    }
+
+    for (attr, value) in scraped.int_metrics {
+        let metric = attr.trim_start_matches("nsslapd-db-").replace("-", "_");
+        if is_database_counter(&attr) {
+            counter!(format!("{PREFIX}{metric}")).absolute(value);
+        } else {
+            gauge!(format!("{PREFIX}{metric}")).set(value as f64);
+        }
     }
 
     Ok(())
 }
 
+#[derive(Debug, Clone, Copy)]
+enum MonitorScraper {
+    Root,
+    Disk,
+    Snmp,
+}
+
+impl MonitorScraper {
+    fn name(&self) -> &'static str {
+        match self {
+            MonitorScraper::Root => "monitor",
+            MonitorScraper::Disk => "disk",
+            MonitorScraper::Snmp => "snmp",
+        }
+    }
+
+    async fn scrape(&self, ldap: &mut Ldap, common_data: &mut MetricsCommonData) -> Result<()> {
+        match self {
+            MonitorScraper::Root => get_root_metrics(ldap, common_data).await,
+            MonitorScraper::Disk => get_disk_metrics(ldap).await,
+            MonitorScraper::Snmp => get_ldap_snmp_metrics(ldap).await,
+        }
+    }
+}
+
+/// Probe readiness of the server and report it as the `internal.ldap.readiness` gauge
+pub async fn get_readiness(ldap_config: &LdapConfig) -> Readiness {
+    let readiness = Readiness::probe(ldap_config).await;
+
+    describe_gauge!(
+        "internal.ldap.readiness",
+        "Server state: down, starting_up (monitor not available yet) or ready"
+    );
+    for state in Readiness::NAMES {
+        let gauge = gauge!("internal.ldap.readiness", "state" => *state);
+        gauge.set(if *state == readiness.name() { 1 } else { 0 });
+    }
+
+    readiness
+}
+
 pub async fn get_ldap_metrics(
-    ldap_config: &LdapConfig,
+    session: &mut LdapSession,
     common_data: &mut MetricsCommonData,
 ) -> Result<()> {
-    let mut ldap = ldap_config.connect().await?;
+    let mut first_error = None;
+
+    for scraper in [
+        MonitorScraper::Root,
+        MonitorScraper::Disk,
+        MonitorScraper::Snmp,
+    ] {
+        let mut result = match session.get().await {
+            Ok(ldap) => scraper.scrape(ldap, common_data).await,
+            Err(error) => Err(error),
+        };
+
+        // Retry once with a new connection, so a single blip does not fail the whole interval
+        if let Err(error) = &result {
+            tracing::debug!("Scrape of {} failed, retrying: {}", scraper.name(), error);
+            session.invalidate();
+            result = match session.get().await {
+                Ok(ldap) => scraper.scrape(ldap, common_data).await,
+                Err(error) => Err(error),
+            };
+        }
+
+        let health_gauge =
+            gauge!("internal.health.ldap_monitoring.scraper", "scraper" => scraper.name());
+        health_gauge.set(result.is_ok() as u8 as f64);
+
+        if let Err(error) = result {
+            session.invalidate();
+            first_error.get_or_insert(error);
+        }
+    }
+
+    first_error.map_or(Ok(()), Err)
+}
+
+async fn get_backends_metrics(ldap: &mut Ldap, common_data: &mut DatabaseCommonData) -> Result<()> {
+    const PREFIX: &str = "backend.";
+
+    let scraped = internal::monitor::LdapBackends::scrape(ldap).await?;
+    count_scrapes(PREFIX, None);
+
+    let gauge = gauge!(format!("{PREFIX}count"));
+    describe_gauge!(format!("{PREFIX}count"), "Number of configured backends");
+    gauge.set(scraped.backends.len() as f64);
+
+    // Reset removed backends
+    for (backend, suffix) in common_data
+        .backends
+        .iter()
+        .filter(|(backend, _)| !scraped.backends.contains_key(*backend))
+    {
+        let gauge = gauge!(format!("{PREFIX}info"), "backend" => backend.clone(), "suffix" => suffix.clone());
+        gauge.set(0);
+    }
+
+    let mut first_error = None;
+    for (backend, suffix) in scraped.backends {
+        let gauge = gauge!(format!("{PREFIX}info"), "backend" => backend.clone(), "suffix" => suffix.clone());
+        gauge.set(1);
+
+        if let Err(error) = get_backend_monitor_metrics(ldap, &backend).await {
+            first_error.get_or_insert(error);
+        }
+        common_data.backends.insert(backend, suffix);
+    }
 
-    get_root_metrics(&mut ldap, common_data).await?;
-    get_disk_metrics(&mut ldap).await?;
-    get_ldap_snmp_metrics(&mut ldap).await?;
+    first_error.map_or(Ok(()), Err)
+}
+
+async fn get_backend_monitor_metrics(ldap: &mut Ldap, backend: &str) -> Result<()> {
+    const PREFIX: &str = "monitor.backend.";
+
+    let scraped = internal::monitor::LdapBackendMonitor::scrape(ldap, backend).await?;
+
+    describe_gauge!(
+        format!(
+            "{PREFIX}{}",
+            internal::monitor::BACKEND_ENTRY_CACHE_HIT_RATIO
+        ),
+        "Entry cache hit ratio (0-100) of the backend"
+    );
+    describe_gauge!(
+        format!("{PREFIX}{}", internal::monitor::BACKEND_DN_CACHE_HIT_RATIO),
+        "DN cache hit ratio (0-100) of the backend"
+    );
+
+    for (attr, value) in scraped.metrics {
+        let metric = attr.replace("-", "_");
+        gauge!(format!("{PREFIX}{metric}"), "backend" => scraped.backend.clone()).set(value);
+    }
 
     Ok(())
 }
+
+/// Scrape database statistics and configured backends. Both require privileged bind
+pub async fn get_ldap_database_metrics(
+    ldap_config: &LdapConfig,
+    common_data: &mut DatabaseCommonData,
+) -> Result<()> {
+    let mut ldap = ldap_config.connect().await?;
+    let database = get_database_metrics(&mut ldap).await;
+    let backends = get_backends_metrics(&mut ldap, common_data).await;
+
+    database.and(backends)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scrape(
+        current: &[(&str, u64)],
+        known: &mut HashMap<String, ConnectionsSeen>,
+        stale_after_scrapes: Option<u64>,
+    ) -> HashMap<String, (u64, Option<f64>)> {
+        let current = current.iter().map(|(k, v)| (k.to_string(), *v)).collect();
+        update_connections(current, known, stale_after_scrapes)
+            .into_iter()
+            .map(|(key, value, average)| (key, (value, average)))
+            .collect()
+    }
+
+    #[test]
+    fn average_is_per_key() {
+        let mut known = HashMap::new();
+        scrape(&[("a", 4)], &mut known, None);
+        let scraped = scrape(&[("a", 2), ("b", 6)], &mut known, None);
+
+        assert_eq!(scraped["a"], (2, Some(3.0)));
+        assert_eq!(scraped["b"], (6, Some(6.0)));
+    }
+
+    #[test]
+    fn idle_keys_are_forgotten() {
+        let mut known = HashMap::new();
+        scrape(&[("a", 2)], &mut known, Some(2));
+
+        let scraped = scrape(&[], &mut known, Some(2));
+        assert_eq!(scraped["a"], (0, Some(1.0)));
+
+        let scraped = scrape(&[], &mut known, Some(2));
+        assert_eq!(scraped["a"], (0, None));
+        assert!(known.is_empty());
+
+        assert!(scrape(&[], &mut known, Some(2)).is_empty());
+    }
+
+    #[test]
+    fn idle_keys_are_kept_without_stale_limit() {
+        let mut known = HashMap::new();
+        scrape(&[("a", 3)], &mut known, None);
+        for _ in 0..10 {
+            scrape(&[], &mut known, None);
+        }
+
+        assert!(known.contains_key("a"));
+    }
+
+    #[test]
+    fn reappearing_key_starts_a_new_average() {
+        let mut known = HashMap::new();
+        scrape(&[("a", 8)], &mut known, Some(1));
+        scrape(&[], &mut known, Some(1));
+
+        let scraped = scrape(&[("a", 2)], &mut known, Some(1));
+        assert_eq!(scraped["a"], (2, Some(2.0)));
+    }
+}
@@ -1,24 +1,48 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use anyhow::Result;
-use internal::LdapConfig;
+use internal::{
+    replica::{Agreement, AgreementFilter, Ruv},
+    LdapConfig,
+};
 use ldap3::Ldap;
-use metrics::{describe_gauge, gauge};
+use metrics::{counter, describe_counter, describe_gauge, gauge};
 
 #[derive(Debug, Default, Clone)]
 pub struct ReplicationCommonData {
     pub agreements: HashSet<String>,
+
+    /// Suffixes (agreement roots) seen over duration of the exporter process
+    pub suffixes: HashSet<String>,
+
+    /// Only the matching agreements are scraped
+    pub agreement_filter: AgreementFilter,
+}
+
+#[derive(Debug, Default)]
+struct SuffixRollup {
+    total: u64,
+    broken: u64,
 }
+
 async fn get_agreement_metrics(
     ldap: &mut Ldap,
     common_data: &mut ReplicationCommonData,
 ) -> Result<()> {
     const PREFIX: &str = "replication.";
 
-    let scraped = internal::replica::Agreement::scrape(ldap).await?;
+    let scraped = Agreement::scrape_filtered(ldap, &common_data.agreement_filter).await?;
+    get_ruv_divergence_metrics(&scraped);
 
     let mut active_cns = HashSet::new();
+    let mut suffixes: HashMap<String, SuffixRollup> = HashMap::new();
     for entry in scraped {
+        let suffix = suffixes.entry(entry.root.clone()).or_default();
+        suffix.total += 1;
+        if entry.enabled && !entry.status.is_healthy() {
+            suffix.broken += 1;
+        }
+
         let labels = [
             ("agreement", entry.cn.clone()),
             ("host", entry.host),
@@ -29,6 +53,9 @@ async fn get_agreement_metrics(
         let g = gauge!(format!("{PREFIX}agreement"), &labels);
         g.set(1);
 
+        let g = gauge!(format!("{PREFIX}agreement.enabled"), &labels);
+        g.set(entry.enabled as u8 as f64);
+
         for ruv in entry.ruvs {
             let mut ruv_labels = ruv.to_labels();
             ruv_labels.extend(
@@ -39,26 +66,90 @@ async fn get_agreement_metrics(
             );
             let g = gauge!(format!("{PREFIX}agreement.ruv"), &ruv_labels);
             g.set(ruv.get_replica_id() as f64);
+
+            if let (Some(timestamp), Ruv::Info { server, .. }) = (ruv.last_change_timestamp(), &ruv)
+            {
+                // Not labeled with the change itself, as it would create a new series on every change
+                let mut age_labels = vec![
+                    ("replica_id", ruv.get_replica_id().to_string()),
+                    ("server", server.clone()),
+                ];
+                age_labels.extend(labels.clone());
+
+                let g = gauge!(
+                    format!("{PREFIX}agreement.ruv.last_change_age_seconds"),
+                    &age_labels
+                );
+                g.set((chrono::Utc::now().timestamp() - timestamp) as f64);
+            }
         }
 
+        // NaN while the update is in progress or never completed, so the previous duration is
+        // not reported as the current one
+        describe_gauge!(
+            format!("{PREFIX}agreement.last_update_duration_seconds"),
+            "Duration of the last complete update. NaN if it is in progress or never completed"
+        );
         let g = gauge!(
             format!("{PREFIX}agreement.last_update_duration_seconds"),
             &labels
         );
-        g.set(entry.last_update_duration_seconds as f64);
+        g.set(
+            entry
+                .last_update_duration_seconds
+                .map_or(f64::NAN, |duration| duration as f64),
+        );
+
+        let replayed_total: u64 = entry.changes_sent.iter().map(|x| x.changes_replayed).sum();
+        let skipped_total: u64 = entry.changes_sent.iter().map(|x| x.changes_skipped).sum();
+
+        describe_counter!(
+            format!("{PREFIX}agreement.changes_replayed.total"),
+            "Changes replayed by the agreement since the daemon startup, summed over all replica ids"
+        );
+        counter!(format!("{PREFIX}agreement.changes_replayed.total"), &labels)
+            .absolute(replayed_total);
+
+        describe_counter!(
+            format!("{PREFIX}agreement.changes_skipped.total"),
+            "Changes skipped by the agreement since the daemon startup, summed over all replica ids"
+        );
+        counter!(format!("{PREFIX}agreement.changes_skipped.total"), &labels)
+            .absolute(skipped_total);
 
         for change in entry.changes_sent {
             let mut change_labels = vec![("replica_id", change.replica_id.to_string())];
             change_labels.extend(labels.clone());
 
+            // Deprecated, replaced by the counters below. Removed in 0.3.0
+            describe_gauge!(
+                format!("{PREFIX}agreement.changes_replayed"),
+                "Deprecated, use agreement.replica.changes_replayed.total"
+            );
             let g_replayed = gauge!(
                 format!("{PREFIX}agreement.changes_replayed"),
                 &change_labels
             );
             g_replayed.set(change.changes_replayed as f64);
 
+            describe_gauge!(
+                format!("{PREFIX}agreement.changes_skipped"),
+                "Deprecated, use agreement.replica.changes_skipped.total"
+            );
             let g_skipped = gauge!(format!("{PREFIX}agreement.changes_skipped"), &change_labels);
             g_skipped.set(change.changes_skipped as f64);
+
+            // Counters since the daemon startup
+            counter!(
+                format!("{PREFIX}agreement.replica.changes_replayed.total"),
+                &change_labels
+            )
+            .absolute(change.changes_replayed);
+            counter!(
+                format!("{PREFIX}agreement.replica.changes_skipped.total"),
+                &change_labels
+            )
+            .absolute(change.changes_skipped);
         }
 
         let status = entry.status;
@@ -78,6 +169,62 @@ async fn get_agreement_metrics(
             &status_labels
         );
         g_last_status_color.set(date as f64);
+
+        describe_gauge!(
+            format!("{PREFIX}agreement.last_init_duration_seconds"),
+            "Duration of the last complete initialization. NaN if it is in progress or never completed"
+        );
+        let g = gauge!(
+            format!("{PREFIX}agreement.last_init_duration_seconds"),
+            &labels
+        );
+        g.set(
+            entry
+                .last_init_duration_seconds
+                .map_or(f64::NAN, |duration| duration as f64),
+        );
+
+        if let Some(init_status) = entry.init_status {
+            let mut init_labels = vec![("state", init_status.state.to_string())];
+            init_labels.extend(labels.clone());
+
+            let g = gauge!(
+                format!("{PREFIX}agreement.last_init_ldap_status"),
+                &init_labels
+            );
+            g.set(init_status.ldap_rc as f64);
+
+            let g = gauge!(
+                format!("{PREFIX}agreement.last_init_repl_status"),
+                &init_labels
+            );
+            g.set(init_status.repl_rc as f64);
+
+            let g = gauge!(format!("{PREFIX}agreement.last_init_healthy"), &labels);
+            g.set(init_status.is_healthy() as u8 as f64);
+
+            let g = gauge!(format!("{PREFIX}agreement.last_init_date"), &labels);
+            g.set(init_status.date.and_utc().timestamp() as f64);
+        }
+    }
+
+    // Reset suffixes without agreements
+    for root in &common_data.suffixes {
+        suffixes.entry(root.clone()).or_default();
+    }
+
+    for (root, rollup) in suffixes {
+        let g = gauge!(format!("{PREFIX}suffix.agreements_total"), "root" => root.clone());
+        g.set(rollup.total as f64);
+
+        let g = gauge!(format!("{PREFIX}suffix.agreements_broken"), "root" => root.clone());
+        describe_gauge!(
+            format!("{PREFIX}suffix.agreements_broken"),
+            "Number of enabled agreements, which are not green or report errors"
+        );
+        g.set(rollup.broken as f64);
+
+        common_data.suffixes.insert(root);
     }
 
     for agreement in common_data.agreements.difference(&active_cns) {
@@ -90,6 +237,32 @@ async fn get_agreement_metrics(
     Ok(())
 }
 
+/// Replica ids seen in the RUV of some agreement, but missing in the RUV of the other one with the
+/// same suffix
+fn get_ruv_divergence_metrics(agreements: &[Agreement]) {
+    const PREFIX: &str = "replication.ruv.";
+
+    let known = Agreement::known_replica_ids(agreements);
+
+    for (root, replica_ids) in &known {
+        let g = gauge!(format!("{PREFIX}known_replica_ids"), "root" => root.clone());
+        g.set(replica_ids.len() as f64);
+    }
+
+    for agreement in agreements {
+        let present = agreement.replica_ids();
+        for replica_id in known.get(&agreement.root).into_iter().flatten() {
+            let g = gauge!(
+                format!("{PREFIX}missing_on_agreement"),
+                "agreement" => agreement.cn.clone(),
+                "root" => agreement.root.clone(),
+                "replica_id" => replica_id.to_string()
+            );
+            g.set(!present.contains(replica_id) as u8 as f64);
+        }
+    }
+}
+
 async fn get_replica_metrics(ldap: &mut Ldap) -> Result<()> {
     const PREFIX: &str = "replication.replica.";
 
@@ -122,7 +295,7 @@ pub async fn get_ldap_replica_metrics(
 ) -> Result<()> {
     const PREFIX: &str = "replication.";
 
-    let mut ldap = ldap_config.connect().await?;
+    let mut ldap = ldap_config.connect_with_retry().await?;
 
     let version = internal::replica::replication_plugin_version(&mut ldap).await?;
     let g = gauge!(format!("{PREFIX}plugin.version"), "version" => version.to_string());
@@ -131,5 +304,14 @@ pub async fn get_ldap_replica_metrics(
     get_replica_metrics(&mut ldap).await?;
     get_agreement_metrics(&mut ldap, common_data).await?;
 
+    let conflicts =
+        internal::replica::conflict_entries(&mut ldap, &ldap_config.default_base).await?;
+    let g = gauge!(format!("{PREFIX}conflict_entries"));
+    describe_gauge!(
+        format!("{PREFIX}conflict_entries"),
+        "Entries with nsds5ReplConflict under the default query base"
+    );
+    g.set(conflicts.len() as f64);
+
     Ok(())
 }
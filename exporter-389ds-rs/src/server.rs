@@ -0,0 +1,278 @@
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use actix_web::{http::header, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use anyhow::{Context, Result};
+use base64::Engine;
+use metrics::counter;
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::select;
+use tokio_rustls::rustls::{
+    self,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::trigger::{self, ScrapeTrigger};
+
+/// Time for the TLS handshake, reading the request and closing the connection
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Scrapes are rare, a couple of workers is enough
+const WORKERS: usize = 2;
+
+/// Concurrent connections per worker
+const MAX_CONNECTIONS: usize = 64;
+
+/// Metrics endpoint (`GET /metrics`) and the scrape trigger (`POST /scrape`, if set), served
+/// over HTTPS if `tls` is set
+pub struct MetricsServer {
+    pub handle: PrometheusHandle,
+    pub tls: Option<rustls::ServerConfig>,
+    pub auth: MetricsAuth,
+    pub trigger: Option<ScrapeTrigger>,
+}
+
+/// Credentials accepted by the metrics endpoint. Any request is accepted if none are set
+#[derive(Default, Clone)]
+pub struct MetricsAuth {
+    pub token: Option<String>,
+    /// `(username, password)`
+    pub basic: Option<(String, String)>,
+}
+
+impl MetricsAuth {
+    pub fn is_enabled(&self) -> bool {
+        self.token.is_some() || self.basic.is_some()
+    }
+
+    pub(crate) fn authorized(&self, request: &HttpRequest) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+
+        let basic = self.basic.as_ref().map(|(username, password)| {
+            base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"))
+        });
+
+        request
+            .headers()
+            .get_all(header::AUTHORIZATION)
+            .filter_map(|value| value.to_str().ok())
+            .filter_map(|value| value.trim().split_once(' '))
+            .any(|(scheme, credentials)| {
+                // Schemes are case insensitive (RFC 9110)
+                let expected = if scheme.eq_ignore_ascii_case("bearer") {
+                    self.token.as_deref()
+                } else if scheme.eq_ignore_ascii_case("basic") {
+                    basic.as_deref()
+                } else {
+                    None
+                };
+                expected.is_some_and(|x| constant_time_eq(x, credentials.trim()))
+            })
+    }
+
+    /// `401` with the `WWW-Authenticate` challenge
+    pub(crate) fn unauthorized(&self) -> HttpResponse {
+        let challenge = if self.basic.is_some() {
+            "Basic realm=\"metrics\""
+        } else {
+            "Bearer"
+        };
+
+        HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, challenge))
+            .finish()
+    }
+}
+
+/// Compare the credentials in time independent of the position of the first difference, so it
+/// can't be guessed byte by byte. Only the length leaks
+fn constant_time_eq(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// TLS config from the PEM certificate chain and private key
+pub fn tls_config(cert: &Path, key: &Path) -> Result<rustls::ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|x| x.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Invalid TLS certificate {}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Invalid TLS key {}", key.display()))?;
+
+    Ok(rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?)
+}
+
+/// Shared by the workers of the metrics endpoint
+struct MetricsState {
+    handle: PrometheusHandle,
+    auth: MetricsAuth,
+}
+
+async fn metrics(request: HttpRequest, state: web::Data<MetricsState>) -> impl Responder {
+    if !state.auth.authorized(&request) {
+        counter!("internal.metrics_endpoint.unauthorized").increment(1);
+        return state.auth.unauthorized();
+    }
+
+    state.handle.run_upkeep();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(state.handle.render())
+}
+
+/// Routes of the metrics endpoint. Other paths get `404`, other methods `405`
+pub(crate) fn routes(config: &mut web::ServiceConfig, trigger: Option<web::Data<ScrapeTrigger>>) {
+    config.service(web::resource("/metrics").route(web::get().to(metrics)));
+
+    if let Some(trigger) = trigger {
+        config
+            .app_data(trigger)
+            .service(web::resource("/scrape").route(web::post().to(trigger::scrape)));
+    }
+}
+
+impl MetricsServer {
+    pub async fn serve(self, addr: SocketAddr, cancel_token: CancellationToken) -> Result<()> {
+        let state = web::Data::new(MetricsState {
+            handle: self.handle,
+            auth: self.auth,
+        });
+        let trigger = self.trigger.map(web::Data::new);
+
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .configure(|config| routes(config, trigger.clone()))
+        })
+        .disable_signals()
+        .workers(WORKERS)
+        .max_connections(MAX_CONNECTIONS)
+        .client_request_timeout(REQUEST_TIMEOUT)
+        .client_disconnect_timeout(REQUEST_TIMEOUT)
+        .tls_handshake_timeout(REQUEST_TIMEOUT);
+
+        let server = match self.tls {
+            Some(tls) => server.bind_rustls_0_23(addr, tls)?,
+            None => server.bind(addr)?,
+        }
+        .run();
+
+        let handle = server.handle();
+        select! {
+            result = server => result?,
+            _ = cancel_token.cancelled() => handle.stop(true).await,
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::TestRequest};
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> HttpRequest {
+        let mut request = TestRequest::get().uri("/metrics");
+        if let Some(authorization) = authorization {
+            request = request.insert_header((header::AUTHORIZATION, authorization));
+        }
+        request.to_http_request()
+    }
+
+    async fn status(auth: MetricsAuth, request: TestRequest) -> StatusCode {
+        let state = web::Data::new(MetricsState {
+            handle: PrometheusBuilder::new().build_recorder().handle(),
+            auth,
+        });
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(state)
+                .configure(|config| routes(config, None)),
+        )
+        .await;
+
+        actix_web::test::call_service(&app, request.to_request())
+            .await
+            .status()
+    }
+
+    #[test]
+    fn disabled_auth_accepts_any_request() {
+        assert!(MetricsAuth::default().authorized(&request(None)));
+    }
+
+    #[test]
+    fn bearer_token() {
+        let auth = MetricsAuth {
+            token: Some("secret".to_string()),
+            basic: None,
+        };
+
+        assert!(auth.authorized(&request(Some("Bearer secret"))));
+        assert!(auth.authorized(&request(Some("bearer secret"))));
+        assert!(!auth.authorized(&request(Some("Bearer secreT"))));
+        assert!(!auth.authorized(&request(Some("Bearer secret2"))));
+        assert!(!auth.authorized(&request(Some("Basic secret"))));
+        assert!(!auth.authorized(&request(None)));
+    }
+
+    #[test]
+    fn basic_credentials() {
+        let auth = MetricsAuth {
+            token: None,
+            basic: Some(("prometheus".to_string(), "pass".to_string())),
+        };
+        // base64 of prometheus:pass
+        let valid = "cHJvbWV0aGV1czpwYXNz";
+
+        assert!(auth.authorized(&request(Some(&format!("Basic {valid}")))));
+        assert!(auth.authorized(&request(Some(&format!("BASIC {valid}")))));
+        assert!(!auth.authorized(&request(Some("Basic cHJvbWV0aGV1czp3cm9uZw=="))));
+        assert!(!auth.authorized(&request(Some(&format!("Bearer {valid}")))));
+        assert!(!auth.authorized(&request(None)));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_value() {
+        assert!(constant_time_eq("abc", "abc"));
+        assert!(!constant_time_eq("abc", "abd"));
+        assert!(!constant_time_eq("abc", "ab"));
+        assert!(!constant_time_eq("", "a"));
+    }
+
+    #[actix_web::test]
+    async fn metrics_route() {
+        let auth = MetricsAuth {
+            token: Some("secret".to_string()),
+            basic: None,
+        };
+        let get = || TestRequest::get().uri("/metrics");
+
+        let authorized = get().insert_header((header::AUTHORIZATION, "Bearer secret"));
+        assert_eq!(status(auth.clone(), authorized).await, StatusCode::OK);
+        assert_eq!(status(auth.clone(), get()).await, StatusCode::UNAUTHORIZED);
+
+        let other_path = TestRequest::get().uri("/");
+        assert_eq!(
+            status(auth.clone(), other_path).await,
+            StatusCode::NOT_FOUND
+        );
+
+        let other_method = TestRequest::post().uri("/metrics");
+        assert_eq!(
+            status(auth, other_method).await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+}
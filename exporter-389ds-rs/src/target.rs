@@ -0,0 +1,174 @@
+use std::future::Future;
+
+use metrics::{
+    Counter, Gauge, Histogram, Key, KeyName, Label, Metadata, Recorder, SharedString, Unit,
+};
+use tokio::task::JoinHandle;
+use tokio_util::task::TaskTracker;
+
+tokio::task_local! {
+    /// `ldap_uri` of the target scraped by the current task. None if there is a single target,
+    /// labeled globally
+    static LDAP_URI: Option<String>;
+}
+
+/// Tracker spawning the tasks of the single target. Metrics registered by the tasks are labeled
+/// with the target `ldap_uri` (if set)
+#[derive(Clone)]
+pub struct TargetTracker {
+    tracker: TaskTracker,
+    ldap_uri: Option<String>,
+}
+
+impl TargetTracker {
+    pub fn new(tracker: TaskTracker, ldap_uri: Option<String>) -> Self {
+        Self { tracker, ldap_uri }
+    }
+
+    pub fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tracker
+            .spawn(LDAP_URI.scope(self.ldap_uri.clone(), task))
+    }
+}
+
+/// Recorder adding `ldap_uri` label of the current task target
+#[derive(Debug)]
+pub struct TargetRecorder<R> {
+    inner: R,
+}
+
+impl<R: Recorder> TargetRecorder<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+
+    fn key(key: &Key) -> Option<Key> {
+        let ldap_uri = LDAP_URI.try_with(|x| x.clone()).ok().flatten()?;
+        Some(key.with_extra_labels(vec![Label::new("ldap_uri", ldap_uri)]))
+    }
+}
+
+impl<R: Recorder> Recorder for TargetRecorder<R> {
+    fn describe_counter(&self, key_name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_counter(key_name, unit, description)
+    }
+
+    fn describe_gauge(&self, key_name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_gauge(key_name, unit, description)
+    }
+
+    fn describe_histogram(&self, key_name: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_histogram(key_name, unit, description)
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        match Self::key(key) {
+            Some(key) => self.inner.register_counter(&key, metadata),
+            None => self.inner.register_counter(key, metadata),
+        }
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        match Self::key(key) {
+            Some(key) => self.inner.register_gauge(&key, metadata),
+            None => self.inner.register_gauge(key, metadata),
+        }
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        match Self::key(key) {
+            Some(key) => self.inner.register_histogram(&key, metadata),
+            None => self.inner.register_histogram(key, metadata),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use metrics::Level;
+
+    use super::*;
+
+    /// Keeps the keys of the registered metrics
+    #[derive(Default, Clone)]
+    struct KeysRecorder(Arc<Mutex<Vec<Key>>>);
+
+    impl Recorder for KeysRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            self.0.lock().unwrap().push(key.clone());
+            Counter::noop()
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            self.0.lock().unwrap().push(key.clone());
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            self.0.lock().unwrap().push(key.clone());
+            Histogram::noop()
+        }
+    }
+
+    fn labels(key: &Key) -> Vec<(String, String)> {
+        key.labels()
+            .map(|x| (x.key().to_string(), x.value().to_string()))
+            .collect()
+    }
+
+    fn metadata() -> Metadata<'static> {
+        Metadata::new(module_path!(), Level::INFO, None)
+    }
+
+    #[tokio::test]
+    async fn metrics_of_target_tasks_carry_ldap_uri() {
+        let keys = KeysRecorder::default();
+        let recorder = Arc::new(TargetRecorder::new(keys.clone()));
+        let tracker = TaskTracker::new();
+
+        let target = TargetTracker::new(tracker.clone(), Some("ldap://ldap1".to_string()));
+        let task_recorder = recorder.clone();
+        target
+            .spawn(async move {
+                let _ = task_recorder.register_gauge(
+                    &Key::from_parts("health", vec![Label::new("name", "q")]),
+                    &metadata(),
+                );
+            })
+            .await
+            .unwrap();
+
+        let single = TargetTracker::new(tracker.clone(), None);
+        let task_recorder = recorder.clone();
+        single
+            .spawn(async move {
+                let _ = task_recorder.register_gauge(&Key::from_name("health"), &metadata());
+            })
+            .await
+            .unwrap();
+
+        // Outside of the target task
+        let _ = recorder.register_counter(&Key::from_name("outside"), &metadata());
+
+        let keys = keys.0.lock().unwrap();
+        assert_eq!(
+            labels(&keys[0]),
+            vec![
+                ("name".to_string(), "q".to_string()),
+                ("ldap_uri".to_string(), "ldap://ldap1".to_string())
+            ]
+        );
+        assert!(labels(&keys[1]).is_empty());
+        assert!(labels(&keys[2]).is_empty());
+    }
+}
@@ -0,0 +1,186 @@
+use std::{sync::Mutex, time::Duration};
+
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use metrics::counter;
+use tokio::{sync::watch, time::Instant};
+
+use crate::server::MetricsAuth;
+
+/// `POST /scrape` of the metrics endpoint, waking all the scrapers before their next interval.
+/// Requires `Authorization: Bearer <token>` header
+pub struct ScrapeTrigger {
+    auth: MetricsAuth,
+    /// Requests received earlier than this after the last accepted one are rejected (429)
+    min_interval: Duration,
+    /// Number of the accepted requests. Scrapers watch it, so a request received during their
+    /// scrape starts the next one as soon as it finishes
+    generation: watch::Sender<u64>,
+    last_trigger: Mutex<Option<Instant>>,
+}
+
+enum Response {
+    Accepted,
+    Unauthorized,
+    TooManyRequests(u64),
+}
+
+impl Response {
+    fn name(&self) -> &'static str {
+        match self {
+            Response::Accepted => "accepted",
+            Response::Unauthorized => "unauthorized",
+            Response::TooManyRequests(_) => "rate_limited",
+        }
+    }
+}
+
+impl ScrapeTrigger {
+    pub fn new(token: String, min_interval: Duration, generation: watch::Sender<u64>) -> Self {
+        Self {
+            auth: MetricsAuth {
+                token: Some(token),
+                basic: None,
+            },
+            min_interval,
+            generation,
+            last_trigger: Mutex::new(None),
+        }
+    }
+
+    fn handle(&self, request: &HttpRequest) -> Response {
+        if !self.auth.authorized(request) {
+            return Response::Unauthorized;
+        }
+
+        let mut last_trigger = self.last_trigger.lock().unwrap();
+        if let Some(last) = *last_trigger {
+            let elapsed = last.elapsed();
+            if elapsed < self.min_interval {
+                return Response::TooManyRequests((self.min_interval - elapsed).as_secs() + 1);
+            }
+        }
+
+        tracing::info!("Scrape triggered on demand");
+        *last_trigger = Some(Instant::now());
+        self.generation.send_modify(|generation| *generation += 1);
+        Response::Accepted
+    }
+}
+
+pub(crate) async fn scrape(
+    request: HttpRequest,
+    trigger: web::Data<ScrapeTrigger>,
+) -> HttpResponse {
+    let response = trigger.handle(&request);
+    counter!("internal.scrape_trigger.requests", "result" => response.name()).increment(1);
+
+    match response {
+        Response::Accepted => HttpResponse::Accepted().finish(),
+        Response::Unauthorized => trigger.auth.unauthorized(),
+        Response::TooManyRequests(retry_after) => HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, retry_after))
+            .finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::TestRequest, App};
+
+    use super::*;
+    use crate::server::routes;
+
+    async fn responses(
+        trigger: Option<ScrapeTrigger>,
+        requests: Vec<TestRequest>,
+    ) -> Vec<StatusCode> {
+        let trigger = trigger.map(web::Data::new);
+        let app = actix_web::test::init_service(
+            App::new().configure(|config| routes(config, trigger.clone())),
+        )
+        .await;
+
+        let mut statuses = Vec::new();
+        for request in requests {
+            let response = actix_web::test::call_service(&app, request.to_request()).await;
+            statuses.push(response.status());
+        }
+        statuses
+    }
+
+    fn post(token: Option<&str>) -> TestRequest {
+        let mut request = TestRequest::post().uri("/scrape");
+        if let Some(token) = token {
+            request = request.insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+        }
+        request
+    }
+
+    #[actix_web::test]
+    async fn unauthorized_requests_do_not_trigger() {
+        let (sender, receiver) = watch::channel(0);
+        let trigger = ScrapeTrigger::new("secret".to_string(), Duration::ZERO, sender);
+
+        let statuses = responses(Some(trigger), vec![post(None), post(Some("wrong"))]).await;
+
+        assert_eq!(statuses, vec![StatusCode::UNAUTHORIZED; 2]);
+        assert_eq!(*receiver.borrow(), 0);
+    }
+
+    #[actix_web::test]
+    async fn accepted_request_is_not_lost() {
+        let (sender, receiver) = watch::channel(0);
+        // Scraper busy during the request
+        let mut scraper = receiver.clone();
+        let trigger = ScrapeTrigger::new("secret".to_string(), Duration::ZERO, sender);
+
+        let statuses = responses(Some(trigger), vec![post(Some("secret"))]).await;
+
+        assert_eq!(statuses, vec![StatusCode::ACCEPTED]);
+        // Sees the request once it finishes, even though it was not waiting for it
+        assert_eq!(scraper.changed().await.ok(), Some(()));
+        assert_eq!(*scraper.borrow_and_update(), 1);
+    }
+
+    #[actix_web::test]
+    async fn requests_are_rate_limited() {
+        let (sender, receiver) = watch::channel(0);
+        let trigger = ScrapeTrigger::new("secret".to_string(), Duration::from_secs(60), sender);
+
+        let statuses = responses(
+            Some(trigger),
+            vec![post(Some("secret")), post(Some("secret"))],
+        )
+        .await;
+
+        assert_eq!(
+            statuses,
+            vec![StatusCode::ACCEPTED, StatusCode::TOO_MANY_REQUESTS]
+        );
+        assert_eq!(*receiver.borrow(), 1);
+    }
+
+    #[actix_web::test]
+    async fn disabled_or_unknown_routes() {
+        let (sender, _receiver) = watch::channel(0);
+        let trigger = ScrapeTrigger::new("secret".to_string(), Duration::ZERO, sender);
+
+        let statuses = responses(
+            Some(trigger),
+            vec![
+                TestRequest::post()
+                    .uri("/trigger")
+                    .insert_header((header::AUTHORIZATION, "Bearer secret")),
+                TestRequest::get().uri("/scrape"),
+            ],
+        )
+        .await;
+        assert_eq!(
+            statuses,
+            vec![StatusCode::NOT_FOUND, StatusCode::METHOD_NOT_ALLOWED]
+        );
+
+        let statuses = responses(None, vec![post(Some("secret"))]).await;
+        assert_eq!(statuses, vec![StatusCode::NOT_FOUND]);
+    }
+}
@@ -25,6 +25,10 @@ pub struct Args {
     #[clap(short = 'C', long, default_value_t = false)]
     pub skip_cert_verification: bool,
 
+    /// Upgrade ldap:// connection with StartTLS before bind
+    #[clap(long, default_value_t = false)]
+    pub start_tls: bool,
+
     #[clap(short = 'a', long)]
     pub expose_address: Option<String>,
 
@@ -55,4 +59,8 @@ pub struct Args {
     #[clap(short = 'd', long)]
     #[clap(value_enum)]
     pub disable_flags: Vec<ArgFlag>,
+
+    /// Validate the configuration, print the problems and exit without connecting
+    #[clap(long, default_value_t = false)]
+    pub check_config: bool,
 }
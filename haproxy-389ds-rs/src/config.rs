@@ -14,6 +14,18 @@ fn default_expose_port() -> u16 {
     6699
 }
 
+fn default_max_tcp_connections() -> usize {
+    16
+}
+
+fn default_max_tcp_message_bytes() -> usize {
+    10000
+}
+
+fn default_tcp_read_timeout_ms() -> u64 {
+    5000
+}
+
 fn default_expose_address() -> String {
     "0.0.0.0".to_string()
 }
@@ -27,6 +39,21 @@ pub struct Config {
     pub common: internal::config::CommonConfig,
 }
 
+impl Config {
+    /// Problems which are not caught by the deserialization. Empty if the config is valid
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = self.common.validate();
+        problems.extend(self.common.validate_query_refs(
+            "haproxy.query",
+            self.haproxy.query.values().map(|query| query.name()),
+        ));
+        if self.haproxy.max_tcp_connections == 0 {
+            problems.push("haproxy.max_tcp_connections has to be positive".to_string());
+        }
+        problems
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct ScrapeIntervalSeconds {
     pub replication_status: u64,
@@ -63,11 +90,22 @@ pub struct CounterAttrsHaproxyQuery {
     pub attr: String,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct AttrEqualsHaproxyQuery {
+    #[serde(flatten)]
+    pub base: BaseHaproxyQuery,
+    /// Attribute of the first returned entry
+    pub attr: String,
+    pub expected: String,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct BaseHaproxyQuery {
     pub name: String,
     pub max_entries: Option<usize>,
     pub scrape_interval_seconds: Option<u64>,
+    /// Overrides `ok_codes` of the query definition
+    pub ok_codes: Option<Vec<u32>>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -76,6 +114,7 @@ pub struct BaseHaproxyQuery {
 pub enum HaproxyQuery {
     CountEntries(CounterHaproxyQuery),
     CountAttrs(CounterAttrsHaproxyQuery),
+    AttrEquals(AttrEqualsHaproxyQuery),
     Success(BaseHaproxyQuery),
 }
 
@@ -86,6 +125,9 @@ impl HaproxyQuery {
             HaproxyQuery::CountAttrs(counter_haproxy_query) => {
                 &counter_haproxy_query.counter.base.name
             }
+            HaproxyQuery::AttrEquals(attr_equals_haproxy_query) => {
+                &attr_equals_haproxy_query.base.name
+            }
             HaproxyQuery::Success(base_haproxy_query) => &base_haproxy_query.name,
         }
     }
@@ -98,6 +140,9 @@ impl HaproxyQuery {
             HaproxyQuery::CountAttrs(counter_haproxy_query) => {
                 counter_haproxy_query.counter.base.max_entries
             }
+            HaproxyQuery::AttrEquals(attr_equals_haproxy_query) => {
+                attr_equals_haproxy_query.base.max_entries
+            }
             HaproxyQuery::Success(base_haproxy_query) => base_haproxy_query.max_entries,
         }
     }
@@ -110,9 +155,27 @@ impl HaproxyQuery {
             HaproxyQuery::CountAttrs(counter_haproxy_query) => {
                 counter_haproxy_query.counter.base.scrape_interval_seconds
             }
+            HaproxyQuery::AttrEquals(attr_equals_haproxy_query) => {
+                attr_equals_haproxy_query.base.scrape_interval_seconds
+            }
             HaproxyQuery::Success(base_haproxy_query) => base_haproxy_query.scrape_interval_seconds,
         }
     }
+
+    pub fn ok_codes(&self) -> Option<&Vec<u32>> {
+        match self {
+            HaproxyQuery::CountEntries(counter_haproxy_query) => {
+                counter_haproxy_query.base.ok_codes.as_ref()
+            }
+            HaproxyQuery::CountAttrs(counter_haproxy_query) => {
+                counter_haproxy_query.counter.base.ok_codes.as_ref()
+            }
+            HaproxyQuery::AttrEquals(attr_equals_haproxy_query) => {
+                attr_equals_haproxy_query.base.ok_codes.as_ref()
+            }
+            HaproxyQuery::Success(base_haproxy_query) => base_haproxy_query.ok_codes.as_ref(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -126,6 +189,18 @@ pub struct HaproxyConfig {
     #[serde(default = "default_expose_address")]
     pub expose_address: String,
 
+    /// Agent-check connections handled concurrently. Further ones wait for a free slot
+    #[serde(default = "default_max_tcp_connections")]
+    pub max_tcp_connections: usize,
+
+    /// Max size of the agent-check command (without newline)
+    #[serde(default = "default_max_tcp_message_bytes")]
+    pub max_tcp_message_bytes: usize,
+
+    /// Time for the client to send the agent-check command
+    #[serde(default = "default_tcp_read_timeout_ms")]
+    pub tcp_read_timeout_ms: u64,
+
     #[serde(default)]
     pub scrape_interval_seconds: ScrapeIntervalSeconds,
 
@@ -134,6 +209,22 @@ pub struct HaproxyConfig {
 
     #[serde(default)]
     pub query: HashMap<String, HaproxyQuery>,
+
+    /// Node fails when the number of current connections (cn=monitor) exceeds this value
+    #[serde(default)]
+    pub max_connections: Option<u64>,
+
+    /// Weight is lowered linearly from 100% at this number of current connections...
+    #[serde(default)]
+    pub weight_soft_max_connections: Option<u64>,
+
+    /// ...to 0% at this one. Both soft and hard max are required to scale the weight
+    #[serde(default)]
+    pub weight_hard_max_connections: Option<u64>,
+    /// Node fails when the last change of any replica in the agreement RUVs is older than this.
+    /// Requires replication_status scrape flag
+    #[serde(default)]
+    pub max_ruv_age_seconds: Option<i64>,
 }
 
 impl Default for HaproxyConfig {
@@ -145,6 +236,13 @@ impl Default for HaproxyConfig {
             scrape_flags: ScrapeFlags::default(),
             query: Default::default(),
             expose_tcp_port: default_expose_tcp_port(),
+            max_tcp_connections: default_max_tcp_connections(),
+            max_tcp_message_bytes: default_max_tcp_message_bytes(),
+            tcp_read_timeout_ms: default_tcp_read_timeout_ms(),
+            max_connections: None,
+            weight_soft_max_connections: None,
+            weight_hard_max_connections: None,
+            max_ruv_age_seconds: None,
         }
     }
 }
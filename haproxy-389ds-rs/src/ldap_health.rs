@@ -1,4 +1,4 @@
-use crate::haproxy;
+use crate::{config::HaproxyConfig, haproxy};
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
@@ -21,9 +21,29 @@ pub struct NodeDisabled {
 #[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToSchema)]
 pub struct LdapStatus {
     pub is_systemd_running: bool,
+    /// State of the systemd unit (e.g. `failed`, `not found`). None if it was not checked
+    pub systemd_state: Option<String>,
     pub is_reachable: bool,
+    /// Server accepts connections, but is not ready to serve requests yet
+    pub is_starting_up: bool,
     pub connection_number: Option<u64>,
     pub queries_status: HashMap<String, bool>,
+    /// Reason of the failure of the query, e.g. observed value
+    pub queries_failures: HashMap<String, String>,
+    /// Unhealthy (non-green) or lagging agreements
+    pub replication_problems: Vec<String>,
+}
+
+impl LdapStatus {
+    /// `failure` is the reason of the failure, None if the query passed
+    pub fn set_query_status(&mut self, query: &str, failure: Option<String>) {
+        self.queries_status
+            .insert(query.to_string(), failure.is_none());
+        match failure {
+            Some(failure) => self.queries_failures.insert(query.to_string(), failure),
+            None => self.queries_failures.remove(query),
+        };
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, utoipa::ToResponse, utoipa::ToSchema)]
@@ -49,25 +69,38 @@ impl Health {
             },
             status: LdapStatus {
                 is_systemd_running: false,
+                systemd_state: None,
                 is_reachable: false,
+                is_starting_up: false,
                 connection_number: None,
                 queries_status: HashMap::new(),
+                queries_failures: HashMap::new(),
+                replication_problems: Vec::new(),
             },
         }
     }
 
     /// to_haproxy_string errors
-    fn _ths_errors(&self, response: &mut haproxy::Response, recover: &mut bool) {
+    fn _ths_errors(
+        &self,
+        response: &mut haproxy::Response,
+        config: &HaproxyConfig,
+        recover: &mut bool,
+    ) {
         let failed_queries =
             self.status
                 .queries_status
                 .iter()
                 .fold(None, |acc, (query, status)| {
                     if !status {
+                        let query = match self.status.queries_failures.get(query) {
+                            Some(failure) => format!("{query} ({failure})"),
+                            None => query.to_string(),
+                        };
                         if let Some(acc) = acc {
                             Some(format!("{}, {}", acc, query))
                         } else {
-                            Some(query.to_string())
+                            Some(query)
                         }
                     } else {
                         acc
@@ -81,6 +114,31 @@ impl Health {
             )));
         }
 
+        if let Some((connections, max)) = self
+            .status
+            .connection_number
+            .zip(config.max_connections)
+            .filter(|(connections, max)| connections > max)
+        {
+            *recover = false;
+            response.fail(Some(&format!(
+                "too many connections: {connections} > {max}"
+            )));
+        }
+
+        if !self.status.replication_problems.is_empty() {
+            *recover = false;
+            response.fail(Some(&format!(
+                "replication problems: {}",
+                self.status.replication_problems.join(", ")
+            )));
+        }
+
+        if self.status.is_starting_up {
+            *recover = false;
+            response.down(Some("ldap is starting up"));
+        }
+
         if !self.status.is_reachable {
             *recover = false;
             response.fail(Some("ldap is not reachable"));
@@ -88,11 +146,30 @@ impl Health {
 
         if !self.status.is_systemd_running {
             *recover = false;
-            response.fail(Some("dirsrv@default systemd unit is not running"));
+            let reason = match &self.status.systemd_state {
+                Some(state) => format!("dirsrv@default systemd unit is not running ({state})"),
+                None => "dirsrv@default systemd unit is not running".to_string(),
+            };
+            response.fail(Some(&reason));
+        }
+    }
+
+    /// Weight (%) based on the current connections and the soft/hard max from the config
+    fn connection_weight(&self, config: &HaproxyConfig) -> Option<u64> {
+        let connections = self.status.connection_number?;
+        let soft_max = config.weight_soft_max_connections?;
+        let hard_max = config.weight_hard_max_connections?;
+
+        if connections <= soft_max {
+            Some(100)
+        } else if connections >= hard_max {
+            Some(0)
+        } else {
+            Some(100 * (hard_max - connections) / (hard_max - soft_max))
         }
     }
 
-    pub fn evaluate(&self, response: &mut haproxy::Response) {
+    pub fn evaluate(&self, response: &mut haproxy::Response, config: &HaproxyConfig) {
         let mut recover = true;
 
         // Allow errors to override drain status
@@ -107,7 +184,7 @@ impl Health {
             recover = false;
         }
 
-        self._ths_errors(response, &mut recover);
+        self._ths_errors(response, config, &mut recover);
 
         // Skip errors in case of hard maintenance
         if self.disabled.mark_hard_maint {
@@ -123,6 +200,9 @@ impl Health {
 
         if recover {
             response.up_and_ready();
+            if let Some(weight) = self.connection_weight(config) {
+                response.weight = Some(weight);
+            }
         }
     }
 }
@@ -8,14 +8,14 @@ use anyhow::{Context, Result};
 use clap::Parser;
 use cli::{ArgFlag, Args};
 use config::Config;
-use internal::{Bind, query::CustomQuery};
+use internal::{BindMethod, cli::SystemdState, monitor::Readiness, query::CustomQuery};
 use ldap_health::Health;
 use std::sync::Arc;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
     select,
-    sync::Mutex,
+    sync::{Mutex, Semaphore},
 };
 use tokio_util::{sync::CancellationToken, task::TaskTracker};
 
@@ -35,7 +35,8 @@ impl AppStateBase {
     }
 
     pub fn evaluate(&mut self) {
-        self.health.evaluate(&mut self.current_reponse);
+        self.health
+            .evaluate(&mut self.current_reponse, &self.config.haproxy);
     }
 }
 
@@ -47,11 +48,15 @@ pub async fn accessibility_loop(
     tracing::info!("Starting 389ds accessibility checks");
 
     loop {
-        if let Err(error) = check_ldap_connection(&config).await {
-            tracing::error!("Error: {}", error);
-            app_state.lock().await.health.status.is_reachable = false;
-        } else {
-            app_state.lock().await.health.status.is_reachable = true;
+        let readiness = Readiness::probe(&config.common.ldap_config).await;
+        if let Some(reason) = readiness.reason() {
+            tracing::error!("Server {}: {}", readiness.name(), reason);
+        }
+
+        {
+            let status = &mut app_state.lock().await.health.status;
+            status.is_reachable = !matches!(readiness, Readiness::Down(_));
+            status.is_starting_up = matches!(readiness, Readiness::StartingUp(_));
         }
 
         select! {
@@ -67,6 +72,129 @@ pub async fn accessibility_loop(
     }
 }
 
+pub async fn ldap_monitoring_loop(
+    config: Config,
+    app_state: AppState,
+    cancel_token: CancellationToken,
+) {
+    tracing::info!("Starting 389ds monitoring checks");
+
+    loop {
+        let ldap_config = &config.common.ldap_config;
+        let connections = internal::with_timeout(
+            ldap_config.operation_timeout_seconds,
+            "Monitor scrape",
+            async {
+                let mut ldap = ldap_config.connect_with_retry().await?;
+                internal::monitor::LdapMonitor::scrape(&mut ldap)
+                    .await
+                    .map(|x| x.int_metrics.get("currentconnections").copied())
+            },
+        )
+        .await;
+
+        let connections = connections.unwrap_or_else(|error| {
+            tracing::error!("Monitor scrape error: {}", error);
+            None
+        });
+        app_state.lock().await.health.status.connection_number = connections;
+
+        select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(
+                config.haproxy.scrape_interval_seconds.ldap_monitoring,
+            )) => {
+
+            },
+            _ = cancel_token.cancelled() => {
+                break
+            }
+        }
+    }
+}
+
+/// Problems of the enabled agreements: failed status or RUV older than `max_ruv_age_seconds`
+async fn replication_problems(config: &Config) -> Result<Vec<String>> {
+    let mut ldap = config.common.ldap_config.connect_with_retry().await?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    let mut problems = Vec::new();
+
+    for agreement in internal::replica::Agreement::scrape(&mut ldap).await? {
+        if !agreement.enabled {
+            continue;
+        }
+
+        // Transient states (e.g. busy replica) would make the node flap
+        if !agreement.status.is_healthy() && !agreement.status.is_transient() {
+            problems.push(format!(
+                "agreement {} is {}: {}",
+                agreement.cn,
+                agreement.status.state,
+                agreement.status.reason()
+            ));
+        }
+
+        if let Some(max_age) = config.haproxy.max_ruv_age_seconds {
+            for ruv in &agreement.ruvs {
+                let stale = ruv
+                    .last_change_timestamp()
+                    .filter(|timestamp| now - timestamp > max_age);
+                if let Some(timestamp) = stale {
+                    problems.push(format!(
+                        "agreement {} replica {} last change {}s ago",
+                        agreement.cn,
+                        ruv.get_replica_id(),
+                        now - timestamp
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+pub async fn replication_status_loop(
+    config: Config,
+    app_state: AppState,
+    cancel_token: CancellationToken,
+) {
+    tracing::info!("Starting replication status checks");
+
+    loop {
+        let problems = internal::with_timeout(
+            config.common.ldap_config.operation_timeout_seconds,
+            "Replication scrape",
+            replication_problems(&config),
+        )
+        .await;
+
+        // Only the agreement problems fail the node. Scrape errors (e.g. the server is not
+        // reachable) are reported by the other checks, the last known problems are kept
+        match problems {
+            Ok(problems) => {
+                for problem in &problems {
+                    tracing::error!("Replication: {}", problem);
+                }
+                app_state.lock().await.health.status.replication_problems = problems;
+            }
+            Err(error) => tracing::error!("Replication scrape failed: {}", error),
+        }
+
+        select! {
+            _ = tokio::time::sleep(tokio::time::Duration::from_secs(
+                config.haproxy.scrape_interval_seconds.replication_status,
+            )) => {
+
+            },
+            _ = cancel_token.cancelled() => {
+                break
+            }
+        }
+    }
+}
+
 pub async fn systemd_status_loop(
     config: Config,
     app_state: AppState,
@@ -75,22 +203,23 @@ pub async fn systemd_status_loop(
     tracing::info!("Starting systemd status checks");
 
     loop {
-        let instance = config.common.scrapers.dsctl.instance_name.clone();
-        let timeout_seconds = config.common.scrapers.dsctl.timeout_seconds;
-        let cli_config = internal::cli::CommandConfig::new(timeout_seconds, instance);
+        let cli_config = config.common.scrapers.dsctl.clone();
 
-        match cli_config.systemd_running().await {
+        match cli_config.systemd_state().await {
             Err(error) => {
                 tracing::error!("Error: {}", error);
-                app_state.lock().await.health.status.is_systemd_running = false;
+                let status = &mut app_state.lock().await.health.status;
+                status.is_systemd_running = false;
+                status.systemd_state = None;
             }
-            Ok(x) => {
-                if x {
-                    app_state.lock().await.health.status.is_systemd_running = true;
-                } else {
-                    tracing::error!("Systemd is not running");
-                    app_state.lock().await.health.status.is_systemd_running = false;
+            Ok(state) => {
+                let running = state == SystemdState::Active;
+                if !running {
+                    tracing::error!("Systemd unit is {}", state);
                 }
+                let status = &mut app_state.lock().await.health.status;
+                status.is_systemd_running = running;
+                status.systemd_state = Some(state.to_string());
             }
         }
 
@@ -111,34 +240,62 @@ pub async fn systemd_status_loop(
 
 pub type AppState = Arc<Mutex<AppStateBase>>;
 
-pub async fn check_ldap_connection(config: &config::Config) -> Result<()> {
-    config.common.ldap_config.connect().await?;
-    Ok(())
+/// First value of the attribute. Attribute names are case insensitive, so the server may return
+/// them in a different case than configured
+fn first_value<'a>(
+    attrs: &'a std::collections::HashMap<String, Vec<String>>,
+    attr: &str,
+) -> Option<&'a String> {
+    attrs
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(attr))
+        .and_then(|(_, values)| values.first())
 }
 
+/// Returns the reason of the failure, None if the query passed
 pub async fn handle_query(
     mut query: CustomQuery,
     haproxy_query: &config::HaproxyQuery,
-) -> Result<bool> {
-    if let config::HaproxyQuery::CountAttrs(count_entries) = haproxy_query {
-        query.attrs = vec![count_entries.attr.clone()];
+) -> Result<Option<String>> {
+    match haproxy_query {
+        config::HaproxyQuery::CountAttrs(count_entries) => {
+            query.attrs = vec![count_entries.attr.clone()];
+        }
+        config::HaproxyQuery::AttrEquals(attr_equals) => {
+            query.attrs = vec![attr_equals.attr.clone()];
+            query.keep_entries = 1;
+        }
+        _ => {}
     }
 
     let metrics = query.get_metrics().await?;
 
+    if !query.is_ok_code(metrics.ldap_code) {
+        tracing::warn!(
+            "Query {} returned unexpected LDAP code {} ({})",
+            query.name,
+            metrics.ldap_code,
+            metrics.ldap_code_text
+        );
+        return Ok(Some(format!(
+            "unexpected LDAP code {} ({})",
+            metrics.ldap_code, metrics.ldap_code_text
+        )));
+    }
+
     match haproxy_query {
         config::HaproxyQuery::CountEntries(counter_haproxy_query) => {
             let value = metrics.object_count;
 
             if let Some(less_than) = counter_haproxy_query.less_than {
                 if value >= less_than {
-                    return Ok(false);
+                    return Ok(Some(format!("{value} entries")));
                 }
             }
 
             if let Some(greater_than) = counter_haproxy_query.greater_than {
                 if value <= greater_than {
-                    return Ok(false);
+                    return Ok(Some(format!("{value} entries")));
                 }
             }
         }
@@ -147,22 +304,37 @@ pub async fn handle_query(
 
             if let Some(less_than) = counter_haproxy_query.counter.less_than {
                 if value >= less_than {
-                    return Ok(false);
+                    return Ok(Some(format!("{value} attributes")));
                 }
             }
 
             if let Some(greater_than) = counter_haproxy_query.counter.greater_than {
                 if value <= greater_than {
-                    return Ok(false);
+                    return Ok(Some(format!("{value} attributes")));
                 }
             }
         }
+        config::HaproxyQuery::AttrEquals(attr_equals) => {
+            let observed = metrics
+                .entries
+                .first()
+                .and_then(|entry| first_value(&entry.attrs, &attr_equals.attr));
+
+            if observed != Some(&attr_equals.expected) {
+                return Ok(Some(format!(
+                    "{} is {} (expected {})",
+                    attr_equals.attr,
+                    observed.map(String::as_str).unwrap_or("missing"),
+                    attr_equals.expected
+                )));
+            }
+        }
         config::HaproxyQuery::Success(_) => {
             // query executed, we are happy
         }
     }
 
-    Ok(true)
+    Ok(None)
 }
 
 #[derive(Debug, Clone)]
@@ -213,12 +385,23 @@ pub async fn setup_queries_loops(
             trio.query_definition.max_entries = Some(max_entries as i32);
         }
 
+        if let Some(ok_codes) = trio.haproxy_query.ok_codes() {
+            trio.query_definition.ok_codes = ok_codes.clone();
+        }
+
         let app_state = app_state.clone();
         tracker.spawn(async move {
             trio.query_definition.ldap_config = Some(config.common.ldap_config.clone());
             let query_name = trio.named_check;
             loop {
-                match handle_query(trio.query_definition.clone(), &trio.haproxy_query).await {
+                let query = handle_query(trio.query_definition.clone(), &trio.haproxy_query);
+                match internal::with_timeout(
+                    config.common.ldap_config.operation_timeout_seconds,
+                    "Query",
+                    query,
+                )
+                .await
+                {
                     Err(e) => {
                         tracing::error!(
                             "Error executing query {} (scrape name: {}): {}",
@@ -231,17 +414,15 @@ pub async fn setup_queries_loops(
                             .await
                             .health
                             .status
-                            .queries_status
-                            .insert(query_name.to_string(), false);
+                            .set_query_status(&query_name, Some("error".to_string()));
                     }
-                    Ok(x) => {
+                    Ok(failure) => {
                         app_state
                             .lock()
                             .await
                             .health
                             .status
-                            .queries_status
-                            .insert(query_name.to_string(), x);
+                            .set_query_status(&query_name, failure);
                     }
                 }
 
@@ -262,33 +443,64 @@ pub async fn setup_queries_loops(
     }
 }
 
-async fn read_until_newline(stream: &mut TcpStream) -> Result<String> {
+/// Agent-check command could not be read
+#[derive(Debug)]
+pub enum ReadCommandError {
+    /// No newline received in time
+    Timeout,
+    /// No newline within the max message size
+    TooLarge(usize),
+}
+
+impl std::fmt::Display for ReadCommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadCommandError::Timeout => write!(f, "Timed out waiting for the command"),
+            ReadCommandError::TooLarge(max) => write!(f, "Command larger than {max} bytes"),
+        }
+    }
+}
+
+impl std::error::Error for ReadCommandError {}
+
+async fn read_until_newline(
+    stream: &mut TcpStream,
+    max_size: usize,
+    timeout: tokio::time::Duration,
+) -> Result<String> {
     let mut bytes: Vec<u8> = vec![];
+    // One extra byte for the newline
+    let mut reader = BufReader::new(stream.take(max_size as u64 + 1));
 
-    for _ in 0..10000 {
-        let byte = stream.read_u8().await.context(format!(
-            "Could not byte from the message. Currently processed {} bytes",
+    tokio::time::timeout(timeout, reader.read_until(b'\n', &mut bytes))
+        .await
+        .map_err(|_| ReadCommandError::Timeout)?
+        .context(format!(
+            "Could not read the message. Currently processed {} bytes",
             bytes.len()
         ))?;
 
-        if byte == b'\n' {
-            return Ok(String::from_utf8(bytes)?);
-        }
-
-        bytes.push(byte);
+    match bytes.pop() {
+        Some(b'\n') => Ok(String::from_utf8(bytes)?),
+        _ if bytes.len() >= max_size => Err(ReadCommandError::TooLarge(max_size).into()),
+        _ => Err(anyhow::anyhow!("Connection closed before newline")),
     }
-
-    Err(anyhow::anyhow!(
-        "Message larger than 10000. Cowardly exiting"
-    ))
 }
 
-async fn process_stream(mut stream: TcpStream, app_state: AppState) -> Result<()> {
-    let command = read_until_newline(&mut stream).await?;
+async fn process_stream(
+    mut stream: TcpStream,
+    app_state: AppState,
+    max_size: usize,
+    timeout: tokio::time::Duration,
+) -> Result<()> {
+    let command = read_until_newline(&mut stream, max_size, timeout).await?;
     if command == "ping" {
-        let mut data = app_state.lock().await;
-        data.evaluate();
-        let response = data.current_reponse.to_haproxy_string();
+        // Do not hold the lock while writing to the (possibly slow) client
+        let response = {
+            let mut data = app_state.lock().await;
+            data.evaluate();
+            data.current_reponse.to_haproxy_string()
+        };
 
         stream.writable().await.context("Could not wait to write")?;
         stream
@@ -305,7 +517,8 @@ async fn process_stream(mut stream: TcpStream, app_state: AppState) -> Result<()
 async fn tcp_server_loop(
     config: Config,
     app_state: AppState,
-    _cancel_token: CancellationToken,
+    cancel_token: CancellationToken,
+    tracker: TaskTracker,
 ) -> Result<()> {
     let addr = format!(
         "{}:{}",
@@ -314,15 +527,56 @@ async fn tcp_server_loop(
     let listener = TcpListener::bind(&addr).await?;
     tracing::info!("Starting tcp server. Listening on {}", &addr);
 
+    serve_tcp(listener, config, app_state, cancel_token, tracker).await
+}
+
+async fn serve_tcp(
+    listener: TcpListener,
+    config: Config,
+    app_state: AppState,
+    cancel_token: CancellationToken,
+    tracker: TaskTracker,
+) -> Result<()> {
+    // 0 is reported by the config validation. Semaphore without permits would never accept
+    let semaphore = Arc::new(Semaphore::new(config.haproxy.max_tcp_connections.max(1)));
+    let max_size = config.haproxy.max_tcp_message_bytes;
+    let timeout = tokio::time::Duration::from_millis(config.haproxy.tcp_read_timeout_ms);
+
     loop {
         let app_state = app_state.clone();
-        if let Err(e) = {
-            let (socket, _) = listener.accept().await?;
-            process_stream(socket, app_state).await
-        } {
-            tracing::error!("Error during tcp processing {:?}", e);
-        }
+        let permit = select! {
+            permit = semaphore.clone().acquire_owned() => permit?,
+            _ = cancel_token.cancelled() => break,
+        };
+        let socket = select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                // e.g. too many open files or connection reset before accept. Listener is fine
+                Err(error) => {
+                    tracing::error!("Error during tcp accept {:?}", error);
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+            _ = cancel_token.cancelled() => break,
+        };
+
+        tracker.spawn(async move {
+            match process_stream(socket, app_state, max_size, timeout).await {
+                Ok(()) => {}
+                // Usually a health check of the port itself (connect without a command)
+                Err(e) if matches!(e.downcast_ref(), Some(ReadCommandError::Timeout)) => {
+                    tracing::warn!("Tcp client did not send a command: {}", e);
+                }
+                Err(e) => tracing::error!("Error during tcp processing {:?}", e),
+            }
+            drop(permit);
+        });
     }
+
+    tracing::info!("Stopping tcp server");
+    drop(listener);
+    Ok(())
 }
 
 #[tokio::main]
@@ -340,13 +594,18 @@ async fn main() -> Result<()> {
         Default::default()
     };
 
+    config.common.ldap_config.apply_env()?;
+
     if let Some(page_size) = args.page_size {
         config.common.ldap_config.page_size = page_size;
     }
 
     if let Some(dn) = args.binddn {
         let pass = args.bindpass.unwrap();
-        let bind = Bind { dn, pass };
+        let bind = BindMethod::Simple {
+            dn,
+            pass: pass.into(),
+        };
         config.common.ldap_config.bind = Some(bind);
     }
 
@@ -370,8 +629,26 @@ async fn main() -> Result<()> {
         config.common.ldap_config.verify_certs = false;
     }
 
+    if args.start_tls {
+        config.common.ldap_config.start_tls = true;
+    }
+
+    let problems = config.validate();
+    if args.check_config {
+        internal::config::exit_with_problems(&problems);
+    }
+    for problem in &problems {
+        tracing::error!("Config problem: {}", problem);
+    }
+
     if config.common.ldap_config.default_base.is_empty() {
-        config.common.ldap_config.detect_base().await?;
+        let detected = config.common.ldap_config.detect_base().await?;
+        if detected.ambiguous {
+            tracing::warn!(
+                "Multiple naming contexts found ({}). Set default_query_base or base_hint to pick the right one",
+                detected.contexts.join("; ")
+            );
+        }
         tracing::info!("Set base to the {}", config.common.ldap_config.default_base);
     }
 
@@ -393,14 +670,31 @@ async fn main() -> Result<()> {
     let cancel_token_orig = CancellationToken::new();
     let app_state: AppState = Arc::new(Mutex::new(AppStateBase::new(config.clone())));
 
+    let cancel_token = cancel_token_orig.clone();
+    tracker.spawn(async move {
+        match internal::shutdown_signal().await {
+            Err(e) => {
+                tracing::error!("Failed to register signal handlers: {}", e);
+                tracing::warn!("Program will work. But killing it can be hard");
+                return;
+            }
+            Ok(signal) => tracing::info!("Received {}", signal),
+        };
+
+        tracing::info!("Shutting down");
+        cancel_token.cancel();
+    });
+
     let app_state_clone = app_state.clone();
     let config_clone = config.clone();
+    let cancel_token = cancel_token_orig.clone();
     let webserver_loop = tracker.spawn(async move {
         tracing::info!("Starting webserver");
         web::webserver(
             config_clone.haproxy.expose_address,
             config_clone.haproxy.expose_port,
             app_state_clone,
+            cancel_token,
         )
         .await
     });
@@ -412,6 +706,28 @@ async fn main() -> Result<()> {
         async move { accessibility_loop(config_clone, app_state_clone, cancel_token).await },
     );
 
+    let app_state_clone = app_state.clone();
+    let config_clone = config.clone();
+    let cancel_token = cancel_token_orig.clone();
+    if config.haproxy.scrape_flags.replication_status {
+        tracker.spawn(async move {
+            replication_status_loop(config_clone, app_state_clone, cancel_token).await
+        });
+    } else {
+        tracing::info!("Skipping replication status checks");
+    }
+
+    let app_state_clone = app_state.clone();
+    let config_clone = config.clone();
+    let cancel_token = cancel_token_orig.clone();
+    if config.haproxy.scrape_flags.ldap_monitoring {
+        tracker.spawn(async move {
+            ldap_monitoring_loop(config_clone, app_state_clone, cancel_token).await
+        });
+    } else {
+        tracing::info!("Skipping 389ds monitoring checks");
+    }
+
     let app_state_clone = app_state.clone();
     let config_clone = config.clone();
     let cancel_token = cancel_token_orig.clone();
@@ -437,14 +753,81 @@ async fn main() -> Result<()> {
     let config_clone = config.clone();
     let cancel_token = cancel_token_orig.clone();
     let app_state_clone = app_state.clone();
-    tracker
-        .spawn(async move { tcp_server_loop(config_clone, app_state_clone, cancel_token).await });
+    let tracker_clone = tracker.clone();
+    tracker.spawn(async move {
+        tcp_server_loop(config_clone, app_state_clone, cancel_token, tracker_clone).await
+    });
 
     tracing::info!("Awaiting close of the webserver_loop");
     webserver_loop.await?;
+    cancel_token_orig.cancel();
 
     tracker.close();
     tracker.wait().await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    async fn ping(addr: std::net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn first_value_ignores_attr_case() {
+        let attrs = HashMap::from([("nsslapd-readonly".to_string(), vec!["off".to_string()])]);
+
+        assert_eq!(
+            first_value(&attrs, "nsslapd-readOnly").map(String::as_str),
+            Some("off")
+        );
+        assert_eq!(first_value(&attrs, "nsslapd-missing"), None);
+    }
+
+    #[tokio::test]
+    async fn concurrent_pings_get_response() {
+        for max_tcp_connections in [0, 1, 16] {
+            let mut config = Config::default();
+            config.haproxy.max_tcp_connections = max_tcp_connections;
+            config.haproxy.tcp_read_timeout_ms = 1000;
+            let app_state: AppState = Arc::new(Mutex::new(AppStateBase::new(config.clone())));
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let cancel_token = CancellationToken::new();
+            let server = tokio::spawn(serve_tcp(
+                listener,
+                config,
+                app_state,
+                cancel_token.clone(),
+                TaskTracker::new(),
+            ));
+
+            let (first, second) =
+                tokio::time::timeout(tokio::time::Duration::from_secs(5), async {
+                    tokio::join!(ping(addr), ping(addr))
+                })
+                .await
+                .expect("both pings are answered");
+            assert!(
+                !first.is_empty(),
+                "max_tcp_connections = {max_tcp_connections}"
+            );
+            assert!(
+                !second.is_empty(),
+                "max_tcp_connections = {max_tcp_connections}"
+            );
+
+            cancel_token.cancel();
+            server.await.unwrap().unwrap();
+        }
+    }
+}
@@ -1,6 +1,8 @@
 use crate::AppState;
 use actix_web::{App, HttpServer, get, post, web};
 use serde::{Deserialize, Serialize};
+use tokio::select;
+use tokio_util::sync::CancellationToken;
 use utoipa::OpenApi;
 use utoipa_actix_web::AppExt;
 
@@ -144,8 +146,13 @@ async fn index(query: web::Query<IndexParams>, data: web::Data<AppState>) -> Str
     data.current_reponse.to_haproxy_string()
 }
 
-pub async fn webserver(addr: String, port: u16, app_state: AppState) {
-    HttpServer::new(move || {
+pub async fn webserver(
+    addr: String,
+    port: u16,
+    app_state: AppState,
+    cancel_token: CancellationToken,
+) {
+    let server = HttpServer::new(move || {
         let app_state = app_state.clone();
         App::new()
             .into_utoipa_app()
@@ -167,9 +174,16 @@ pub async fn webserver(addr: String, port: u16, app_state: AppState) {
     .disable_signals()
     .bind((addr, port))
     .unwrap()
-    .run()
-    .await
-    .unwrap()
+    .run();
+
+    let handle = server.handle();
+    select! {
+        result = server => result.unwrap(),
+        _ = cancel_token.cancelled() => {
+            tracing::info!("Stopping webserver");
+            handle.stop(true).await
+        }
+    }
 }
 
 #[derive(utoipa::OpenApi)]
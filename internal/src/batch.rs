@@ -0,0 +1,63 @@
+use anyhow::Result;
+use ldap3::{
+    adapters::{Adapter, EntriesOnly, PagedResults},
+    Ldap, Scope,
+};
+use serde::Deserialize;
+
+use crate::LdapConfig;
+
+/// Filter counted by the batch scrape
+#[derive(Deserialize, Debug, Clone)]
+pub struct NamedFilter {
+    pub name: String,
+    pub filter: String,
+
+    /// Defaults to the `default_query_base`
+    #[serde(default)]
+    pub base: Option<String>,
+}
+
+async fn count(ldap: &mut Ldap, ldap_config: &LdapConfig, filter: &NamedFilter) -> Result<u64> {
+    let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![
+        Box::new(EntriesOnly::new()),
+        Box::new(PagedResults::new(ldap_config.page_size)),
+    ];
+
+    let mut search = ldap
+        .streaming_search_with(
+            adapters,
+            filter.base.as_ref().unwrap_or(&ldap_config.default_base),
+            Scope::Subtree,
+            &filter.filter,
+            vec!["1.1"],
+        )
+        .await?;
+
+    let mut result = 0;
+    while search.next().await?.is_some() {
+        result += 1;
+    }
+    search.finish().await.success()?;
+
+    Ok(result)
+}
+
+/// Count entries of every filter using a single connection. Error of one filter does not stop
+/// the others
+pub async fn batch_count(
+    ldap_config: &LdapConfig,
+    filters: &[NamedFilter],
+) -> Result<Vec<(String, Result<u64>)>> {
+    let mut ldap = ldap_config.connect().await?;
+    let mut result = Vec::new();
+
+    for filter in filters {
+        result.push((
+            filter.name.clone(),
+            count(&mut ldap, ldap_config, filter).await,
+        ));
+    }
+
+    Ok(result)
+}
@@ -1,12 +1,15 @@
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::time::Duration;
 use tokio::process::Command;
 use tokio::time::timeout;
 
 pub const DEFAULT_INSTANCE: &str = "default";
 
+/// Healthcheck pattern covering the backend checks (including index configuration)
+pub const BACKENDS_CHECK: &str = "backends:*";
+
 fn default_instance() -> String {
     DEFAULT_INSTANCE.to_string()
 }
@@ -17,6 +20,28 @@ pub struct CommandConfig {
 
     #[serde(rename = "instance", default = "default_instance")]
     pub instance_name: String,
+
+    /// Run `logs*` healthchecks too. Skipped by default, as the logs can be huge
+    #[serde(default)]
+    pub include_log_checks: bool,
+
+    /// Command (with args) prepended to the executed commands. By default dsctl is run with
+    /// `sudo` and other commands directly. Empty list runs everything directly (e.g. as root
+    /// in containers), custom wrapper is applied to all the commands
+    #[serde(default)]
+    pub command_prefix: Option<Vec<String>>,
+
+    /// dsctl binary. Default: `dsctl` from `$PATH`
+    #[serde(default)]
+    pub dsctl_path: Option<String>,
+
+    /// Args passed to dsctl before `--json <instance>`
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+
+    /// Add shortened finding description as a metric label. Increases label cardinality
+    #[serde(default)]
+    pub description_label: bool,
 }
 
 impl Default for CommandConfig {
@@ -24,10 +49,63 @@ impl Default for CommandConfig {
         Self {
             timeout_seconds: None,
             instance_name: default_instance(),
+            include_log_checks: false,
+            command_prefix: None,
+            dsctl_path: None,
+            extra_args: Vec::new(),
+            description_label: false,
         }
     }
 }
 
+/// State of the dirsrv systemd unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemdState {
+    /// Active or reloading
+    Active,
+    /// Inactive, activating or deactivating
+    Inactive,
+    Failed,
+    /// Unit does not exist, e.g. instance was removed
+    NotFound,
+}
+
+impl SystemdState {
+    /// Parse `systemctl show --property=LoadState,ActiveState` output
+    pub fn from_show(output: &str) -> Result<Self> {
+        let properties: HashMap<&str, &str> = output
+            .lines()
+            .filter_map(|x| x.split_once('='))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+
+        if properties.get("LoadState") == Some(&"not-found") {
+            return Ok(SystemdState::NotFound);
+        }
+
+        match properties.get("ActiveState") {
+            Some(&"active") | Some(&"reloading") => Ok(SystemdState::Active),
+            Some(&"inactive") | Some(&"activating") | Some(&"deactivating") => {
+                Ok(SystemdState::Inactive)
+            }
+            Some(&"failed") => Ok(SystemdState::Failed),
+            other => Err(anyhow!("Unknown systemd unit state: {:?}", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for SystemdState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SystemdState::Active => "active",
+            SystemdState::Inactive => "inactive",
+            SystemdState::Failed => "failed",
+            SystemdState::NotFound => "not found",
+        };
+        f.write_str(name)
+    }
+}
+
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq, Copy, std::hash::Hash)]
 pub enum Severity {
     #[serde(alias = "High", alias = "high", alias = "HIGH")]
@@ -59,14 +137,93 @@ pub struct HealthcheckEntry {
     pub description: String,
 }
 
+/// Max length (in chars) of the description returned by [`HealthcheckEntry::short_description`]
+pub const SHORT_DESCRIPTION_LENGTH: usize = 100;
+
+impl HealthcheckEntry {
+    /// First line of the description with collapsed whitespace, truncated to
+    /// [`SHORT_DESCRIPTION_LENGTH`] chars. Safe to use as a metric label
+    pub fn short_description(&self) -> String {
+        let line = self
+            .description
+            .lines()
+            .map(str::trim)
+            .find(|x| !x.is_empty())
+            .unwrap_or_default();
+        let collapsed = line.split_whitespace().collect::<Vec<_>>().join(" ");
+
+        if collapsed.chars().count() <= SHORT_DESCRIPTION_LENGTH {
+            collapsed
+        } else {
+            let mut truncated: String = collapsed
+                .chars()
+                .take(SHORT_DESCRIPTION_LENGTH - 3)
+                .collect();
+            truncated.push_str("...");
+            truncated
+        }
+    }
+}
+
+/// Number of the healthcheck findings recommending reindex, grouped by backend. Backend name is
+/// taken from the finding items (e.g. `["Backends", "userroot", "cn"]`)
+pub fn pending_reindex(entries: &[HealthcheckEntry]) -> HashMap<String, u64> {
+    entries
+        .iter()
+        .filter(|x| {
+            x.detail.to_lowercase().contains("reindex") || x.fix.to_lowercase().contains("reindex")
+        })
+        .fold(HashMap::new(), |mut acc, x| {
+            let backend = x
+                .items
+                .get(1)
+                .map(|x| x.to_lowercase())
+                .unwrap_or("unknown".to_string());
+            *acc.entry(backend).or_insert(0) += 1;
+            acc
+        })
+}
+
 impl CommandConfig {
     pub fn new(timeout_seconds: Option<u64>, instance_name: String) -> Self {
         Self {
             timeout_seconds,
             instance_name,
+            include_log_checks: false,
+            command_prefix: None,
+            dsctl_path: None,
+            extra_args: Vec::new(),
+            description_label: false,
+        }
+    }
+
+    /// Command with the configured prefix. `privileged` commands are prefixed with `sudo` by default
+    fn command(&self, program: &str, privileged: bool) -> Command {
+        let prefix = match &self.command_prefix {
+            Some(prefix) => prefix.clone(),
+            None if privileged => vec!["sudo".to_string()],
+            None => Vec::new(),
+        };
+
+        match prefix.split_first() {
+            Some((wrapper, args)) => {
+                let mut cmd = Command::new(wrapper);
+                cmd.args(args).arg(program);
+                cmd
+            }
+            None => Command::new(program),
         }
     }
 
+    /// dsctl command for the configured instance, followed by `args`
+    fn dsctl_command(&self, args: &[&str]) -> Command {
+        let mut cmd = self.command(self.dsctl_path.as_deref().unwrap_or("dsctl"), true);
+        cmd.args(&self.extra_args)
+            .args(["--json", &self.instance_name])
+            .args(args);
+        cmd
+    }
+
     async fn execute_cmd(&self, cmd: &mut Command) -> Result<std::process::Output> {
         Ok(if let Some(timeout_s) = self.timeout_seconds {
             timeout(
@@ -80,14 +237,7 @@ impl CommandConfig {
     }
 
     async fn list_checks(&self) -> Result<Vec<String>> {
-        let mut cmd = Command::new("sudo");
-        cmd.args([
-            "dsctl",
-            "--json",
-            &self.instance_name,
-            "healthcheck",
-            "--list-checks",
-        ]);
+        let mut cmd = self.dsctl_command(&["healthcheck", "--list-checks"]);
         let result = self.execute_cmd(&mut cmd).await?;
         if !result.status.success() {
             let error = std::str::from_utf8(&result.stderr)
@@ -106,23 +256,25 @@ impl CommandConfig {
         Ok(Vec::from_iter(checks))
     }
 
-    pub async fn systemd_running(&self) -> Result<bool> {
-        let mut cmd = Command::new("systemctl");
-        cmd.args(["is-active", &format!("dirsrv@{}", &self.instance_name)]);
+    pub async fn systemd_state(&self) -> Result<SystemdState> {
+        let mut cmd = self.command("systemctl", false);
+        cmd.args([
+            "show",
+            "--property=LoadState,ActiveState",
+            &format!("dirsrv@{}", &self.instance_name),
+        ]);
         let result = self.execute_cmd(&mut cmd).await?;
-        Ok(result.status.success())
+        if !result.status.success() {
+            let error = std::str::from_utf8(&result.stderr)
+                .unwrap_or("Undefined error. That is really bad");
+            return Err(anyhow!("systemctl show failed: {}", error));
+        }
+
+        SystemdState::from_show(&String::from_utf8_lossy(&result.stdout))
     }
 
     pub async fn healthcheck(&self, check_pattern: &str) -> Result<Vec<HealthcheckEntry>> {
-        let mut cmd = Command::new("sudo");
-        cmd.args([
-            "dsctl",
-            "--json",
-            &self.instance_name,
-            "healthcheck",
-            "--check",
-            check_pattern,
-        ]);
+        let mut cmd = self.dsctl_command(&["healthcheck", "--check", check_pattern]);
 
         let result = self.execute_cmd(&mut cmd).await?;
 
@@ -141,8 +293,8 @@ impl CommandConfig {
 
         for pattern in check_patterns
             .into_iter()
-            // Skip logs for now as they can grow exponentially
-            .filter(|x| !x.starts_with("logs"))
+            // Logs can grow exponentially
+            .filter(|x| self.include_log_checks || !x.starts_with("logs"))
         {
             result.extend(self.healthcheck(&pattern).await?);
         }
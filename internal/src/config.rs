@@ -1,4 +1,6 @@
-use crate::{cli::CommandConfig, query::CustomQuery, LdapConfig};
+use std::collections::HashSet;
+
+use crate::{cli::CommandConfig, gids::GidsConfig, query::CustomQuery, LdapConfig};
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -8,6 +10,9 @@ pub struct Scrapers {
 
     #[serde(default)]
     pub query: Vec<CustomQuery>,
+
+    #[serde(default)]
+    pub gids: GidsConfig,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -18,3 +23,52 @@ pub struct CommonConfig {
     #[serde(default)]
     pub scrapers: Scrapers,
 }
+
+impl CommonConfig {
+    /// Problems which are not caught by the deserialization. Empty if the config is valid
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = self.ldap_config.validate();
+        let mut names = HashSet::new();
+
+        for query in &self.scrapers.query {
+            if !names.insert(query.name.as_str()) {
+                problems.push(format!("Query {} is defined more than once", query.name));
+            }
+            if query.filter.is_empty() {
+                problems.push(format!("Query {} has an empty filter", query.name));
+            }
+            if query.uri.as_ref().is_some_and(|uri| uri.is_empty()) {
+                problems.push(format!("Query {} has an empty uri", query.name));
+            }
+        }
+
+        problems
+    }
+
+    /// Problems of the queries referenced by the binary specific `section`, e.g. missing
+    /// definitions in `[[scrapers.query]]`
+    pub fn validate_query_refs<'a>(
+        &self,
+        section: &str,
+        names: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<String> {
+        names
+            .into_iter()
+            .filter(|name| !self.scrapers.query.iter().any(|query| query.name == *name))
+            .map(|name| format!("Query {name} used in {section} is not defined in scrapers.query"))
+            .collect()
+    }
+}
+
+/// Print the problems and exit (`--check-config`). Exit code is 1 if there are any
+pub fn exit_with_problems(problems: &[String]) -> ! {
+    if problems.is_empty() {
+        println!("Config is valid");
+        std::process::exit(0);
+    }
+
+    for problem in problems {
+        println!("{problem}");
+    }
+    std::process::exit(1);
+}
@@ -0,0 +1,147 @@
+use ldap3::LdapError;
+
+/// insufficientAccessRights
+const RC_INSUFFICIENT_ACCESS: u32 = 50;
+/// timeLimitExceeded
+const RC_TIME_LIMIT_EXCEEDED: u32 = 3;
+/// inappropriateAuthentication, invalidCredentials
+const RC_BIND: &[u32] = &[48, 49];
+
+/// Name of the LDAP result code (RFC 4511), e.g. `sizeLimitExceeded`
+pub fn result_code_name(rc: u32) -> &'static str {
+    match rc {
+        0 => "success",
+        1 => "operationsError",
+        2 => "protocolError",
+        3 => "timeLimitExceeded",
+        4 => "sizeLimitExceeded",
+        5 => "compareFalse",
+        6 => "compareTrue",
+        7 => "authMethodNotSupported",
+        8 => "strongerAuthRequired",
+        10 => "referral",
+        11 => "adminLimitExceeded",
+        12 => "unavailableCriticalExtension",
+        13 => "confidentialityRequired",
+        14 => "saslBindInProgress",
+        16 => "noSuchAttribute",
+        17 => "undefinedAttributeType",
+        18 => "inappropriateMatching",
+        19 => "constraintViolation",
+        20 => "attributeOrValueExists",
+        21 => "invalidAttributeSyntax",
+        32 => "noSuchObject",
+        33 => "aliasProblem",
+        34 => "invalidDNSyntax",
+        36 => "aliasDereferencingProblem",
+        48 => "inappropriateAuthentication",
+        49 => "invalidCredentials",
+        50 => "insufficientAccessRights",
+        51 => "busy",
+        52 => "unavailable",
+        53 => "unwillingToPerform",
+        54 => "loopDetect",
+        64 => "namingViolation",
+        65 => "objectClassViolation",
+        66 => "notAllowedOnNonLeaf",
+        67 => "notAllowedOnRDN",
+        68 => "entryAlreadyExists",
+        69 => "objectClassModsProhibited",
+        71 => "affectsMultipleDSAs",
+        80 => "other",
+        _ => "unknown",
+    }
+}
+
+/// Entry is hidden or not readable for the current bind
+#[derive(Debug)]
+pub struct PrivilegedBindRequired(pub String);
+
+impl std::fmt::Display for PrivilegedBindRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}. Privileged bind is required", self.0)
+    }
+}
+
+impl std::error::Error for PrivilegedBindRequired {}
+
+/// Category of the scrape failure, used to route alerts (e.g. bind errors to LDAP admins, parse
+/// errors to exporter maintainers)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Timeout,
+    Connect,
+    Bind,
+    Parse,
+    Permission,
+    Other,
+}
+
+impl ErrorKind {
+    pub const ALL: [ErrorKind; 6] = [
+        ErrorKind::Timeout,
+        ErrorKind::Connect,
+        ErrorKind::Bind,
+        ErrorKind::Parse,
+        ErrorKind::Permission,
+        ErrorKind::Other,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Connect => "connect",
+            ErrorKind::Bind => "bind",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Permission => "permission",
+            ErrorKind::Other => "other",
+        }
+    }
+
+    pub fn classify(error: &anyhow::Error) -> Self {
+        if error.downcast_ref::<PrivilegedBindRequired>().is_some() {
+            return ErrorKind::Permission;
+        }
+
+        if let Some(error) = error.downcast_ref::<LdapError>() {
+            return match error {
+                LdapError::Timeout { .. } => ErrorKind::Timeout,
+                LdapError::Io { .. }
+                | LdapError::NativeTLS { .. }
+                | LdapError::EndOfStream
+                | LdapError::ResultRecv { .. }
+                | LdapError::OpSend { .. } => ErrorKind::Connect,
+                LdapError::LdapResult { result } => match result.rc {
+                    RC_INSUFFICIENT_ACCESS => ErrorKind::Permission,
+                    RC_TIME_LIMIT_EXCEEDED => ErrorKind::Timeout,
+                    rc if RC_BIND.contains(&rc) => ErrorKind::Bind,
+                    _ => ErrorKind::Other,
+                },
+                _ => ErrorKind::Other,
+            };
+        }
+
+        if error
+            .downcast_ref::<tokio::time::error::Elapsed>()
+            .is_some()
+        {
+            return ErrorKind::Timeout;
+        }
+
+        if let Some(error) = error.downcast_ref::<std::io::Error>() {
+            if error.kind() == std::io::ErrorKind::TimedOut {
+                return ErrorKind::Timeout;
+            }
+        }
+
+        if error.downcast_ref::<serde_json::Error>().is_some()
+            || error.downcast_ref::<std::num::ParseIntError>().is_some()
+            || error.downcast_ref::<std::num::ParseFloatError>().is_some()
+            || error.downcast_ref::<chrono::ParseError>().is_some()
+        {
+            return ErrorKind::Parse;
+        }
+
+        ErrorKind::Other
+    }
+}
@@ -1,12 +1,13 @@
 use std::collections::HashMap;
+use std::sync::{atomic::AtomicU64, Arc};
 
-use crate::LdapConfig;
+use crate::{paging::CountedPagedResults, LdapConfig};
 use anyhow::{anyhow, Result};
 use ldap3::{
-    adapters::{Adapter, EntriesOnly, PagedResults},
-    Scope, SearchEntry,
+    adapters::{Adapter, EntriesOnly},
+    Ldap, Scope, SearchEntry,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 const UID: &str = "uid";
 const GID_NUMBER: &str = "gidNumber";
@@ -14,6 +15,59 @@ const ACCOUNTS_ATTRS: &[&str] = &[GID_NUMBER, UID];
 
 type GidNumbers = Vec<i64>;
 
+pub const DEFAULT_ACCOUNTS_FILTER: &str = "(objectClass=posixAccount)";
+pub const DEFAULT_GROUPS_FILTER: &str = "(objectClass=posixGroup)";
+
+fn default_accounts_filter() -> String {
+    DEFAULT_ACCOUNTS_FILTER.to_string()
+}
+
+fn default_groups_filter() -> String {
+    DEFAULT_GROUPS_FILTER.to_string()
+}
+
+pub const DEFAULT_LOW_GID_THRESHOLD: i64 = 1000;
+
+fn default_low_gid_threshold() -> i64 {
+    DEFAULT_LOW_GID_THRESHOLD
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GidsConfig {
+    /// Filter of the accounts (entries with uid and gidNumber)
+    #[serde(default = "default_accounts_filter")]
+    pub accounts_filter: String,
+
+    /// Filter of the groups (entries with gidNumber)
+    #[serde(default = "default_groups_filter")]
+    pub groups_filter: String,
+
+    /// Search accounts and groups concurrently on two connections. Faster, but doubles the
+    /// number of binds
+    #[serde(default)]
+    pub concurrent_connections: bool,
+
+    /// Export the uids of the accounts with missing groups (one series per account)
+    #[serde(default)]
+    pub export_uids: bool,
+
+    /// Primary gids below this value are in the system range (may collide with system groups)
+    #[serde(default = "default_low_gid_threshold")]
+    pub low_gid_threshold: i64,
+}
+
+impl Default for GidsConfig {
+    fn default() -> Self {
+        Self {
+            accounts_filter: default_accounts_filter(),
+            groups_filter: default_groups_filter(),
+            concurrent_connections: false,
+            export_uids: false,
+            low_gid_threshold: default_low_gid_threshold(),
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 struct LdapAccount {
     pub dn: String,
@@ -21,12 +75,30 @@ struct LdapAccount {
     pub gid_number: i64,
 }
 
-async fn load_accounts(ldap_config: &LdapConfig) -> Result<Vec<LdapAccount>> {
-    let mut ldap = ldap_config.connect().await?;
+/// Entries with empty or non-numeric gidNumber are skipped (with a warning), so a single broken
+/// entry does not fail the whole check
+fn parse_gid(dn: &str, gid_number: &str) -> Option<i64> {
+    match gid_number.trim().parse::<i64>() {
+        Ok(gid_number) => Some(gid_number),
+        Err(_) => {
+            tracing::warn!("Skipping {dn}: invalid {GID_NUMBER} {gid_number:?}");
+            None
+        }
+    }
+}
 
+async fn load_accounts(
+    ldap: &mut Ldap,
+    ldap_config: &LdapConfig,
+    filter: &str,
+    pages: &Arc<AtomicU64>,
+) -> Result<Vec<LdapAccount>> {
     let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![
         Box::new(EntriesOnly::new()),
-        Box::new(PagedResults::new(ldap_config.page_size)),
+        Box::new(CountedPagedResults::new(
+            ldap_config.page_size,
+            pages.clone(),
+        )),
     ];
 
     let mut search = ldap
@@ -34,7 +106,7 @@ async fn load_accounts(ldap_config: &LdapConfig) -> Result<Vec<LdapAccount>> {
             adapters,
             &ldap_config.default_base,
             Scope::Subtree,
-            "(objectClass=posixAccount)",
+            filter,
             ACCOUNTS_ATTRS,
         )
         .await?;
@@ -61,9 +133,10 @@ async fn load_accounts(ldap_config: &LdapConfig) -> Result<Vec<LdapAccount>> {
             .get(GID_NUMBER)
             .unwrap_or(&DEF_UNKNOWN)
             .first()
-            .ok_or(anyhow::anyhow!("No GID attribute"))?
-            .parse::<i64>()
-            .unwrap();
+            .ok_or(anyhow::anyhow!("No GID attribute"))?;
+        let Some(gid_number) = parse_gid(&dn, gid_number) else {
+            continue;
+        };
 
         result.push(LdapAccount {
             dn,
@@ -75,12 +148,18 @@ async fn load_accounts(ldap_config: &LdapConfig) -> Result<Vec<LdapAccount>> {
     Ok(result)
 }
 
-async fn load_groups(ldap_config: &LdapConfig) -> Result<GidNumbers> {
-    let mut ldap = ldap_config.connect().await?;
-
+async fn load_groups(
+    ldap: &mut Ldap,
+    ldap_config: &LdapConfig,
+    filter: &str,
+    pages: &Arc<AtomicU64>,
+) -> Result<GidNumbers> {
     let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![
         Box::new(EntriesOnly::new()),
-        Box::new(PagedResults::new(ldap_config.page_size)),
+        Box::new(CountedPagedResults::new(
+            ldap_config.page_size,
+            pages.clone(),
+        )),
     ];
 
     let mut search = ldap
@@ -88,7 +167,7 @@ async fn load_groups(ldap_config: &LdapConfig) -> Result<GidNumbers> {
             adapters,
             &ldap_config.default_base,
             Scope::Subtree,
-            "(objectClass=posixGroup)",
+            filter,
             vec![GID_NUMBER],
         )
         .await?;
@@ -106,9 +185,10 @@ async fn load_groups(ldap_config: &LdapConfig) -> Result<GidNumbers> {
             .get(GID_NUMBER)
             .unwrap_or(&DEF_UNKNOWN)
             .first()
-            .ok_or(anyhow!("No GID attribute"))?
-            .parse::<i64>()
-            .unwrap();
+            .ok_or(anyhow!("No GID attribute"))?;
+        let Some(gid_number) = parse_gid(&entry.dn, gid_number) else {
+            continue;
+        };
 
         result.push(gid_number);
     }
@@ -129,13 +209,98 @@ fn missing_gids(accounts: &[LdapAccount], groups: &[i64]) -> HashMap<i64, u64> {
         })
 }
 
-/// Get missing gid -> uid occurences number
-pub async fn missing_gids_to_uid_mapping(ldap_config: &LdapConfig) -> Result<HashMap<i64, u64>> {
-    let accounts = crate::gids::load_accounts(ldap_config);
-    let groups = crate::gids::load_groups(ldap_config);
+/// missing gid -> uids of the accounts
+fn missing_gids_uids(accounts: Vec<LdapAccount>, groups: &[i64]) -> HashMap<i64, Vec<String>> {
+    accounts
+        .into_iter()
+        .filter(|account| !groups.contains(&account.gid_number))
+        .fold(HashMap::new(), |mut acc, account| {
+            acc.entry(account.gid_number).or_default().push(account.uid);
+            acc
+        })
+}
+
+/// Accounts and groups are searched one after another on a single connection, unless
+/// `concurrent_connections` is set - then each search uses its own connection
+async fn load_accounts_and_groups(
+    ldap_config: &LdapConfig,
+    gids_config: &GidsConfig,
+    pages: &Arc<AtomicU64>,
+) -> Result<(Vec<LdapAccount>, GidNumbers)> {
+    let accounts_filter = &gids_config.accounts_filter;
+    let groups_filter = &gids_config.groups_filter;
+
+    if gids_config.concurrent_connections {
+        let (mut accounts_ldap, mut groups_ldap) = tokio::try_join!(
+            ldap_config.connect_with_retry(),
+            ldap_config.connect_with_retry()
+        )?;
+        tokio::try_join!(
+            load_accounts(&mut accounts_ldap, ldap_config, accounts_filter, pages),
+            load_groups(&mut groups_ldap, ldap_config, groups_filter, pages)
+        )
+    } else {
+        let mut ldap = ldap_config.connect_with_retry().await?;
+        let accounts = load_accounts(&mut ldap, ldap_config, accounts_filter, pages).await?;
+        let groups = load_groups(&mut ldap, ldap_config, groups_filter, pages).await?;
+        Ok((accounts, groups))
+    }
+}
 
-    let (accounts, groups) = tokio::join!(accounts, groups);
-    let (accounts, groups) = (accounts?, groups?);
+/// Result of the gids scrape
+#[derive(Debug, Default)]
+pub struct GidsReport {
+    /// missing gid -> uid occurences number
+    pub missing: HashMap<i64, u64>,
+    /// missing gid -> uids of the affected accounts. Filled only by the detailed scrape
+    pub missing_uids: HashMap<i64, Vec<String>>,
+    /// Accounts with the primary gid below `low_gid_threshold` (system gid range)
+    pub low_number_count: u64,
+}
+
+/// Scrape accounts and groups. Pages fetched by both searches are added to `pages`. Uids of the
+/// accounts with missing gids are collected only if `detailed` is set
+pub async fn gids_report_counted(
+    ldap_config: &LdapConfig,
+    gids_config: &GidsConfig,
+    pages: &Arc<AtomicU64>,
+    detailed: bool,
+) -> Result<GidsReport> {
+    let (accounts, groups) = load_accounts_and_groups(ldap_config, gids_config, pages).await?;
+
+    let low_number_count = accounts
+        .iter()
+        .filter(|account| account.gid_number < gids_config.low_gid_threshold)
+        .count() as u64;
+    let missing = missing_gids(&accounts, &groups);
+    let missing_uids = if detailed {
+        missing_gids_uids(accounts, &groups)
+    } else {
+        HashMap::new()
+    };
+
+    Ok(GidsReport {
+        missing,
+        missing_uids,
+        low_number_count,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(missing_gids(&accounts, &groups))
+    #[test]
+    fn parse_gid_valid() {
+        assert_eq!(parse_gid("cn=a", "1000"), Some(1000));
+        assert_eq!(parse_gid("cn=a", " 1000 "), Some(1000));
+    }
+
+    #[test]
+    fn parse_gid_bogus() {
+        assert_eq!(parse_gid("cn=a", ""), None);
+        assert_eq!(parse_gid("cn=a", "admins"), None);
+        assert_eq!(parse_gid("cn=a", "10.5"), None);
+        assert_eq!(parse_gid("cn=a", "99999999999999999999"), None);
+    }
 }
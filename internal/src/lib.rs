@@ -1,21 +1,150 @@
+pub mod batch;
 pub mod cli;
 pub mod config;
+pub mod error;
 pub mod gids;
 pub(crate) mod logfmt;
+pub mod logging;
 pub mod monitor;
+pub mod paging;
+pub mod probe;
 pub mod query;
 pub mod replica;
+pub mod session;
+pub mod sort;
+pub mod state;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use ldap3::{Ldap, LdapConnAsync, Scope, SearchEntry};
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 
+/// Password of the simple bind. File and environment variable are read at connect time, so
+/// the secret does not have to be kept in the config file
+#[derive(Debug, Clone)]
+pub enum Password {
+    Inline(String),
+    /// File with the password. Trailing newline is ignored
+    File(PathBuf),
+    /// Name of the environment variable with the password
+    Env(String),
+}
+
+impl Password {
+    pub fn resolve(&self) -> Result<String> {
+        match self {
+            Password::Inline(pass) => Ok(pass.clone()),
+            Password::File(path) => Ok(std::fs::read_to_string(path)
+                .with_context(|| format!("Cannot read bind password file {}", path.display()))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string()),
+            Password::Env(name) => {
+                std::env::var(name).map_err(|_| anyhow!("Bind password variable {name} is not set"))
+            }
+        }
+    }
+}
+
+impl From<String> for Password {
+    fn from(pass: String) -> Self {
+        Password::Inline(pass)
+    }
+}
+
+/// Authentication performed after connecting. In the config file it is either
+/// `{ dn, pass | pass_file | pass_env }` (simple bind) or `{ sasl = "external" }`
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Bind {
-    pub dn: String,
-    pub pass: String,
+#[serde(try_from = "RawBind", into = "RawBind")]
+pub enum BindMethod {
+    Simple {
+        dn: String,
+        pass: Password,
+    },
+    /// Identity taken from the TLS client certificate (`client_cert`, `client_key`)
+    SaslExternal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SaslMechanism {
+    External,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RawBind {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pass: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pass_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pass_env: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sasl: Option<SaslMechanism>,
+}
+
+impl RawBind {
+    /// `pass_file` takes precedence over `pass_env`, and both over the inline `pass`
+    fn password(&mut self) -> Option<Password> {
+        let pass = self.pass.take().map(Password::Inline);
+        let pass_env = self.pass_env.take().map(Password::Env);
+        let pass_file = self.pass_file.take().map(Password::File);
+        pass_file.or(pass_env).or(pass)
+    }
+}
+
+impl TryFrom<RawBind> for BindMethod {
+    type Error = String;
+
+    fn try_from(mut raw: RawBind) -> std::result::Result<Self, Self::Error> {
+        match (raw.dn.take(), raw.password(), raw.sasl) {
+            (Some(dn), Some(pass), None) => Ok(BindMethod::Simple { dn, pass }),
+            (None, None, Some(SaslMechanism::External)) => Ok(BindMethod::SaslExternal),
+            _ => Err(
+                "bind requires either dn with pass (or pass_file, pass_env), or sasl".to_string(),
+            ),
+        }
+    }
 }
 
+impl From<BindMethod> for RawBind {
+    fn from(bind: BindMethod) -> Self {
+        match bind {
+            BindMethod::Simple { dn, pass } => {
+                let mut raw = RawBind {
+                    dn: Some(dn),
+                    pass: None,
+                    pass_file: None,
+                    pass_env: None,
+                    sasl: None,
+                };
+                match pass {
+                    Password::Inline(pass) => raw.pass = Some(pass),
+                    Password::File(path) => raw.pass_file = Some(path),
+                    Password::Env(name) => raw.pass_env = Some(name),
+                }
+                raw
+            }
+            BindMethod::SaslExternal => RawBind {
+                dn: None,
+                pass: None,
+                pass_file: None,
+                pass_env: None,
+                sasl: Some(SaslMechanism::External),
+            },
+        }
+    }
+}
+
+pub const ENV_URI: &str = "O11Y_389DS_URI";
+pub const ENV_BASEDN: &str = "O11Y_389DS_BASEDN";
+pub const ENV_BINDDN: &str = "O11Y_389DS_BINDDN";
+pub const ENV_BINDPASS: &str = "O11Y_389DS_BINDPASS";
+pub const ENV_PAGE_SIZE: &str = "O11Y_389DS_PAGE_SIZE";
+pub const ENV_VERIFY_CERTS: &str = "O11Y_389DS_VERIFY_CERTS";
+pub const ENV_START_TLS: &str = "O11Y_389DS_START_TLS";
+
 fn default_true() -> bool {
     true
 }
@@ -28,13 +157,115 @@ pub struct LdapConfig {
     #[serde(default = "default_true")]
     pub verify_certs: bool,
 
+    /// Upgrade plain `ldap://` connection with StartTLS before bind. Certificates are verified
+    /// according to `verify_certs`
+    #[serde(default)]
+    pub start_tls: bool,
+
     #[serde(default = "default_page_size")]
     pub page_size: i32,
 
     #[serde(default, rename = "default_query_base")]
     pub default_base: String,
 
-    pub bind: Option<Bind>,
+    /// Naming context to pick when the base is auto-detected, e.g. dc=example,dc=com. Matches
+    /// the context itself or its parent
+    #[serde(default)]
+    pub base_hint: Option<String>,
+
+    /// Reused connections (see `session::LdapSession`) are recreated after this time
+    #[serde(default)]
+    pub connection_max_lifetime_seconds: Option<u64>,
+
+    /// Give up connecting (TCP, TLS handshake, StartTLS) after this time. No timeout by default
+    #[serde(default)]
+    pub connect_timeout_seconds: Option<u64>,
+
+    /// Give up the bind, and the scrape or check using the connection, after this time. No
+    /// timeout by default
+    #[serde(default)]
+    pub operation_timeout_seconds: Option<u64>,
+
+    /// Failed connections (network errors and timeouts only) are retried this many times by
+    /// `connect_with_retry`
+    #[serde(default = "default_connect_retries")]
+    pub connect_retries: u32,
+
+    /// Wait before the first retry. Doubled with every next retry, up to 64 times
+    #[serde(default = "default_connect_backoff_ms")]
+    pub connect_backoff_ms: u64,
+
+    /// PEM client certificate presented during TLS handshake (e.g. for SASL EXTERNAL)
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+
+    /// PEM (PKCS#8) private key of the `client_cert`
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+
+    pub bind: Option<BindMethod>,
+}
+
+/// Naming contexts which do not hold the directory data
+const NON_DATA_CONTEXTS: &[&str] = &["o=netscaperoot", "cn=config", "cn=monitor", "cn=schema"];
+
+/// Result of the base auto-detection
+#[derive(Debug, Clone)]
+pub struct DetectedBase {
+    pub base: String,
+
+    /// All naming contexts reported by the server
+    pub contexts: Vec<String>,
+
+    /// More than one data suffix was found and no hint was given
+    pub ambiguous: bool,
+}
+
+impl DetectedBase {
+    fn choose(contexts: Vec<String>, hint: Option<&str>) -> Result<Self> {
+        if let Some(hint) = hint {
+            let hint = hint.to_lowercase();
+            let base = contexts
+                .iter()
+                .find(|x| {
+                    let x = x.to_lowercase();
+                    x == hint || x.ends_with(&format!(",{hint}"))
+                })
+                .ok_or(anyhow!(
+                    "No naming context matches the base hint {hint}. Available: {}",
+                    contexts.join("; ")
+                ))?
+                .clone();
+
+            return Ok(Self {
+                base,
+                contexts,
+                ambiguous: false,
+            });
+        }
+
+        let data_contexts = contexts
+            .iter()
+            .filter(|x| {
+                let x = x.to_lowercase();
+                (x.starts_with("dc=") || x.starts_with("o="))
+                    && !NON_DATA_CONTEXTS.contains(&x.as_str())
+            })
+            .collect::<Vec<_>>();
+
+        let base = data_contexts
+            .first()
+            .copied()
+            .or(contexts.first())
+            .ok_or(anyhow!("No naming contexts"))?
+            .clone();
+
+        Ok(Self {
+            base,
+            ambiguous: data_contexts.len() > 1,
+            contexts,
+        })
+    }
 }
 
 impl Default for LdapConfig {
@@ -42,9 +273,18 @@ impl Default for LdapConfig {
         Self {
             bind: None,
             verify_certs: true,
+            start_tls: false,
             uri: default_host(),
             page_size: default_page_size(),
             default_base: Default::default(),
+            base_hint: None,
+            connection_max_lifetime_seconds: None,
+            connect_timeout_seconds: None,
+            operation_timeout_seconds: None,
+            connect_retries: default_connect_retries(),
+            connect_backoff_ms: default_connect_backoff_ms(),
+            client_cert: None,
+            client_key: None,
         }
     }
 }
@@ -57,40 +297,395 @@ fn default_page_size() -> i32 {
     999
 }
 
+fn default_connect_retries() -> u32 {
+    2
+}
+
+fn default_connect_backoff_ms() -> u64 {
+    200
+}
+
 impl LdapConfig {
-    pub async fn detect_base(&mut self) -> Result<()> {
-        let (conn, mut ldap) = LdapConnAsync::new(&self.uri).await?;
-        ldap3::drive!(conn);
+    /// Override settings with the `O11Y_389DS_*` environment variables. Should be applied after
+    /// loading the config file and before applying CLI flags
+    pub fn apply_env(&mut self) -> Result<()> {
+        self.apply_env_from(|name| std::env::var(name).ok())
+    }
+
+    /// Same as `apply_env`, but the variables are looked up by `var`. Empty values are ignored
+    pub fn apply_env_from(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        let env_var = |name: &str| var(name).filter(|x| !x.is_empty());
+
+        if let Some(uri) = env_var(ENV_URI) {
+            self.uri = uri;
+        }
+
+        if let Some(basedn) = env_var(ENV_BASEDN) {
+            self.default_base = basedn;
+        }
+
+        if let Some(page_size) = env_var(ENV_PAGE_SIZE) {
+            self.page_size = page_size
+                .parse()
+                .map_err(|e| anyhow!("Invalid {ENV_PAGE_SIZE}: {e}"))?;
+        }
+
+        if let Some(verify_certs) = env_var(ENV_VERIFY_CERTS) {
+            self.verify_certs = verify_certs
+                .parse()
+                .map_err(|e| anyhow!("Invalid {ENV_VERIFY_CERTS}: {e}"))?;
+        }
+
+        if let Some(start_tls) = env_var(ENV_START_TLS) {
+            self.start_tls = start_tls
+                .parse()
+                .map_err(|e| anyhow!("Invalid {ENV_START_TLS}: {e}"))?;
+        }
+
+        match (env_var(ENV_BINDDN), env_var(ENV_BINDPASS), &mut self.bind) {
+            (None, None, _) => {}
+            (Some(dn), Some(pass), bind) => {
+                *bind = Some(BindMethod::Simple {
+                    dn,
+                    pass: pass.into(),
+                })
+            }
+            (env_dn, env_pass, Some(BindMethod::Simple { dn, pass })) => {
+                if let Some(env_dn) = env_dn {
+                    *dn = env_dn;
+                }
+                if let Some(env_pass) = env_pass {
+                    *pass = env_pass.into();
+                }
+            }
+            (_, _, Some(_)) => {
+                return Err(anyhow!(
+                    "Both {ENV_BINDDN} and {ENV_BINDPASS} are required to replace SASL bind"
+                ))
+            }
+            (_, _, None) => {
+                return Err(anyhow!(
+                    "Both {ENV_BINDDN} and {ENV_BINDPASS} are required when bind is not configured"
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Problems which are not caught by the deserialization. Empty if the config is valid
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.uri.is_empty() {
+            problems.push("ldap_uri is empty".to_string());
+        } else if !["ldap://", "ldaps://", "ldapi://"]
+            .iter()
+            .any(|scheme| self.uri.starts_with(scheme))
+        {
+            problems.push(format!(
+                "ldap_uri {} has to start with ldap://, ldaps:// or ldapi://",
+                self.uri
+            ));
+        }
+
+        if self.page_size <= 0 {
+            problems.push(format!(
+                "page_size has to be positive, got {}",
+                self.page_size
+            ));
+        }
+
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            problems.push("client_cert and client_key have to be set together".to_string());
+        }
+
+        if let Some(BindMethod::Simple {
+            pass: Password::File(path),
+            ..
+        }) = &self.bind
+        {
+            if !path.exists() {
+                problems.push(format!("bind password file {path:?} does not exist"));
+            }
+        }
+
+        problems
+    }
+
+    /// Set `default_base` to one of the server naming contexts. `base_hint` is used if set,
+    /// otherwise data suffixes (dc=, o=) are preferred over the config/admin ones
+    pub async fn detect_base(&mut self) -> Result<DetectedBase> {
+        let mut ldap = self.open().await?;
 
         let result = ldap
             .search("", Scope::Base, "(objectClass=*)", &["namingContexts"])
             .await?;
 
-        if let Some(first) = result.0.into_iter().next() {
-            let entry = SearchEntry::construct(first);
-            self.default_base = entry
-                .attrs
-                .get("namingContexts")
-                .ok_or(anyhow!("No naming contexts attribute"))?
-                .first()
-                .ok_or(anyhow!("No naming contexts"))?
-                .to_string();
-            Ok(())
-        } else {
-            Err(anyhow!("Cannot retrive naming contexts"))
-        }
+        let first = result
+            .0
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("Cannot retrive naming contexts"))?;
+        let contexts = SearchEntry::construct(first)
+            .attrs
+            .remove("namingContexts")
+            .ok_or(anyhow!("No naming contexts attribute"))?;
+
+        let detected = DetectedBase::choose(contexts, self.base_hint.as_deref())?;
+        self.default_base = detected.base.clone();
+
+        Ok(detected)
     }
 
+    /// Connect (and bind, if configured) to the server. Every attempt is counted in the
+    /// `internal.ldap.connect_attempts_total` metric
     pub async fn connect(&self) -> Result<Ldap> {
-        let settings = ldap3::LdapConnSettings::new().set_no_tls_verify(!self.verify_certs);
+        let result = self.try_connect().await;
 
-        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.uri).await?;
-        ldap3::drive!(conn);
+        let outcome = if result.is_ok() { "ok" } else { "error" };
+        metrics::counter!("internal.ldap.connect_attempts_total", "result" => outcome).increment(1);
+
+        result
+    }
+
+    /// `connect` retried according to `connect_retries` and `connect_backoff_ms`
+    pub async fn connect_with_retry(&self) -> Result<Ldap> {
+        retry_connect(
+            self,
+            self.connect_retries,
+            std::time::Duration::from_millis(self.connect_backoff_ms),
+        )
+        .await
+    }
+
+    /// Open the connection without bind. StartTLS failure is an error, never a fallback to
+    /// cleartext
+    async fn open(&self) -> Result<Ldap> {
+        if self.start_tls && self.uri.to_lowercase().starts_with("ldaps://") {
+            return Err(anyhow!(
+                "start_tls cannot be used with ldaps:// ({}). Use ldap:// instead",
+                self.uri
+            ));
+        }
+
+        let mut settings = ldap3::LdapConnSettings::new()
+            .set_no_tls_verify(!self.verify_certs)
+            .set_starttls(self.start_tls);
 
-        if let Some(bind) = &self.bind {
-            ldap.simple_bind(&bind.dn, &bind.pass).await?;
+        if let Some(seconds) = self.connect_timeout_seconds {
+            settings = settings.set_conn_timeout(std::time::Duration::from_secs(seconds));
         }
 
+        if let Some(connector) = self.tls_connector()? {
+            settings = settings.set_connector(connector);
+        }
+
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.uri)
+            .await
+            .with_context(|| {
+                if self.start_tls {
+                    format!("StartTLS negotiation with {} failed", self.uri)
+                } else {
+                    format!("Unable to connect to {}", self.uri)
+                }
+            })?;
+        ldap3::drive!(conn);
+
+        Ok(ldap)
+    }
+
+    /// TLS connector with the client identity, if `client_cert` is configured
+    fn tls_connector(&self) -> Result<Option<native_tls::TlsConnector>> {
+        let (cert, key) = match (&self.client_cert, &self.client_key) {
+            (None, None) => return Ok(None),
+            (Some(cert), Some(key)) => (cert, key),
+            _ => return Err(anyhow!("client_cert and client_key must be set together")),
+        };
+
+        let cert = std::fs::read(cert).with_context(|| format!("Unable to read {cert:?}"))?;
+        let key = std::fs::read(key).with_context(|| format!("Unable to read {key:?}"))?;
+
+        let connector = native_tls::TlsConnector::builder()
+            .identity(native_tls::Identity::from_pkcs8(&cert, &key)?)
+            .danger_accept_invalid_certs(!self.verify_certs)
+            .danger_accept_invalid_hostnames(!self.verify_certs)
+            .build()?;
+
+        Ok(Some(connector))
+    }
+
+    async fn try_connect(&self) -> Result<Ldap> {
+        let connecting = format!("Connecting to {}", self.uri);
+        let mut ldap = with_timeout(self.connect_timeout_seconds, &connecting, self.open()).await?;
+
+        with_timeout(self.operation_timeout_seconds, "Bind", self.bind(&mut ldap)).await?;
+
         Ok(ldap)
     }
+
+    async fn bind(&self, ldap: &mut Ldap) -> Result<()> {
+        match &self.bind {
+            None => {}
+            Some(BindMethod::Simple { dn, pass }) => {
+                ldap.simple_bind(dn, &pass.resolve()?).await?.success()?;
+            }
+            Some(BindMethod::SaslExternal) => {
+                ldap.sasl_external_bind().await?.success()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Connect, retrying up to `retries` times with exponential backoff (`backoff`, `2 * backoff`,
+/// ...). Only network errors and timeouts are retried, e.g. invalid credentials fail at once
+pub async fn retry_connect(
+    config: &LdapConfig,
+    retries: u32,
+    backoff: std::time::Duration,
+) -> Result<Ldap> {
+    let mut attempt = 0;
+    loop {
+        match config.connect().await {
+            Ok(ldap) => return Ok(ldap),
+            Err(error)
+                if attempt < retries
+                    && matches!(
+                        error::ErrorKind::classify(&error),
+                        error::ErrorKind::Connect | error::ErrorKind::Timeout
+                    ) =>
+            {
+                tokio::time::sleep(retry_backoff(backoff, attempt)).await;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Delay before the retry: `backoff` doubled with every attempt. Capped at `64 * backoff`, so a
+/// large number of retries does not overflow
+fn retry_backoff(backoff: std::time::Duration, attempt: u32) -> std::time::Duration {
+    backoff.saturating_mul(1 << attempt.min(6))
+}
+
+/// Await the future, failing after `seconds` (if set). Timeout error keeps
+/// `tokio::time::error::Elapsed` as a source, so it is classified as `error::ErrorKind::Timeout`
+pub async fn with_timeout<T>(
+    seconds: Option<u64>,
+    what: &str,
+    future: impl std::future::Future<Output = Result<T>>,
+) -> Result<T> {
+    match seconds {
+        None => future.await,
+        Some(seconds) => tokio::time::timeout(std::time::Duration::from_secs(seconds), future)
+            .await
+            .with_context(|| format!("{what} timed out after {seconds}s"))?,
+    }
+}
+
+/// Wait for SIGINT (ctrl-c) or SIGTERM (sent e.g. by `systemctl stop`). Returns name of the
+/// received signal
+pub async fn shutdown_signal() -> Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            Ok("ctrl-c")
+        }
+        _ = sigterm.recv() => Ok("SIGTERM"),
+    }
+}
+
+/// Timestamp formats without the offset (UTC, `Z` suffix) seen in 389ds attributes
+const NAIVE_TIME_FORMATS: &[&str] = &[
+    "%Y%m%d%H%M%SZ",
+    "%Y%m%d%H%M%S%.fZ",
+    "%Y-%m-%dT%H:%M:%SZ",
+    "%Y-%m-%dT%H:%M:%S%.fZ",
+];
+
+/// Timestamp formats with the timezone offset
+const OFFSET_TIME_FORMATS: &[&str] = &[
+    "%Y%m%d%H%M%S%z",
+    "%Y%m%d%H%M%S%.f%z",
+    "%Y-%m-%dT%H:%M:%S%:z",
+    "%Y-%m-%dT%H:%M:%S%.f%:z",
+];
+
+/// Parse timestamp (LDAP generalized time or ISO 8601) in any of the known formats. Timestamps
+/// with offset are converted to UTC
+pub fn parse_time(value: &str) -> Result<chrono::NaiveDateTime> {
+    NAIVE_TIME_FORMATS
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(value, format).ok())
+        .or_else(|| {
+            OFFSET_TIME_FORMATS.iter().find_map(|format| {
+                chrono::DateTime::parse_from_str(value, format)
+                    .ok()
+                    .map(|x| x.naive_utc())
+            })
+        })
+        .ok_or(anyhow!("Unknown time format: {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> chrono::NaiveDateTime {
+        chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f").unwrap()
+    }
+
+    #[test]
+    fn parse_time_formats() {
+        let cases = [
+            ("20240102030405Z", "2024-01-02 03:04:05"),
+            ("20240102030405.123Z", "2024-01-02 03:04:05.123"),
+            ("2024-01-02T03:04:05Z", "2024-01-02 03:04:05"),
+            ("2024-01-02T03:04:05.123Z", "2024-01-02 03:04:05.123"),
+            ("20240102050405+0200", "2024-01-02 03:04:05"),
+            ("20240102050405.123+0200", "2024-01-02 03:04:05.123"),
+            ("2024-01-02T01:04:05-02:00", "2024-01-02 03:04:05"),
+            ("2024-01-02T05:04:05.123+02:00", "2024-01-02 03:04:05.123"),
+        ];
+
+        for (value, expected) in cases {
+            assert_eq!(parse_time(value).unwrap(), time(expected), "{value}");
+        }
+    }
+
+    #[test]
+    fn parse_time_unknown_format() {
+        assert!(parse_time("").is_err());
+        assert!(parse_time("2024-01-02").is_err());
+        assert!(parse_time("yesterday").is_err());
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        let backoff = std::time::Duration::from_millis(200);
+        assert_eq!(retry_backoff(backoff, 0), backoff);
+        assert_eq!(retry_backoff(backoff, 3), backoff * 8);
+        assert_eq!(retry_backoff(backoff, 6), backoff * 64);
+        assert_eq!(retry_backoff(backoff, 100), backoff * 64);
+        assert_eq!(
+            retry_backoff(std::time::Duration::MAX, 1),
+            std::time::Duration::MAX
+        );
+    }
+
+    #[test]
+    fn sasl_bind_mechanisms() {
+        let bind =
+            |sasl: &str| serde_json::from_value::<BindMethod>(serde_json::json!({ "sasl": sasl }));
+
+        assert!(matches!(bind("external"), Ok(BindMethod::SaslExternal)));
+        assert!(bind("gssapi").is_err());
+    }
 }
@@ -21,6 +21,20 @@ impl From<Pairs> for HashMap<String, String> {
     }
 }
 
+impl Pairs {
+    /// All the values of the keys, in the order of appearance. Unlike `HashMap<String, String>`
+    /// conversion, repeated keys are not overwritten
+    pub fn into_multimap(self) -> HashMap<String, Vec<String>> {
+        let mut map: HashMap<String, Vec<String>> = HashMap::new();
+
+        for Pair { key, val } in self.0 {
+            map.entry(key).or_default().push(val.unwrap_or_default());
+        }
+
+        map
+    }
+}
+
 fn complete_pair(buf: String, pair: Option<Pair>) -> Pair {
     match pair {
         Some(Pair { key: k, val: _ }) => Pair {
@@ -46,7 +60,8 @@ pub fn parse(message: &str) -> Pairs {
     for c in message.chars() {
         match (quoted, c) {
             (false, ' ') => {
-                if !buf.is_empty() {
+                // `key=` and `key=""` are pairs with an empty value
+                if !buf.is_empty() || pair.is_some() {
                     if !garbage {
                         // the buffer that we just processed is either a value
                         // or a valueless key depending on the current state of
@@ -58,6 +73,10 @@ pub fn parse(message: &str) -> Pairs {
                 }
                 garbage = false;
             }
+            (false, '=') if pair.is_some() => {
+                // separator was already found, so `=` is a part of the value
+                buf.push(c);
+            }
             (false, '=') => {
                 if !buf.is_empty() {
                     pair = Some(Pair {
@@ -70,7 +89,10 @@ pub fn parse(message: &str) -> Pairs {
                 }
             }
             (true, '\\') => {
-                escape = true;
+                if escape {
+                    buf.push(c);
+                }
+                escape = !escape;
             }
             (_, '"') => {
                 if escape {
@@ -95,9 +117,86 @@ pub fn parse(message: &str) -> Pairs {
 
     // and process one final time at the end of the message to get the last
     // data point
-    if !garbage {
+    if !garbage && (!buf.is_empty() || pair.is_some()) {
         pairs.push(complete_pair(buf, pair));
     }
 
     Pairs(pairs)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &str, val: Option<&str>) -> Pair {
+        Pair {
+            key: key.to_string(),
+            val: val.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn quoted_value_with_spaces_and_separator() {
+        assert_eq!(
+            parse(r#"msg="a=b c d" level=info"#).0,
+            vec![pair("msg", Some("a=b c d")), pair("level", Some("info"))]
+        );
+    }
+
+    #[test]
+    fn separator_inside_value() {
+        assert_eq!(
+            parse("filter=(uid=x) base=dc=example,dc=com").0,
+            vec![
+                pair("filter", Some("(uid=x)")),
+                pair("base", Some("dc=example,dc=com"))
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_and_missing_values() {
+        assert_eq!(
+            parse(r#"a= b="" c"#).0,
+            vec![pair("a", Some("")), pair("b", Some("")), pair("c", None)]
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            parse(r#"path="C:\\dir" quote="say \"hi\"" raw=a\b"#).0,
+            vec![
+                pair("path", Some(r"C:\dir")),
+                pair("quote", Some(r#"say "hi""#)),
+                pair("raw", Some(r"a\b"))
+            ]
+        );
+    }
+
+    #[test]
+    fn multimap_keeps_repeated_keys() {
+        let map = parse("partition=/ size=1 partition=/var size=2 flag").into_multimap();
+
+        assert_eq!(map["partition"], vec!["/", "/var"]);
+        assert_eq!(map["size"], vec!["1", "2"]);
+        assert_eq!(map["flag"], vec![""]);
+    }
+
+    #[test]
+    fn last_pair_is_flushed() {
+        assert_eq!(
+            parse("a=1 b=2").0,
+            vec![pair("a", Some("1")), pair("b", Some("2"))]
+        );
+        assert_eq!(
+            parse("a=1 b=").0,
+            vec![pair("a", Some("1")), pair("b", Some(""))]
+        );
+        assert_eq!(
+            parse("a=1 flag").0,
+            vec![pair("a", Some("1")), pair("flag", None)]
+        );
+        assert_eq!(parse("a=1 =garbage").0, vec![pair("a", Some("1"))]);
+    }
+}
@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use ldap3::{Ldap, Scope, SearchEntry};
+
+use crate::error::PrivilegedBindRequired;
+
+const CONFIG_DN: &str = "cn=config";
+
+pub const ACCESS_LOG_ENABLED: &str = "nsslapd-accesslog-logging-enabled";
+pub const ACCESS_LOG_LEVEL: &str = "nsslapd-accesslog-level";
+pub const ERROR_LOG_LEVEL: &str = "nsslapd-errorlog-level";
+pub const AUDIT_LOG_ENABLED: &str = "nsslapd-auditlog-logging-enabled";
+pub const AUDITFAIL_LOG_ENABLED: &str = "nsslapd-auditfaillog-logging-enabled";
+
+const LOGGING_ATTRS: &[&str] = &[
+    ACCESS_LOG_ENABLED,
+    ACCESS_LOG_LEVEL,
+    ERROR_LOG_LEVEL,
+    AUDIT_LOG_ENABLED,
+    AUDITFAIL_LOG_ENABLED,
+];
+
+/// Logging settings from `cn=config`. Requires privileged bind
+pub struct LdapLoggingConfig {
+    /// attribute (lowercase) -> value
+    pub attrs: HashMap<String, String>,
+}
+
+impl LdapLoggingConfig {
+    pub async fn scrape(ldap: &mut Ldap) -> Result<Self> {
+        let search = ldap
+            .search(
+                CONFIG_DN,
+                Scope::Base,
+                "(objectClass=*)",
+                LOGGING_ATTRS.to_vec(),
+            )
+            .await?;
+
+        // insufficientAccessRights or noSuchObject (cn=config is hidden for unprivileged binds)
+        if search.1.rc == 50 || search.1.rc == 32 {
+            return Err(PrivilegedBindRequired(format!(
+                "Unable to read {CONFIG_DN} (code {})",
+                search.1.rc
+            ))
+            .into());
+        }
+
+        let entry = search
+            .success()?
+            .0
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("Unable to read {CONFIG_DN}"))?;
+        let entry = SearchEntry::construct(entry);
+
+        let attrs = entry
+            .attrs
+            .into_iter()
+            .filter_map(|(attr, values)| {
+                values
+                    .into_iter()
+                    .next()
+                    .map(|value| (attr.to_lowercase(), value))
+            })
+            .collect();
+
+        Ok(Self { attrs })
+    }
+
+    /// Compare settings with the expected values (attribute -> value, case insensitive). Returns
+    /// list of mismatches: (attribute, expected, actual). Attributes hidden by ACIs are reported
+    /// as mismatches with the `None` value
+    pub fn drift<'a>(
+        &self,
+        expected: &'a [(&'a str, String)],
+    ) -> Vec<(&'a str, &'a str, Option<&str>)> {
+        expected
+            .iter()
+            .filter_map(|(attr, value)| {
+                let actual = self.attrs.get(&attr.to_lowercase()).map(String::as_str);
+                if actual.is_some_and(|x| x.eq_ignore_ascii_case(value)) {
+                    None
+                } else {
+                    Some((*attr, value.as_str(), actual))
+                }
+            })
+            .collect()
+    }
+}
@@ -4,9 +4,13 @@ use anyhow::{anyhow, Result};
 use chrono::NaiveDateTime;
 use ldap3::{Ldap, Scope, SearchEntry};
 
+use crate::error::PrivilegedBindRequired;
+use crate::LdapConfig;
+
 const UNKNOWN: &str = "UNKNOWN";
 
-const SNMP_METRICS_INT: &[&str] = &[
+/// Values accumulated since the daemon start
+pub const SNMP_METRICS_COUNTER: &[&str] = &[
     "anonymousbinds",
     "unauthbinds",
     "simpleauthbinds",
@@ -27,19 +31,23 @@ const SNMP_METRICS_INT: &[&str] = &[
     "chainings",
     "securityerrors",
     "errors",
-    "connections",
     "connectionseq",
-    "connectionsinmaxthreads",
     "connectionsmaxthreadscount",
     "bytesrecv",
     "bytessent",
     "entriesreturned",
     "referralsreturned",
+    "cachehits",
+    "consumerhits",
+];
+
+/// Current values
+pub const SNMP_METRICS_GAUGE: &[&str] = &[
+    "connections",
+    "connectionsinmaxthreads",
     "supplierentries",
     "copyentries",
     "cacheentries",
-    "cachehits",
-    "consumerhits",
 ];
 
 const ROOT_METRICS_INT: &[&str] = &[
@@ -61,6 +69,17 @@ const ROOT_METRICS_DATE: &[&str] = &["currenttime", "starttime"];
 
 const DISK_METRICS_INT: &[&str] = &["used", "available", "size", "use%"];
 
+const DATABASE_MONITOR_DN: &str = "cn=database,cn=monitor,cn=ldbm database,cn=plugins,cn=config";
+
+const BACKENDS_DN: &str = "cn=ldbm database,cn=plugins,cn=config";
+
+/// BDB attributes used to compute the database cache hit ratio
+pub const DATABASE_CACHE_HIT: &str = "nsslapd-db-cache-hit";
+pub const DATABASE_CACHE_TRY: &str = "nsslapd-db-cache-try";
+
+/// DNs reserved for the administration, which should not be used by the regular clients
+pub const DEFAULT_PRIVILEGED_DNS: &[&str] = &["cn=directory manager"];
+
 #[derive(Debug, Default, Clone)]
 pub struct MetricsCommonData {
     ///  List of the used connection dns over duration of the exporter process
@@ -76,7 +95,30 @@ pub struct LdapConnection {
     pub ip: String,
 }
 
+/// Parse the `connection` attribute of cn=monitor, e.g.
+/// `64:20240101120000Z:3:2:-:cn=directory manager:0:0:0:1:ip=::1`. The peer address is the
+/// last field and keeps its colons (IPv6). Local (ldapi) peers are reported as `ip=local`
+pub fn parse_connection_line(line: &str) -> LdapConnection {
+    let dn = line.split(':').nth(5).unwrap_or(UNKNOWN);
+    let ip = line
+        .rsplit_once(":ip=")
+        .map(|(_, ip)| ip)
+        .unwrap_or(UNKNOWN);
+
+    LdapConnection {
+        dn: dn.to_string(),
+        ip: ip.to_string(),
+    }
+}
+
 pub struct LdapConnections(Vec<LdapConnection>);
+
+impl From<Vec<LdapConnection>> for LdapConnections {
+    fn from(connections: Vec<LdapConnection>) -> Self {
+        Self(connections)
+    }
+}
+
 impl LdapConnections {
     pub fn count(&self) -> usize {
         self.0.len()
@@ -98,6 +140,23 @@ impl LdapConnections {
         })
     }
 
+    /// Number of connections bound as each of the given DNs (case insensitive). Every DN is
+    /// present in the result, even if there are no connections bound as it
+    pub fn group_by_privileged_dn(&self, dns: &[String]) -> HashMap<String, u64> {
+        let by_dn = self.group_by_dn();
+
+        dns.iter()
+            .map(|dn| {
+                let count = by_dn
+                    .iter()
+                    .filter(|(bound, _)| bound.eq_ignore_ascii_case(dn))
+                    .map(|(_, count)| count)
+                    .sum();
+                (dn.to_lowercase(), count)
+            })
+            .collect()
+    }
+
     pub fn group_by_ip(&self) -> HashMap<String, u64> {
         self.0.iter().fold(HashMap::new(), |mut acc, x| {
             let v = acc.entry(x.ip.clone()).or_insert(0);
@@ -146,23 +205,14 @@ impl LdapMonitor {
                     }
                     "connection" => {
                         for attr in attr_val {
-                            let values = attr.split(':').collect::<Vec<_>>();
-                            let dn = values.get(5).unwrap_or(&UNKNOWN);
-                            let ip = values.get(10).unwrap_or(&UNKNOWN).replace("ip=", "");
-
-                            let connection = LdapConnection {
-                                dn: dn.to_string(),
-                                ip: ip.to_string(),
-                            };
-                            result.connections.0.push(connection);
+                            result.connections.0.push(parse_connection_line(&attr));
                         }
                     }
                     _ if ROOT_METRICS_DATE.contains(&attr.as_str()) => {
                         if let Some(value) = attr_val.first() {
-                            result.date_metrics.insert(
-                                attr.clone(),
-                                NaiveDateTime::parse_from_str(value, "%Y%m%d%H%M%SZ")?,
-                            );
+                            result
+                                .date_metrics
+                                .insert(attr.clone(), crate::parse_time(value)?);
                         }
                     }
                     _ if ROOT_METRICS_INT.contains(&attr.as_str()) => {
@@ -183,6 +233,35 @@ impl LdapMonitor {
     }
 }
 
+/// Parse size reported by the server, e.g. `1024`, `512 MB`, `18.5GB` or `2 GiB`, into bytes.
+/// `KB`, `MB`... are decimal units, `KiB`, `MiB`... and single letters (`K`, `M`...) binary ones
+pub fn parse_bytes(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|x: char| !x.is_ascii_digit() && x != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "KB" => 1000,
+        "MB" => 1000_u64.pow(2),
+        "GB" => 1000_u64.pow(3),
+        "TB" => 1000_u64.pow(4),
+        "K" | "KIB" => 1 << 10,
+        "M" | "MIB" => 1 << 20,
+        "G" | "GIB" => 1 << 30,
+        "T" | "TIB" => 1 << 40,
+        _ => return None,
+    };
+
+    if let Ok(number) = number.parse::<u64>() {
+        return number.checked_mul(multiplier);
+    }
+    let number = number.parse::<f64>().ok()?;
+    Some((number * multiplier as f64) as u64)
+}
+
 pub struct LdapPartition {
     pub int_metrics: HashMap<String, u64>,
 }
@@ -192,13 +271,39 @@ pub struct LdapDisk {
     pub partitions: HashMap<String, LdapPartition>,
 }
 
+/// Partitions of a single `dsdisk` value. The line may list multiple partitions, n-th value of
+/// the key belongs to the n-th partition
+fn parse_dsdisk(line: &str) -> HashMap<String, LdapPartition> {
+    #[allow(non_snake_case)]
+    let ZER0: String = String::from("0");
+
+    let pairs = crate::logfmt::parse(line).into_multimap();
+    let partitions = pairs.get("partition").cloned().unwrap_or_default();
+
+    partitions
+        .into_iter()
+        .enumerate()
+        .map(|(index, partition)| {
+            let mut ldap_partition = LdapPartition {
+                int_metrics: Default::default(),
+            };
+
+            for key in DISK_METRICS_INT {
+                let value = pairs.get(*key).and_then(|x| x.get(index)).unwrap_or(&ZER0);
+                ldap_partition
+                    .int_metrics
+                    .insert(key.to_string(), parse_bytes(value).unwrap_or_default());
+            }
+
+            (partition, ldap_partition)
+        })
+        .collect()
+}
+
 impl LdapDisk {
     pub async fn scrape(ldap: &mut Ldap) -> Result<Self> {
         let attrs = vec!["dsdisk"];
 
-        #[allow(non_snake_case)]
-        let ZER0: String = String::from("0");
-
         let search_int = ldap
             .search(
                 "cn=disk space,cn=monitor",
@@ -216,27 +321,7 @@ impl LdapDisk {
 
             for attr_vals in entry.attrs.values() {
                 for attr_val in attr_vals {
-                    let pairs: HashMap<String, String> = crate::logfmt::parse(attr_val).into();
-                    if let Some(partition) = pairs.get("partition") {
-                        let mut ldap_partition = LdapPartition {
-                            int_metrics: Default::default(),
-                        };
-
-                        for key in DISK_METRICS_INT {
-                            ldap_partition.int_metrics.insert(
-                                key.to_string(),
-                                pairs
-                                    .get(*key)
-                                    .unwrap_or(&ZER0)
-                                    .parse::<u64>()
-                                    .unwrap_or_default(),
-                            );
-                        }
-
-                        result
-                            .partitions
-                            .insert(partition.to_string(), ldap_partition);
-                    }
+                    result.partitions.extend(parse_dsdisk(attr_val));
                 }
             }
             Ok(result)
@@ -248,12 +333,13 @@ impl LdapDisk {
 
 /// Scrapable object
 pub struct LdapSNMP {
-    pub int_metrics: HashMap<String, i32>,
+    /// Counters since the daemon start can easily exceed i32 (e.g. bytessent)
+    pub int_metrics: HashMap<String, u64>,
 }
 
 impl LdapSNMP {
     pub async fn scrape(ldap: &mut Ldap) -> Result<Self> {
-        let attrs = SNMP_METRICS_INT.to_vec();
+        let attrs = [SNMP_METRICS_COUNTER, SNMP_METRICS_GAUGE].concat();
 
         let search_int = ldap
             .search(
@@ -272,9 +358,14 @@ impl LdapSNMP {
 
             for (attr, attr_val) in entry.attrs {
                 if let Some(value) = attr_val.first() {
-                    result
-                        .int_metrics
-                        .insert(attr, value.parse::<i32>().unwrap_or_default());
+                    match value.parse::<u64>() {
+                        Ok(value) => {
+                            result.int_metrics.insert(attr, value);
+                        }
+                        Err(error) => {
+                            tracing::warn!("Skipping snmp attribute {attr} ({value:?}): {error}")
+                        }
+                    }
                 }
             }
             Ok(result)
@@ -283,3 +374,320 @@ impl LdapSNMP {
         }
     }
 }
+
+/// Scrapable object. Attribute set differs between BDB and LMDB backends, so every numeric
+/// attribute present in the entry is kept
+pub struct LdapDatabase {
+    pub int_metrics: HashMap<String, u64>,
+}
+
+impl LdapDatabase {
+    pub async fn scrape(ldap: &mut Ldap) -> Result<Self> {
+        let search_int = ldap
+            .search(
+                DATABASE_MONITOR_DN,
+                Scope::Base,
+                "(objectClass=*)",
+                vec!["*"],
+            )
+            .await?;
+
+        if let Some(entry) = search_int.success()?.0.into_iter().next() {
+            let mut result = Self {
+                int_metrics: Default::default(),
+            };
+            let entry = SearchEntry::construct(entry);
+
+            for (attr, attr_val) in entry.attrs {
+                if let Some(value) = attr_val.first().and_then(|x| x.parse::<u64>().ok()) {
+                    result.int_metrics.insert(attr.to_lowercase(), value);
+                }
+            }
+            Ok(result)
+        } else {
+            Err(anyhow!("Unable to get database metrics"))
+        }
+    }
+
+    /// Ratio (0-100) of the database cache hits. None if the backend does not report cache
+    /// statistics (LMDB) or there were no cache lookups yet
+    pub fn cache_hit_ratio(&self) -> Option<f64> {
+        let hits = *self.int_metrics.get(DATABASE_CACHE_HIT)?;
+        let tries = *self.int_metrics.get(DATABASE_CACHE_TRY)?;
+
+        if tries == 0 {
+            return None;
+        }
+
+        Some(hits as f64 / tries as f64 * 100.0)
+    }
+}
+
+/// Backends (ldbm database instances) configured on the server
+pub struct LdapBackends {
+    /// backend name (cn) -> suffix
+    pub backends: HashMap<String, String>,
+}
+
+impl LdapBackends {
+    /// Requires privileged bind. cn=config is not readable for anonymous or regular users
+    pub async fn scrape(ldap: &mut Ldap) -> Result<Self> {
+        let search = ldap
+            .search(
+                BACKENDS_DN,
+                Scope::OneLevel,
+                "(objectClass=nsBackendInstance)",
+                vec!["cn", "nsslapd-suffix"],
+            )
+            .await?;
+
+        // insufficientAccessRights or noSuchObject (cn=config is hidden for unprivileged binds)
+        if search.1.rc == 50 || search.1.rc == 32 {
+            return Err(PrivilegedBindRequired(format!(
+                "Unable to read backends from {BACKENDS_DN} (code {})",
+                search.1.rc
+            ))
+            .into());
+        }
+
+        let mut result = Self {
+            backends: Default::default(),
+        };
+
+        for entry in search.success()?.0 {
+            let entry = SearchEntry::construct(entry);
+            let first = |attr: &str| {
+                entry
+                    .attrs
+                    .get(attr)
+                    .and_then(|x| x.first())
+                    .cloned()
+                    .unwrap_or(UNKNOWN.to_string())
+            };
+
+            result.backends.insert(first("cn"), first("nsslapd-suffix"));
+        }
+
+        Ok(result)
+    }
+}
+
+pub const BACKEND_ENTRY_CACHE_HIT_RATIO: &str = "entrycachehitratio";
+pub const BACKEND_DN_CACHE_HIT_RATIO: &str = "dncachehitratio";
+
+/// Cache statistics of the single backend (`cn=monitor,cn=<backend>,cn=ldbm database,...`).
+/// Every numeric attribute is kept: entry/dn cache stats and `nsslapd-db-*` counters
+pub struct LdapBackendMonitor {
+    pub backend: String,
+    pub metrics: HashMap<String, f64>,
+}
+
+impl LdapBackendMonitor {
+    /// Requires privileged bind. Backend names can be discovered with `LdapBackends::scrape`
+    pub async fn scrape(ldap: &mut Ldap, backend: &str) -> Result<Self> {
+        let dn = format!("cn=monitor,cn={backend},{BACKENDS_DN}");
+        let search = ldap
+            .search(&dn, Scope::Base, "(objectClass=*)", vec!["*"])
+            .await?;
+
+        if search.1.rc == 50 || search.1.rc == 32 {
+            return Err(PrivilegedBindRequired(format!(
+                "Unable to read {dn} (code {})",
+                search.1.rc
+            ))
+            .into());
+        }
+
+        let entry = search
+            .success()?
+            .0
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("Unable to get monitor of the {backend} backend"))?;
+        let entry = SearchEntry::construct(entry);
+
+        let metrics = entry
+            .attrs
+            .into_iter()
+            .filter_map(|(attr, values)| {
+                let value = values.first()?.parse::<f64>().ok()?;
+                Some((attr.to_lowercase(), value))
+            })
+            .collect();
+
+        Ok(Self {
+            backend: backend.to_string(),
+            metrics,
+        })
+    }
+}
+
+/// State of the server as seen by the readiness probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Readiness {
+    /// Unable to connect to the server
+    Down(String),
+
+    /// Server accepts connections, but root DSE or `cn=monitor` is not available yet (e.g.
+    /// backends are still starting)
+    StartingUp(String),
+
+    Ready,
+}
+
+impl Readiness {
+    pub const NAMES: &[&str] = &["down", "starting_up", "ready"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Readiness::Down(_) => "down",
+            Readiness::StartingUp(_) => "starting_up",
+            Readiness::Ready => "ready",
+        }
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        match self {
+            Readiness::Down(reason) | Readiness::StartingUp(reason) => Some(reason),
+            Readiness::Ready => None,
+        }
+    }
+
+    /// Probe root DSE anonymously, then `cn=monitor` using the configured bind
+    pub async fn probe(ldap_config: &LdapConfig) -> Self {
+        let anonymous = LdapConfig {
+            bind: None,
+            ..ldap_config.clone()
+        };
+
+        let mut ldap = match anonymous.connect().await {
+            Ok(ldap) => ldap,
+            Err(error) => return Readiness::Down(error.to_string()),
+        };
+
+        if let Err(error) = read_base_entry(&mut ldap, "").await {
+            return Readiness::StartingUp(format!("root DSE not available: {error}"));
+        }
+
+        let mut ldap = match ldap_config.connect().await {
+            Ok(ldap) => ldap,
+            Err(error) => return Readiness::StartingUp(format!("bind failed: {error}")),
+        };
+
+        if let Err(error) = read_base_entry(&mut ldap, "cn=monitor").await {
+            return Readiness::StartingUp(format!("cn=monitor not available: {error}"));
+        }
+
+        Readiness::Ready
+    }
+}
+
+async fn read_base_entry(ldap: &mut Ldap, dn: &str) -> Result<()> {
+    let (entries, _) = ldap
+        .search(dn, Scope::Base, "(objectClass=*)", vec!["1.1"])
+        .await?
+        .success()?;
+
+    if entries.is_empty() {
+        return Err(anyhow!("No entry returned"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dsdisk_with_two_partitions() {
+        let partitions = parse_dsdisk(
+            "partition=\"/\" size=\"10GB\" used=\"4GB\" available=\"6GB\" use%=\"40\" \
+             partition=\"/var\" size=\"2G\" used=\"1G\" available=\"1G\" use%=\"50\"",
+        );
+
+        assert_eq!(partitions.len(), 2);
+        let root = &partitions["/"].int_metrics;
+        assert_eq!(root["size"], 10_000_000_000);
+        assert_eq!(root["used"], 4_000_000_000);
+        assert_eq!(root["available"], 6_000_000_000);
+        assert_eq!(root["use%"], 40);
+
+        let var = &partitions["/var"].int_metrics;
+        assert_eq!(var["size"], 2 << 30);
+        assert_eq!(var["used"], 1 << 30);
+        assert_eq!(var["available"], 1 << 30);
+        assert_eq!(var["use%"], 50);
+    }
+
+    #[test]
+    fn dsdisk_missing_values_are_zero() {
+        let partitions = parse_dsdisk("partition=/ size=1024 partition=/var");
+
+        assert_eq!(partitions["/"].int_metrics["size"], 1024);
+        assert_eq!(partitions["/"].int_metrics["used"], 0);
+        assert_eq!(partitions["/var"].int_metrics["size"], 0);
+        assert!(parse_dsdisk("size=1024").is_empty());
+    }
+
+    #[test]
+    fn parse_bytes_decimal_units() {
+        assert_eq!(parse_bytes("512"), Some(512));
+        assert_eq!(parse_bytes("512B"), Some(512));
+        assert_eq!(parse_bytes("2KB"), Some(2_000));
+        assert_eq!(parse_bytes("2 MB"), Some(2_000_000));
+        assert_eq!(parse_bytes("1.5GB"), Some(1_500_000_000));
+        assert_eq!(parse_bytes("3tb"), Some(3_000_000_000_000));
+    }
+
+    #[test]
+    fn parse_bytes_binary_units() {
+        assert_eq!(parse_bytes("2K"), Some(2_048));
+        assert_eq!(parse_bytes("2KiB"), Some(2_048));
+        assert_eq!(parse_bytes("1M"), Some(1_048_576));
+        assert_eq!(parse_bytes("1.5MiB"), Some(1_572_864));
+        assert_eq!(parse_bytes("1G"), Some(1_073_741_824));
+        assert_eq!(parse_bytes("1TiB"), Some(1_099_511_627_776));
+    }
+
+    #[test]
+    fn parse_bytes_invalid() {
+        assert_eq!(parse_bytes(""), None);
+        assert_eq!(parse_bytes("12PB"), None);
+        assert_eq!(parse_bytes("many"), None);
+        assert_eq!(parse_bytes("99999999999TB"), None);
+    }
+
+    #[test]
+    fn parse_connection_line_ipv4() {
+        let connection = parse_connection_line(
+            "64:20240101120000Z:3:2:-:cn=app,dc=example,dc=com:0:0:0:1:ip=192.0.2.10",
+        );
+        assert_eq!(connection.dn, "cn=app,dc=example,dc=com");
+        assert_eq!(connection.ip, "192.0.2.10");
+    }
+
+    #[test]
+    fn parse_connection_line_ipv6() {
+        let connection = parse_connection_line(
+            "64:20240101120000Z:3:2:-:cn=directory manager:0:0:0:1:ip=2001:db8::1",
+        );
+        assert_eq!(connection.dn, "cn=directory manager");
+        assert_eq!(connection.ip, "2001:db8::1");
+    }
+
+    #[test]
+    fn parse_connection_line_local() {
+        let connection =
+            parse_connection_line("64:20240101120000Z:3:2:-:cn=directory manager:0:0:0:1:ip=local");
+        assert_eq!(connection.dn, "cn=directory manager");
+        assert_eq!(connection.ip, "local");
+    }
+
+    #[test]
+    fn parse_connection_line_without_peer() {
+        let connection = parse_connection_line("64:20240101120000Z");
+        assert_eq!(connection.dn, UNKNOWN);
+        assert_eq!(connection.ip, UNKNOWN);
+    }
+}
@@ -0,0 +1,80 @@
+//! Paged search helpers
+
+use std::fmt::Debug;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use async_trait::async_trait;
+use ldap3::{
+    adapters::{Adapter, PagedResults},
+    result::Result,
+    LdapResult, RequestId, ResultEntry, Scope, SearchStream,
+};
+
+/// `PagedResults` adapter which counts the fetched pages. Every page is a separate search
+/// operation, so a new page is detected by the change of the operation id
+#[derive(Clone, Debug)]
+pub struct CountedPagedResults<S: AsRef<str>, A> {
+    inner: PagedResults<S, A>,
+    pages: Arc<AtomicU64>,
+    last_id: Option<RequestId>,
+}
+
+impl<S, A> CountedPagedResults<S, A>
+where
+    S: AsRef<str> + Send + Sync,
+    A: AsRef<[S]> + Send + Sync,
+{
+    pub fn new(page_size: i32, pages: Arc<AtomicU64>) -> Self {
+        Self {
+            inner: PagedResults::new(page_size),
+            pages,
+            last_id: None,
+        }
+    }
+
+    fn count_page<'a>(&mut self, stream: &mut SearchStream<'a, S, A>)
+    where
+        S: 'a,
+        A: 'a,
+    {
+        let id = stream.ldap_handle().last_id();
+        if self.last_id != Some(id) {
+            self.last_id = Some(id);
+            self.pages.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[async_trait]
+impl<'a, S, A> Adapter<'a, S, A> for CountedPagedResults<S, A>
+where
+    S: AsRef<str> + Clone + Debug + Send + Sync + 'a,
+    A: AsRef<[S]> + Clone + Debug + Send + Sync + 'a,
+{
+    async fn start(
+        &mut self,
+        stream: &mut SearchStream<'a, S, A>,
+        base: &str,
+        scope: Scope,
+        filter: &str,
+        attrs: A,
+    ) -> Result<()> {
+        self.last_id = None;
+        self.inner.start(stream, base, scope, filter, attrs).await?;
+        self.count_page(stream);
+        Ok(())
+    }
+
+    async fn next(&mut self, stream: &mut SearchStream<'a, S, A>) -> Result<Option<ResultEntry>> {
+        let entry = self.inner.next(stream).await;
+        self.count_page(stream);
+        entry
+    }
+
+    async fn finish(&mut self, stream: &mut SearchStream<'a, S, A>) -> LdapResult {
+        self.inner.finish(stream).await
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use ldap3::{Ldap, Mod, Scope, SearchEntry};
+
+/// noSuchObject
+const NO_SUCH_OBJECT: u32 = 32;
+
+/// Prefix of the `description` written by the probe. Only the entries carrying it are removed
+/// as the leftovers of the interrupted probes
+const PROBE_MARKER: &str = "o11y-389ds-rs write probe";
+
+/// Write path check. Adds, modifies and deletes the entry under the given DN.
+/// WARNING: it writes to the directory (and replicates the changes). Use a dedicated DN,
+/// which does not hold any real data
+pub struct WriteProbe {
+    /// DN of the probe entry. Its parent must exist
+    pub dn: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteProbeResult {
+    pub add: Duration,
+    pub modify: Duration,
+    pub delete: Duration,
+}
+
+impl WriteProbeResult {
+    pub fn total(&self) -> Duration {
+        self.add + self.modify + self.delete
+    }
+}
+
+impl WriteProbe {
+    pub fn new(dn: String) -> Self {
+        Self { dn }
+    }
+
+    fn cn(&self) -> Result<&str> {
+        self.dn
+            .split(',')
+            .next()
+            .and_then(|rdn| rdn.split_once('='))
+            .filter(|(attr, _)| attr.trim().eq_ignore_ascii_case("cn"))
+            .map(|(_, value)| value.trim())
+            .ok_or(anyhow!(
+                "Write probe DN must start with the cn RDN: {}",
+                self.dn
+            ))
+    }
+
+    /// Check if the entry was written by the probe (see `PROBE_MARKER`)
+    fn is_probe_entry(entry: &SearchEntry) -> bool {
+        let has_attr = |attr: &str, matches: &dyn Fn(&str) -> bool| {
+            entry
+                .attrs
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(attr))
+                .flat_map(|(_, values)| values)
+                .any(|value| matches(value))
+        };
+
+        has_attr("objectClass", &|value| {
+            value.eq_ignore_ascii_case("applicationProcess")
+        }) && has_attr("description", &|value| value.starts_with(PROBE_MARKER))
+    }
+
+    /// Remove the leftover of the previously interrupted probe. Fails if the entry under the
+    /// probe DN was not written by the probe
+    async fn remove_leftover(&self, ldap: &mut Ldap) -> Result<()> {
+        let ldap3::SearchResult(entries, result) = ldap
+            .search(
+                &self.dn,
+                Scope::Base,
+                "(objectClass=*)",
+                vec!["objectClass", "description"],
+            )
+            .await?;
+        if result.rc == NO_SUCH_OBJECT {
+            return Ok(());
+        }
+        result.success()?;
+
+        let Some(entry) = entries.into_iter().next().map(SearchEntry::construct) else {
+            return Ok(());
+        };
+        if !Self::is_probe_entry(&entry) {
+            return Err(anyhow!(
+                "Entry {} exists and was not written by the write probe, refusing to delete it",
+                self.dn
+            ));
+        }
+
+        ldap.delete(&self.dn).await?.success()?;
+        Ok(())
+    }
+
+    pub async fn run(&self, ldap: &mut Ldap) -> Result<WriteProbeResult> {
+        let cn = self.cn()?;
+        let timestamp = chrono::Utc::now().to_rfc3339();
+
+        self.remove_leftover(ldap).await?;
+
+        let start = Instant::now();
+        let description = format!("{PROBE_MARKER} {timestamp}");
+        ldap.add(
+            &self.dn,
+            vec![
+                ("objectClass", HashSet::from(["top", "applicationProcess"])),
+                ("cn", HashSet::from([cn])),
+                ("description", HashSet::from([description.as_str()])),
+            ],
+        )
+        .await?
+        .success()?;
+        let add = start.elapsed();
+
+        let start = Instant::now();
+        let description = format!("{PROBE_MARKER} {timestamp} modified");
+        ldap.modify(
+            &self.dn,
+            vec![Mod::Replace(
+                "description",
+                HashSet::from([description.as_str()]),
+            )],
+        )
+        .await?
+        .success()?;
+        let modify = start.elapsed();
+
+        let start = Instant::now();
+        ldap.delete(&self.dn).await?.success()?;
+        let delete = start.elapsed();
+
+        Ok(WriteProbeResult {
+            add,
+            modify,
+            delete,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn entry(attrs: &[(&str, &str)]) -> SearchEntry {
+        let mut entry_attrs: HashMap<String, Vec<String>> = HashMap::new();
+        for (attr, value) in attrs {
+            entry_attrs
+                .entry(attr.to_string())
+                .or_default()
+                .push(value.to_string());
+        }
+        SearchEntry {
+            dn: "cn=probe,dc=example,dc=com".to_string(),
+            attrs: entry_attrs,
+            bin_attrs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn only_marked_entries_are_probe_leftovers() {
+        let marker = format!("{PROBE_MARKER} 2024-01-01T00:00:00+00:00");
+        assert!(WriteProbe::is_probe_entry(&entry(&[
+            ("objectClass", "top"),
+            ("objectClass", "applicationProcess"),
+            ("description", &marker),
+        ])));
+        assert!(!WriteProbe::is_probe_entry(&entry(&[
+            ("objectClass", "applicationProcess"),
+            ("description", "Real service"),
+        ])));
+        assert!(!WriteProbe::is_probe_entry(&entry(&[
+            ("objectClass", "inetOrgPerson"),
+            ("description", &marker),
+        ])));
+    }
+}
@@ -1,14 +1,39 @@
 use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use std::time::Instant;
 
 use anyhow::Result;
 use ldap3::{
-    adapters::{Adapter, EntriesOnly, PagedResults},
+    adapters::{Adapter, EntriesOnly},
     Ldap, Scope, SearchEntry,
 };
 use serde::Deserialize;
 
-use crate::Bind;
+use crate::{paging::CountedPagedResults, BindMethod, LdapConfig};
+
+/// sizeLimitExceeded
+pub const RC_SIZE_LIMIT_EXCEEDED: u32 = 4;
+/// adminLimitExceeded (e.g. nsslapd-pagedsizelimit)
+pub const RC_ADMIN_LIMIT_EXCEEDED: u32 = 11;
+
+fn default_ok_codes() -> Vec<u32> {
+    vec![0]
+}
+
+/// How the `CustomQuery::value_attr` values of multiple entries are combined
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueAggregation {
+    /// Value of the first entry having the attribute
+    #[default]
+    First,
+    /// Sum of the values of all the entries
+    Sum,
+}
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct CustomQuery {
@@ -19,15 +44,45 @@ pub struct CustomQuery {
     #[serde(default)]
     pub attrs: Vec<String>,
 
+    /// Count entries that have these attributes present. They do not affect other metrics
+    #[serde(default)]
+    pub presence_attrs: Vec<String>,
+
+    /// Numeric attribute reported as the query value (first value of the entry). Does not
+    /// affect other metrics
+    pub value_attr: Option<String>,
+
+    #[serde(default)]
+    pub value_aggregation: ValueAggregation,
+
     pub verify_certs: Option<bool>,
-    pub bind: Option<Bind>,
+    pub bind: Option<BindMethod>,
     pub uri: Option<String>,
     pub page_size: Option<i32>,
     pub default_base: Option<String>,
 
+    /// Request the entries sorted by the server (by `entrydn`), so the checksum is computed while
+    /// they are streamed instead of buffering and sorting them. Falls back to the client sorting
+    /// if the server does not support the sort control
+    #[serde(default)]
+    pub server_sort: bool,
+
+    /// LDAP result codes considered healthy (e.g. 4 - sizeLimitExceeded)
+    #[serde(default = "default_ok_codes")]
+    pub ok_codes: Vec<u32>,
+
+    /// Lowercase and trim DN components before sorting the entries, so replicas returning DNs
+    /// in different case give the same checksum
+    #[serde(default)]
+    pub normalize_dn: bool,
+
     /// It's the operational parameter, handled by the code
     #[serde(skip, default)]
     pub ldap_config: Option<crate::LdapConfig>,
+
+    /// Number of the returned entries to keep in the metrics. Used for diagnostics
+    #[serde(skip, default)]
+    pub keep_entries: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -39,10 +94,66 @@ pub struct Metrics {
     /// Duration of the query
     pub query_time: std::time::Duration,
     pub ldap_code: u32,
+    /// Name of the `ldap_code`, e.g. `sizeLimitExceeded`
+    pub ldap_code_text: &'static str,
     pub sha256_checksum: String,
 
     /// Bytes of the received attributes values
     pub bytes: u64,
+
+    /// First entries returned by the query (see `CustomQuery::keep_entries`)
+    pub entries: Vec<SearchEntry>,
+
+    /// Entries were sorted by the server (see `CustomQuery::server_sort`)
+    pub server_sorted: bool,
+
+    /// Number of entries with the attribute present (see `CustomQuery::presence_attrs`)
+    pub attrs_present: HashMap<String, u64>,
+
+    /// Number of the pages fetched by the paged search
+    pub pages_fetched: u64,
+
+    /// Value of the `CustomQuery::value_attr`. None if no entry had a numeric value
+    pub value: Option<f64>,
+
+    /// Server returned only part of the results (sizeLimitExceeded or adminLimitExceeded).
+    /// Counts and checksum describe the partial set
+    pub truncated: bool,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+fn hash_entry(hasher: &mut Sha256, value: &serde_json::Value) {
+    hasher.update(value.to_string());
+}
+
+/// Sha256 of the entries values sorted by DN, so it does not depend on the order in which the
+/// server returned them
+fn checksum(mut entries: Vec<(String, serde_json::Value)>) -> String {
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut hasher = Sha256::new();
+    for (_, value) in &entries {
+        hash_entry(&mut hasher, value);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// `CN=John ,  OU=People` -> `cn=john,ou=people`
+pub fn normalize_dn(dn: &str) -> String {
+    dn.split(',')
+        .map(|component| match component.split_once('=') {
+            Some((attr, value)) => format!(
+                "{}={}",
+                attr.trim().to_lowercase(),
+                value.trim().to_lowercase()
+            ),
+            None => component.trim().to_lowercase(),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
 impl CustomQuery {
@@ -53,16 +164,29 @@ impl CustomQuery {
             filter,
             max_entries: None,
             attrs: Vec::new(),
+            presence_attrs: Vec::new(),
+            value_attr: None,
+            value_aggregation: ValueAggregation::First,
             bind: None,
             uri: None,
             page_size: None,
             default_base: None,
+            server_sort: false,
+            ok_codes: default_ok_codes(),
+            normalize_dn: false,
             verify_certs: None,
             ldap_config: Some(ldap_config),
+            keep_entries: 0,
         }
     }
 
-    pub async fn connect(&self) -> Result<Ldap> {
+    /// Check if the LDAP result code is one of the `ok_codes`
+    pub fn is_ok_code(&self, code: u32) -> bool {
+        self.ok_codes.contains(&code)
+    }
+
+    /// Main ldap config with the per-query overrides applied
+    pub fn effective_config(&self) -> Result<LdapConfig> {
         let mut config = self.ldap_config.clone().ok_or(anyhow::anyhow!(
             "No ldap config. This is (most likely) a bug"
         ))?;
@@ -87,50 +211,139 @@ impl CustomQuery {
             config.verify_certs = verify_certs;
         }
 
-        config.connect().await
+        Ok(config)
     }
-    pub async fn get_metrics(&self) -> Result<Metrics> {
-        let mut ldap = self.connect().await?;
 
-        let ldap_config = self.ldap_config.as_ref().ok_or(anyhow::anyhow!(
-            "No ldap config. This is (most likely) a bug"
-        ))?;
+    pub async fn connect(&self) -> Result<Ldap> {
+        self.effective_config()?.connect_with_retry().await
+    }
+
+    pub async fn get_metrics(&self) -> Result<Metrics> {
+        let ldap_config = self.effective_config()?;
+        let mut ldap = ldap_config.connect_with_retry().await?;
 
+        let pages = Arc::new(AtomicU64::new(0));
         let adapters: Vec<Box<dyn Adapter<_, _>>> = vec![
             Box::new(EntriesOnly::new()),
-            Box::new(PagedResults::new(ldap_config.page_size)),
+            Box::new(CountedPagedResults::new(
+                ldap_config.page_size,
+                pages.clone(),
+            )),
         ];
 
         if let Some(max_entries) = self.max_entries {
             ldap.with_search_options(ldap3::SearchOptions::new().sizelimit(max_entries));
         }
 
+        let server_sorted = self.server_sort
+            && crate::sort::supports_control(&mut ldap, crate::sort::SORT_REQUEST_OID).await?;
+        if server_sorted {
+            ldap.with_controls(crate::sort::ServerSideSort {
+                attr: crate::sort::DN_SORT_ATTR,
+            });
+        }
+
+        // Empty attrs list means all attributes, so presence and value attributes are already there
+        let extra_attrs: Vec<String> = if self.attrs.is_empty() {
+            Vec::new()
+        } else {
+            self.presence_attrs
+                .iter()
+                .chain(self.value_attr.iter())
+                .filter(|x| !self.attrs.iter().any(|y| y.eq_ignore_ascii_case(x)))
+                .cloned()
+                .collect()
+        };
+
         let mut search = ldap
             .streaming_search_with(
                 adapters,
                 &ldap_config.default_base,
                 Scope::Subtree,
                 &self.filter,
-                &self.attrs,
+                self.attrs
+                    .iter()
+                    .chain(extra_attrs.iter())
+                    .collect::<Vec<_>>(),
             )
             .await?;
         let mut object_count = 0;
         let mut attrs_count: u64 = 0;
 
+        // Entries sorted by the server are hashed as they come, others are buffered and sorted
+        let mut hasher = server_sorted.then(Sha256::new);
         let mut checksums: Vec<(String, serde_json::Value)> = Vec::new();
 
         let mut bytes = 0_u64;
+        let mut entries = Vec::new();
+        let mut attrs_present: HashMap<String, u64> =
+            self.presence_attrs.iter().map(|x| (x.clone(), 0)).collect();
+        let mut value: Option<f64> = None;
 
         let start = Instant::now();
         while let Some(entry) = search.next().await? {
-            let entry = SearchEntry::construct(entry);
+            let mut entry = SearchEntry::construct(entry);
+
+            for (attr, count) in attrs_present.iter_mut() {
+                if entry
+                    .attrs
+                    .iter()
+                    .any(|x| x.0.eq_ignore_ascii_case(attr) && !x.1.is_empty())
+                    || entry
+                        .bin_attrs
+                        .iter()
+                        .any(|x| x.0.eq_ignore_ascii_case(attr) && !x.1.is_empty())
+                {
+                    *count += 1;
+                }
+            }
+            if let Some(value_attr) = &self.value_attr {
+                let entry_value = entry
+                    .attrs
+                    .iter()
+                    .find(|x| x.0.eq_ignore_ascii_case(value_attr))
+                    .and_then(|x| x.1.first())
+                    .and_then(|x| x.trim().parse::<f64>().ok());
+
+                value = match (self.value_aggregation, value, entry_value) {
+                    (ValueAggregation::First, Some(value), _) => Some(value),
+                    (ValueAggregation::Sum, Some(value), Some(entry_value)) => {
+                        Some(value + entry_value)
+                    }
+                    (_, value, entry_value) => entry_value.or(value),
+                };
+            }
+
+            entry
+                .attrs
+                .retain(|attr, _| !extra_attrs.iter().any(|x| x.eq_ignore_ascii_case(attr)));
+            entry
+                .bin_attrs
+                .retain(|attr, _| !extra_attrs.iter().any(|x| x.eq_ignore_ascii_case(attr)));
 
-            bytes += entry.attrs.iter().fold(0, |acc, x| acc + x.1.len()) as u64;
-            attrs_count += entry.attrs.len() as u64;
+            if entries.len() < self.keep_entries {
+                entries.push(entry.clone());
+            }
+
+            bytes += entry
+                .attrs
+                .values()
+                .flatten()
+                .map(|x| x.len())
+                .chain(entry.bin_attrs.values().flatten().map(|x| x.len()))
+                .sum::<usize>() as u64;
+            attrs_count += (entry.attrs.len() + entry.bin_attrs.len()) as u64;
+
+            // Binary (non UTF-8) values, e.g. userCertificate;binary, are hashed as hex
+            let bin_attrs = entry
+                .bin_attrs
+                .into_iter()
+                .map(|(attr, values)| (attr, values.iter().map(|x| to_hex(x)).collect()));
 
             let mut attrs: Vec<(String, serde_json::Value)> = entry
                 .attrs
                 .into_iter()
+                .chain(bin_attrs)
                 .map(|mut x| {
                     x.1.sort();
                     (x.0, serde_json::to_value(&x.1).unwrap())
@@ -139,31 +352,145 @@ impl CustomQuery {
 
             attrs.sort_by_key(|x| x.0.clone());
 
-            checksums.push((entry.dn.clone(), serde_json::to_value(attrs).unwrap()));
+            let value = serde_json::to_value(attrs).unwrap();
+            match &mut hasher {
+                Some(hasher) => hash_entry(hasher, &value),
+                None => {
+                    // Normalized DNs follow the entrydn order of the server sorting
+                    let dn = if self.normalize_dn || self.server_sort {
+                        normalize_dn(&entry.dn)
+                    } else {
+                        entry.dn.clone()
+                    };
+                    checksums.push((dn, value));
+                }
+            }
 
             object_count += 1;
         }
         let query_time = start.elapsed();
 
-        let ldap_code = search.finish().await.rc;
+        let result = search.finish().await;
+        let ldap_code = result.rc;
 
-        checksums.sort_by_key(|x| x.0.clone());
+        if server_sorted {
+            if let Some(code) = crate::sort::sort_result_code(&result.ctrls).filter(|x| *x != 0) {
+                return Err(anyhow::anyhow!(
+                    "Server side sorting failed with the code {code}"
+                ));
+            }
+        }
 
-        let mut hasher = Sha256::new();
-        hasher.update(
-            checksums
-                .into_iter()
-                .fold(String::new(), |acc, x| format!("{acc}{}", x.1)),
-        );
-        let sha256_checksum = format!("{:x}", hasher.finalize());
+        let sha256_checksum = match hasher {
+            Some(hasher) => format!("{:x}", hasher.finalize()),
+            None => checksum(checksums),
+        };
 
         Ok(Metrics {
             object_count,
             attrs_count,
             query_time,
             ldap_code,
+            ldap_code_text: crate::error::result_code_name(ldap_code),
             sha256_checksum,
             bytes,
+            entries,
+            server_sorted,
+            attrs_present,
+            value,
+            pages_fetched: pages.load(Ordering::Relaxed),
+            truncated: ldap_code == RC_SIZE_LIMIT_EXCEEDED || ldap_code == RC_ADMIN_LIMIT_EXCEEDED,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_does_not_depend_on_the_entries_order() {
+        let a = ("cn=a".to_string(), serde_json::json!([["cn", ["a"]]]));
+        let b = ("cn=b".to_string(), serde_json::json!([["cn", ["b"]]]));
+
+        assert_eq!(
+            checksum(vec![a.clone(), b.clone()]),
+            checksum(vec![b.clone(), a.clone()])
+        );
+        assert_ne!(checksum(vec![a.clone()]), checksum(vec![b]));
+    }
+
+    #[test]
+    fn streamed_checksum_matches_the_sorted_one() {
+        let a = ("cn=a".to_string(), serde_json::json!([["cn", ["a"]]]));
+        let b = ("cn=b".to_string(), serde_json::json!([["cn", ["b"]]]));
+
+        let mut hasher = Sha256::new();
+        hash_entry(&mut hasher, &a.1);
+        hash_entry(&mut hasher, &b.1);
+
+        assert_eq!(format!("{:x}", hasher.finalize()), checksum(vec![b, a]));
+    }
+
+    #[test]
+    fn normalize_dn_ignores_case_and_spaces() {
+        assert_eq!(
+            normalize_dn("CN=John Smith, OU=People,DC=Example,DC=com"),
+            normalize_dn("cn=john smith,ou=people, dc=example ,dc=COM")
+        );
+        assert_eq!(
+            normalize_dn("CN=John ,  OU=People"),
+            "cn=john,ou=people".to_string()
+        );
+        assert_ne!(
+            normalize_dn("cn=john,ou=people"),
+            normalize_dn("cn=jane,ou=people")
+        );
+    }
+
+    #[test]
+    fn normalized_checksum_ignores_dn_case() {
+        let a = serde_json::json!([["cn", ["a"]]]);
+        let b = serde_json::json!([["cn", ["b"]]]);
+        let entries = |dn_a: &str, dn_b: &str, normalize: bool| {
+            let dn = |x: &str| {
+                if normalize {
+                    normalize_dn(x)
+                } else {
+                    x.to_string()
+                }
+            };
+            vec![(dn(dn_a), a.clone()), (dn(dn_b), b.clone())]
+        };
+
+        // Uppercase DN sorts before the lowercase ones, changing the order of the hashed values
+        assert_ne!(
+            checksum(entries("cn=a,dc=example", "CN=B,DC=example", false)),
+            checksum(entries("cn=a,dc=example", "cn=b,dc=example", false))
+        );
+        assert_eq!(
+            checksum(entries("cn=a,dc=example", "CN=B,DC=example", true)),
+            checksum(entries("cn=a,dc=example", "cn=b,dc=example", true))
+        );
+    }
+
+    #[test]
+    fn query_overrides_search_base() {
+        let main = LdapConfig {
+            default_base: "dc=example,dc=com".to_string(),
+            page_size: 999,
+            ..Default::default()
+        };
+        let mut query = CustomQuery::new("people".to_string(), "(uid=*)".to_string(), main);
+
+        let config = query.effective_config().unwrap();
+        assert_eq!(config.default_base, "dc=example,dc=com");
+        assert_eq!(config.page_size, 999);
+
+        query.default_base = Some("ou=people,dc=example,dc=com".to_string());
+        query.page_size = Some(100);
+        let config = query.effective_config().unwrap();
+        assert_eq!(config.default_base, "ou=people,dc=example,dc=com");
+        assert_eq!(config.page_size, 100);
+    }
+}
@@ -1,8 +1,12 @@
+use std::collections::{BTreeSet, HashMap};
+
 use serde::{de::Error, Deserialize, Deserializer};
 
 use anyhow::{anyhow, Context, Result};
 use chrono::NaiveDateTime;
 use ldap3::{Ldap, Scope, SearchEntry};
+
+use crate::parse_time;
 use serde_aux::prelude::*;
 
 const SPACE: &str = " ";
@@ -14,16 +18,29 @@ const HOST: &str = "nsDS5ReplicaHost";
 const ROOT: &str = "nsDS5ReplicaRoot";
 const RUV: &str = "nsds50ruv";
 const STATUS: &str = "nsds5replicaLastUpdateStatusJSON";
+/// Plain text status, the only one available on older servers (e.g. RHEL 7)
+const LEGACY_STATUS: &str = "nsds5replicaLastUpdateStatus";
+const ENABLED: &str = "nsds5ReplicaEnabled";
 
 const UPDATE_START: &str = "nsds5replicaLastUpdateStart";
 const UPDATE_END: &str = "nsds5replicaLastUpdateEnd";
 const CHANGES_SENT: &str = "nsds5replicaChangesSentSinceStartup";
 
+const INIT_STATUS: &str = "nsds5replicaLastInitStatusJSON";
+const INIT_START: &str = "nsds5replicaLastInitStart";
+const INIT_END: &str = "nsds5replicaLastInitEnd";
+
 const REPLICA_ROOT: &str = "nsDS5ReplicaRoot";
 const REPLICA_NAME: &str = "nsDS5ReplicaName";
 pub const REPLICA_CHANGES: &str = "nsds5ReplicaChangeCount";
 pub const REPLICA_ACTIVE: &str = "nsds5replicareapactive";
 
+/// State of the `StatusJSON::unknown`
+pub const UNKNOWN_STATE: &str = "unknown";
+
+/// NSDS50_REPL_REPLICA_BUSY - consumer is being updated by other supplier
+const REPL_RC_REPLICA_BUSY: i64 = 1;
+
 pub fn get_attr(entry: &SearchEntry, attr: &str) -> String {
     entry
         .attrs
@@ -39,7 +56,23 @@ where
     D: Deserializer<'de>,
 {
     let s: &str = Deserialize::deserialize(deserializer)?;
-    NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%SZ").map_err(D::Error::custom)
+    crate::parse_time(s).map_err(D::Error::custom)
+}
+
+/// Replication timestamp. Agreements that never ran report empty, `0` or epoch zero values
+fn timestamp(value: &str) -> Option<NaiveDateTime> {
+    parse_time(value)
+        .ok()
+        .filter(|x| x.and_utc().timestamp() != 0)
+}
+
+/// Duration between the start and end timestamps of the update. None if any of them is missing, or
+/// if end is earlier than start - the update is in progress (end is left from the previous run)
+fn duration_seconds(start: &str, end: &str) -> Option<i64> {
+    match (timestamp(start), timestamp(end)) {
+        (Some(start), Some(end)) if end >= start => Some((end - start).num_seconds()),
+        _ => None,
+    }
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -59,6 +92,111 @@ pub struct StatusJSON {
     pub message: String,
 }
 
+impl StatusJSON {
+    /// Build the status from the plain text one, e.g. `0 Replica acquired successfully: Incremental
+    /// update succeeded` or `Error (0) Replica acquired successfully: ...`. The leading number is
+    /// used as ldap return code, state is guessed from the code and the text. Date is unknown
+    pub fn from_legacy(status: &str) -> Result<Self> {
+        let trimmed = status.trim();
+        let trimmed = trimmed
+            .strip_prefix("Error")
+            .unwrap_or(trimmed)
+            .trim_start();
+        let (code, text) = trimmed.split_once(SPACE).unwrap_or((trimmed, ""));
+        let ldap_rc = code
+            .trim_matches(|x| x == '(' || x == ')')
+            .parse::<i64>()
+            .map_err(|_| anyhow!("Unsupported replication status {status:?}"))?;
+        let text = text.trim().to_string();
+
+        let lowercase = text.to_lowercase();
+        let state = if ldap_rc != 0 || lowercase.contains("fail") || lowercase.contains("error") {
+            "red"
+        } else if lowercase.contains("busy") || lowercase.contains("in progress") {
+            "amber"
+        } else {
+            "green"
+        };
+
+        Ok(Self {
+            state: state.to_string(),
+            ldap_rc,
+            ldap_rc_text: text.clone(),
+            repl_rc: 0,
+            repl_rc_text: text.clone(),
+            date: NaiveDateTime::default(),
+            message: text,
+        })
+    }
+
+    /// Status of the agreement whose status attribute is missing or unparsable. It is never
+    /// healthy, `message` describes the problem
+    pub fn unknown(message: String) -> Self {
+        Self {
+            state: UNKNOWN_STATE.to_string(),
+            ldap_rc: 0,
+            ldap_rc_text: String::new(),
+            repl_rc: 0,
+            repl_rc_text: String::new(),
+            date: NaiveDateTime::default(),
+            message,
+        }
+    }
+
+    /// JSON status of the agreement, or the legacy one on older servers. Problems are reported as
+    /// unknown status, so they do not abort the scrape of the other agreements
+    fn from_entry(entry: &SearchEntry) -> Self {
+        let status = match entry.attrs.get(STATUS).and_then(|x| x.first()) {
+            Some(status) => serde_json::from_str(status).map_err(anyhow::Error::from),
+            None => match entry.attrs.get(LEGACY_STATUS).and_then(|x| x.first()) {
+                Some(status) => Self::from_legacy(status),
+                None => Err(anyhow!("Missing {STATUS} and {LEGACY_STATUS}")),
+            },
+        };
+
+        status.unwrap_or_else(|error| {
+            tracing::warn!("Unknown status of the agreement {}: {}", entry.dn, error);
+            Self::unknown(error.to_string())
+        })
+    }
+
+    /// Agreement is healthy if the state is green and there are no ldap/replication errors
+    pub fn is_healthy(&self) -> bool {
+        self.state == "green" && self.ldap_rc == 0 && self.repl_rc == 0
+    }
+
+    /// Unhealthy state that is expected to resolve itself (e.g. busy replica, update in progress).
+    /// Ldap errors (e.g. unreachable consumer) are never transient
+    pub fn is_transient(&self) -> bool {
+        !self.is_healthy()
+            && self.ldap_rc == 0
+            && (self.state == "amber"
+                || self.repl_rc == REPL_RC_REPLICA_BUSY
+                || self.repl_rc_text.to_lowercase().contains("busy"))
+    }
+
+    /// Check if status matches the key: numeric keys are compared with the replication return
+    /// code, others with the state or (case insensitive substring) the replication status text
+    pub fn matches(&self, key: &str) -> bool {
+        if let Ok(code) = key.parse::<i64>() {
+            return self.repl_rc == code;
+        }
+        let key = key.to_lowercase();
+        self.state.to_lowercase() == key || self.repl_rc_text.to_lowercase().contains(&key)
+    }
+
+    /// Most specific description of the status: replication error, ldap error or the message
+    pub fn reason(&self) -> &str {
+        if self.repl_rc != 0 {
+            &self.repl_rc_text
+        } else if self.ldap_rc != 0 {
+            &self.ldap_rc_text
+        } else {
+            &self.message
+        }
+    }
+}
+
 pub struct ChangesSent {
     pub replica_id: i64,
     pub changes_replayed: u64,
@@ -66,25 +204,31 @@ pub struct ChangesSent {
 }
 
 impl ChangesSent {
+    /// Parse `id:replayed/skipped` tokens. Some server versions omit the skipped part
+    /// (`id:replayed`), then it is 0
     pub fn parse(definition: &str) -> Vec<Self> {
         definition
-            .split(SPACE)
-            .filter_map(|definition: &str| {
-                let (replica_id, changes) = definition.split_once(":")?;
-                let (changes_sent, changes_skipped) = changes.split_once("/")?;
-
-                let replica_id = replica_id.parse().ok()?;
-                let changes_sent = changes_sent.parse().ok()?;
-                let changes_skipped = changes_skipped.parse().ok()?;
-
-                Some(ChangesSent {
-                    replica_id,
-                    changes_replayed: changes_sent,
-                    changes_skipped,
-                })
+            .split_whitespace()
+            .filter_map(|token: &str| {
+                let parsed = Self::parse_token(token);
+                if parsed.is_none() {
+                    tracing::trace!("Skipping unparsable changes sent token {token:?}");
+                }
+                parsed
             })
             .collect()
     }
+
+    fn parse_token(token: &str) -> Option<Self> {
+        let (replica_id, changes) = token.split_once(":")?;
+        let (changes_sent, changes_skipped) = changes.split_once("/").unwrap_or((changes, "0"));
+
+        Some(ChangesSent {
+            replica_id: replica_id.parse().ok()?,
+            changes_replayed: changes_sent.parse().ok()?,
+            changes_skipped: changes_skipped.parse().ok()?,
+        })
+    }
 }
 
 pub enum Ruv {
@@ -130,6 +274,14 @@ impl Ruv {
         }
     }
 
+    /// Unix timestamp of the last change. CSN starts with 8 hex digits of the time it was created
+    pub fn last_change_timestamp(&self) -> Option<i64> {
+        match self {
+            Ruv::Info { last_change, .. } => i64::from_str_radix(last_change.get(..8)?, 16).ok(),
+            _ => None,
+        }
+    }
+
     pub fn parse(definition: &str) -> Result<Self> {
         const REPLICAGEN: &str = "replicageneration";
         const REPLICA_PREFIX: &str = "replica ";
@@ -195,6 +347,26 @@ impl Ruv {
     }
 }
 
+/// DNs of the replication conflict entries under the base. Conflict entries are ldapsubentries,
+/// so they need to be requested explicitly
+pub async fn conflict_entries(ldap: &mut Ldap, base: &str) -> Result<Vec<String>> {
+    let search = ldap
+        .search(
+            base,
+            Scope::Subtree,
+            "(&(nsds5ReplConflict=*)(|(objectClass=*)(objectClass=ldapsubentry)))",
+            vec!["1.1"],
+        )
+        .await?
+        .success()?;
+
+    Ok(search
+        .0
+        .into_iter()
+        .map(|x| SearchEntry::construct(x).dn)
+        .collect())
+}
+
 /// Get version of the replica plugin
 pub async fn replication_plugin_version(ldap: &mut Ldap) -> Result<String> {
     const ATTR: &str = "nsslapd-pluginversion";
@@ -227,20 +399,88 @@ pub async fn replication_plugin_version(ldap: &mut Ldap) -> Result<String> {
     Ok(version.to_string())
 }
 
+/// Agreements to scrape. Empty filter matches all of them
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AgreementFilter {
+    /// Suffix of the agreements (nsDS5ReplicaRoot), e.g. dc=example,dc=com
+    #[serde(default)]
+    pub root: Option<String>,
+
+    /// Agreement cn. `*` matches any characters, e.g. `to-ldap*`
+    #[serde(default)]
+    pub cn: Option<String>,
+}
+
+impl AgreementFilter {
+    /// Search filter of the agreements, so the server returns only the matching ones
+    pub fn ldap_filter(&self) -> String {
+        let mut filter = "(objectClass=nsds5ReplicationAgreement)".to_string();
+
+        if let Some(root) = &self.root {
+            filter = format!("(&{filter}({ROOT}={}))", ldap3::ldap_escape(root));
+        }
+
+        if let Some(cn) = &self.cn {
+            let glob = cn
+                .split('*')
+                .map(ldap3::ldap_escape)
+                .collect::<Vec<_>>()
+                .join("*");
+            filter = format!("(&{filter}({CN}={glob}))");
+        }
+
+        filter
+    }
+}
+
 pub struct Agreement {
     pub cn: String,
     pub host: String,
     pub root: String,
 
+    /// Agreement can be paused (nsds5ReplicaEnabled: off). Missing attribute means enabled
+    pub enabled: bool,
+
     pub changes_sent: Vec<ChangesSent>,
-    pub last_update_duration_seconds: i64,
+    /// None if the agreement never ran or the update is in progress
+    pub last_update_duration_seconds: Option<i64>,
 
     pub ruvs: Vec<Ruv>,
     pub status: StatusJSON,
+
+    /// Status of the last total update (online init). None if the consumer was never initialized
+    /// by this agreement
+    pub init_status: Option<StatusJSON>,
+    pub last_init_duration_seconds: Option<i64>,
 }
 
 impl Agreement {
+    /// Replica ids present in the RUV of the agreement (broken ones included)
+    pub fn replica_ids(&self) -> BTreeSet<i64> {
+        self.ruvs
+            .iter()
+            .filter(|x| !matches!(x, Ruv::ReplicaGen(_)))
+            .map(|x| x.get_replica_id())
+            .collect()
+    }
+
+    /// Replica ids known from the RUVs of all the agreements, grouped by the suffix (root)
+    pub fn known_replica_ids(agreements: &[Self]) -> HashMap<String, BTreeSet<i64>> {
+        let mut result: HashMap<String, BTreeSet<i64>> = HashMap::new();
+        for agreement in agreements {
+            result
+                .entry(agreement.root.clone())
+                .or_default()
+                .extend(agreement.replica_ids());
+        }
+        result
+    }
+
     pub async fn scrape(ldap: &mut Ldap) -> Result<Vec<Self>> {
+        Self::scrape_filtered(ldap, &AgreementFilter::default()).await
+    }
+
+    pub async fn scrape_filtered(ldap: &mut Ldap, filter: &AgreementFilter) -> Result<Vec<Self>> {
         let attrs = vec![
             CN,
             HOST,
@@ -250,15 +490,15 @@ impl Agreement {
             UPDATE_END,
             CHANGES_SENT,
             STATUS,
+            ENABLED,
+            LEGACY_STATUS,
+            INIT_STATUS,
+            INIT_START,
+            INIT_END,
         ];
 
         let search = ldap
-            .search(
-                "cn=config",
-                Scope::Subtree,
-                "(objectClass=nsds5ReplicationAgreement)",
-                attrs,
-            )
+            .search("cn=config", Scope::Subtree, &filter.ldap_filter(), attrs)
             .await?;
 
         let mut result = Vec::new();
@@ -269,32 +509,53 @@ impl Agreement {
             let cn = get_attr(&entry, CN);
             let host = get_attr(&entry, HOST);
             let root = get_attr(&entry, ROOT);
+            let enabled = !get_attr(&entry, ENABLED).eq_ignore_ascii_case("off");
 
             let update_start = get_attr(&entry, UPDATE_START);
             let update_end = get_attr(&entry, UPDATE_END);
             let changes_sent = get_attr(&entry, CHANGES_SENT);
-            let status = get_attr(&entry, STATUS);
+            let init_start = get_attr(&entry, INIT_START);
+            let init_end = get_attr(&entry, INIT_END);
 
             let mut ruvs = Vec::<Ruv>::new();
             for ruv in entry.attrs.get(RUV).unwrap_or(EMPTY_VEC_STR) {
                 ruvs.push(Ruv::parse(ruv)?)
             }
 
-            let update_start = NaiveDateTime::parse_from_str(&update_start, "%Y%m%d%H%M%SZ")?;
-            let update_end = NaiveDateTime::parse_from_str(&update_end, "%Y%m%d%H%M%SZ")?;
-            let last_update_duration_seconds = (update_start - update_end).num_seconds();
+            // Unparsable (or missing) timestamps should not abort scrape of all the agreements
+            let last_update_duration_seconds = duration_seconds(&update_start, &update_end);
+            if last_update_duration_seconds.is_none() {
+                tracing::debug!(
+                    "Agreement {cn} has no complete update (start: {update_start}, end: {update_end}). Skipping duration"
+                );
+            }
+            let last_init_duration_seconds = duration_seconds(&init_start, &init_end);
 
             let changes_sent = ChangesSent::parse(&changes_sent);
-            let status: StatusJSON = serde_json::from_str(&status)?;
+            let status = StatusJSON::from_entry(&entry);
+            let init_status =
+                entry
+                    .attrs
+                    .get(INIT_STATUS)
+                    .and_then(|x| x.first())
+                    .map(|init_status| {
+                        serde_json::from_str(init_status).unwrap_or_else(|error| {
+                            tracing::warn!("Unknown init status of the agreement {cn}: {error}");
+                            StatusJSON::unknown(error.to_string())
+                        })
+                    });
 
             result.push(Agreement {
                 cn,
                 host,
                 root,
+                enabled,
                 changes_sent,
                 last_update_duration_seconds,
                 ruvs,
                 status,
+                init_status,
+                last_init_duration_seconds,
             })
         }
         Ok(result)
@@ -343,3 +604,178 @@ impl Replica {
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn changes(definition: &str) -> Vec<(i64, u64, u64)> {
+        ChangesSent::parse(definition)
+            .into_iter()
+            .map(|x| (x.replica_id, x.changes_replayed, x.changes_skipped))
+            .collect()
+    }
+
+    #[test]
+    fn changes_sent_with_skipped() {
+        assert_eq!(changes("1:10/2 2:5/0"), vec![(1, 10, 2), (2, 5, 0)]);
+    }
+
+    #[test]
+    fn changes_sent_without_skipped() {
+        assert_eq!(changes("1:10 2:5"), vec![(1, 10, 0), (2, 5, 0)]);
+        assert_eq!(changes("1:10 2:5/3"), vec![(1, 10, 0), (2, 5, 3)]);
+    }
+
+    #[test]
+    fn changes_sent_skips_invalid_tokens() {
+        assert_eq!(changes(""), vec![]);
+        assert_eq!(changes("1 x:2 3:y 4:5/z 6:7"), vec![(6, 7, 0)]);
+    }
+
+    #[test]
+    fn update_duration() {
+        assert_eq!(
+            duration_seconds("20240101120000Z", "20240101120130Z"),
+            Some(90)
+        );
+        assert_eq!(
+            duration_seconds("20240101120000Z", "20240101120000Z"),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn update_duration_in_progress() {
+        // End is left from the previous update
+        assert_eq!(duration_seconds("20240101120130Z", "20240101120000Z"), None);
+    }
+
+    #[test]
+    fn update_duration_without_timestamps() {
+        assert_eq!(duration_seconds("", "20240101120000Z"), None);
+        assert_eq!(duration_seconds("20240101120000Z", "0"), None);
+        assert_eq!(duration_seconds("19700101000000Z", "20240101120000Z"), None);
+    }
+
+    fn filter(root: Option<&str>, cn: Option<&str>) -> String {
+        AgreementFilter {
+            root: root.map(str::to_string),
+            cn: cn.map(str::to_string),
+        }
+        .ldap_filter()
+    }
+
+    #[test]
+    fn ldap_filter_without_restrictions() {
+        assert_eq!(
+            filter(None, None),
+            "(objectClass=nsds5ReplicationAgreement)"
+        );
+    }
+
+    #[test]
+    fn ldap_filter_root() {
+        assert_eq!(
+            filter(Some("dc=example,dc=com"), None),
+            "(&(objectClass=nsds5ReplicationAgreement)(nsDS5ReplicaRoot=dc=example,dc=com))"
+        );
+    }
+
+    #[test]
+    fn ldap_filter_cn_glob() {
+        assert_eq!(
+            filter(None, Some("to-*(x)*")),
+            "(&(objectClass=nsds5ReplicationAgreement)(cn=to-*\\28x\\29*))"
+        );
+    }
+
+    #[test]
+    fn ldap_filter_root_and_cn() {
+        assert_eq!(
+            filter(Some("dc=example,dc=com"), Some("to-ldap*")),
+            "(&(&(objectClass=nsds5ReplicationAgreement)(nsDS5ReplicaRoot=dc=example,dc=com))(cn=to-ldap*))"
+        );
+    }
+
+    #[test]
+    fn ldap_filter_escapes_root() {
+        assert_eq!(
+            filter(Some("o=a(b)\\c*"), None),
+            "(&(objectClass=nsds5ReplicationAgreement)(nsDS5ReplicaRoot=o=a\\28b\\29\\5cc\\2a))"
+        );
+    }
+
+    #[test]
+    fn legacy_status_with_leading_code() {
+        let status = StatusJSON::from_legacy(
+            "0 Replica acquired successfully: Incremental update succeeded",
+        )
+        .unwrap();
+        assert_eq!(status.ldap_rc, 0);
+        assert_eq!(status.state, "green");
+        assert_eq!(
+            status.message,
+            "Replica acquired successfully: Incremental update succeeded"
+        );
+        assert!(status.is_healthy());
+
+        let status = StatusJSON::from_legacy("1 Can't acquire busy replica").unwrap();
+        assert_eq!(status.ldap_rc, 1);
+        assert_eq!(status.state, "red");
+    }
+
+    #[test]
+    fn legacy_status_with_error_prefix() {
+        let status = StatusJSON::from_legacy(
+            "Error (0) Replica acquired successfully: Incremental update started",
+        )
+        .unwrap();
+        assert_eq!(status.ldap_rc, 0);
+        assert_eq!(status.state, "green");
+
+        let status = StatusJSON::from_legacy(
+            "Error (-1) Problem connecting to replica - LDAP error: Can't contact LDAP server",
+        )
+        .unwrap();
+        assert_eq!(status.ldap_rc, -1);
+        assert_eq!(status.state, "red");
+        assert!(!status.is_healthy());
+
+        let status = StatusJSON::from_legacy("Error (0) Replica busy, update in progress").unwrap();
+        assert_eq!(status.state, "amber");
+    }
+
+    #[test]
+    fn legacy_status_without_code() {
+        assert!(StatusJSON::from_legacy("Replica acquired successfully").is_err());
+        assert!(StatusJSON::from_legacy("").is_err());
+    }
+
+    #[test]
+    fn missing_or_invalid_status_is_unknown() {
+        let entry = |attrs: &[(&str, &str)]| SearchEntry {
+            dn: "cn=to-ldap2,cn=replica,cn=config".to_string(),
+            attrs: attrs
+                .iter()
+                .map(|(attr, value)| (attr.to_string(), vec![value.to_string()]))
+                .collect(),
+            bin_attrs: HashMap::new(),
+        };
+
+        let status = StatusJSON::from_entry(&entry(&[]));
+        assert_eq!(status.state, UNKNOWN_STATE);
+        assert!(!status.is_healthy());
+        assert!(!status.is_transient());
+
+        let status = StatusJSON::from_entry(&entry(&[(STATUS, "{not json")]));
+        assert_eq!(status.state, UNKNOWN_STATE);
+
+        let status = StatusJSON::from_entry(&entry(&[(LEGACY_STATUS, "garbage")]));
+        assert_eq!(status.state, UNKNOWN_STATE);
+
+        let status =
+            StatusJSON::from_entry(&entry(&[(LEGACY_STATUS, "0 Incremental update succeeded")]));
+        assert_eq!(status.state, "green");
+    }
+}
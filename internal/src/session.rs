@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use ldap3::Ldap;
+
+use crate::LdapConfig;
+
+/// Reusable connection. The connection is created on demand and recycled after
+/// `connection_max_lifetime_seconds`, so it does not stay pinned to a single server behind a VIP.
+/// Reports `internal.ldap.session.{created,recycled,errored}` counters
+pub struct LdapSession {
+    ldap_config: LdapConfig,
+    connection: Option<(Ldap, Instant)>,
+}
+
+impl LdapSession {
+    pub fn new(ldap_config: LdapConfig) -> Self {
+        Self {
+            ldap_config,
+            connection: None,
+        }
+    }
+
+    fn expired(&self, created: Instant) -> bool {
+        self.ldap_config
+            .connection_max_lifetime_seconds
+            .is_some_and(|max| created.elapsed() >= Duration::from_secs(max))
+    }
+
+    /// Get the connection. New one is created if there is none or it is too old
+    pub async fn get(&mut self) -> Result<&mut Ldap> {
+        if let Some((mut ldap, created)) = self.connection.take() {
+            if self.expired(created) {
+                metrics::counter!("internal.ldap.session.recycled").increment(1);
+                let _ = ldap.unbind().await;
+            } else {
+                return Ok(&mut self.connection.insert((ldap, created)).0);
+            }
+        }
+
+        let ldap = self
+            .ldap_config
+            .connect_with_retry()
+            .await
+            .inspect_err(|_| {
+                metrics::counter!("internal.ldap.session.errored").increment(1);
+            })?;
+        metrics::counter!("internal.ldap.session.created").increment(1);
+
+        Ok(&mut self.connection.insert((ldap, Instant::now())).0)
+    }
+
+    /// Drop the connection after an error. Next `get` creates a new one
+    pub fn invalidate(&mut self) {
+        if self.connection.take().is_some() {
+            metrics::counter!("internal.ldap.session.errored").increment(1);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Server side sorting control ([RFC 2891](https://tools.ietf.org/html/rfc2891))
+
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use ldap3::{
+    asn1::{parse_tag, parse_uint, ASNTag, OctetString, Sequence, Tag},
+    controls::{Control, RawControl},
+    Ldap, Scope, SearchEntry,
+};
+
+pub const SORT_REQUEST_OID: &str = "1.2.840.113556.1.4.473";
+pub const SORT_RESPONSE_OID: &str = "1.2.840.113556.1.4.474";
+
+/// Operational attribute with the normalized DN of the entry (389ds), so the entries are sorted
+/// by their unique name
+pub const DN_SORT_ATTR: &str = "entrydn";
+
+/// Sort request with a single sort key (ascending, default ordering rule)
+pub struct ServerSideSort<'a> {
+    pub attr: &'a str,
+}
+
+impl From<ServerSideSort<'_>> for RawControl {
+    fn from(sort: ServerSideSort<'_>) -> RawControl {
+        let key = Tag::Sequence(Sequence {
+            inner: vec![Tag::OctetString(OctetString {
+                inner: sort.attr.as_bytes().to_vec(),
+                ..Default::default()
+            })],
+            ..Default::default()
+        });
+        let keys = Tag::Sequence(Sequence {
+            inner: vec![key],
+            ..Default::default()
+        });
+
+        let mut buf = BytesMut::with_capacity(sort.attr.len() + 8);
+        ldap3::asn1::write::encode_into(&mut buf, keys.into_structure()).expect("encoded");
+
+        RawControl {
+            ctype: SORT_REQUEST_OID.to_owned(),
+            crit: false,
+            val: Some(buf.to_vec()),
+        }
+    }
+}
+
+/// Result code of the sort response control. None if the server did not send it
+pub fn sort_result_code(ctrls: &[Control]) -> Option<u64> {
+    let raw = ctrls
+        .iter()
+        .find(|ctrl| ctrl.1.ctype == SORT_RESPONSE_OID)?
+        .1
+        .val
+        .as_ref()?;
+
+    let (_, tag) = parse_tag(raw).ok()?;
+    let code = tag.expect_constructed()?.into_iter().next()?;
+    let (_, code) = parse_uint(&code.expect_primitive()?).ok()?;
+    Some(code)
+}
+
+/// Check if the server advertises the control in the root DSE
+pub async fn supports_control(ldap: &mut Ldap, oid: &str) -> Result<bool> {
+    let (entries, _) = ldap
+        .search("", Scope::Base, "(objectClass=*)", vec!["supportedControl"])
+        .await?
+        .success()?;
+
+    let entry = SearchEntry::construct(
+        entries
+            .into_iter()
+            .next()
+            .ok_or(anyhow!("Cannot retrive root DSE"))?,
+    );
+
+    Ok(entry
+        .attrs
+        .get("supportedControl")
+        .map(|x| x.iter().any(|x| x == oid))
+        .unwrap_or_default())
+}
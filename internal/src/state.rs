@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Counter values persisted between the check runs. Used to compute increases of the cumulative
+/// counters (e.g. snmp errors) instead of the totals
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CounterState {
+    /// unix timestamp of the last save
+    pub timestamp: i64,
+    pub counters: HashMap<String, u64>,
+}
+
+/// Overrides the directory of the state files
+pub const ENV_STATE_DIR: &str = "O11Y_389DS_STATE_DIR";
+
+/// Directory of the state files, private to the user: `O11Y_389DS_STATE_DIR`,
+/// `$XDG_STATE_HOME/o11y-389ds-rs` or `~/.local/state/o11y-389ds-rs`
+pub fn default_state_dir() -> PathBuf {
+    let env_dir = |name: &str| std::env::var_os(name).filter(|x| !x.is_empty());
+
+    if let Some(dir) = env_dir(ENV_STATE_DIR) {
+        PathBuf::from(dir)
+    } else if let Some(dir) = env_dir("XDG_STATE_HOME") {
+        PathBuf::from(dir).join("o11y-389ds-rs")
+    } else if let Some(home) = env_dir("HOME") {
+        PathBuf::from(home).join(".local/state/o11y-389ds-rs")
+    } else {
+        let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+        std::env::temp_dir().join(format!("o11y-389ds-rs-{user}"))
+    }
+}
+
+/// Default state file location, unique per check and host
+pub fn default_state_path(check: &str, uri: &str) -> PathBuf {
+    let host: String = uri
+        .chars()
+        .map(|x| if x.is_ascii_alphanumeric() { x } else { '_' })
+        .collect();
+    default_state_dir().join(format!("{check}-{host}.json"))
+}
+
+impl CounterState {
+    pub fn new(counters: HashMap<String, u64>) -> Self {
+        Self {
+            timestamp: chrono::Utc::now().timestamp(),
+            counters,
+        }
+    }
+
+    /// Seconds elapsed since the state was saved
+    pub fn age(&self) -> i64 {
+        chrono::Utc::now().timestamp() - self.timestamp
+    }
+
+    /// Missing file is not an error - there is just no previous state. Same for the unparsable
+    /// one (e.g. truncated write), which is overwritten by the next `save`
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = std::fs::read(path)?;
+        match serde_json::from_slice(&content) {
+            Ok(state) => Ok(Some(state)),
+            Err(error) => {
+                tracing::warn!("Ignoring invalid state file {}: {error}", path.display());
+                Ok(None)
+            }
+        }
+    }
+
+    /// Missing parent directory is created, accessible only by the user
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            if !dir.exists() {
+                std::fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(dir)?;
+            }
+        }
+        std::fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    /// Increase of the counter since the saved state. `None` if the counter was not saved.
+    /// Counter lower than saved one means restart of the daemon, so the whole value is an increase
+    pub fn delta(&self, key: &str, current: u64) -> Option<u64> {
+        self.counters.get(key).map(|previous| {
+            if current >= *previous {
+                current - previous
+            } else {
+                current
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_state_is_overwritten() {
+        let dir = std::env::temp_dir().join(format!("o11y-389ds-rs-test-{}", std::process::id()));
+        let path = dir.join("nested").join("state.json");
+
+        assert!(CounterState::load(&path).unwrap().is_none());
+
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"{\"timestamp\": 1").unwrap();
+        assert!(CounterState::load(&path).unwrap().is_none());
+
+        let state = CounterState::new(HashMap::from([("errors".to_string(), 3)]));
+        state.save(&path).unwrap();
+        let loaded = CounterState::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.counters, state.counters);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
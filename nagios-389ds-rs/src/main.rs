@@ -1,8 +1,11 @@
-use std::collections::HashMap;
+mod range;
 
-use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use anyhow::{anyhow, Context, Result};
 use clap::{ArgGroup, Args, Parser, Subcommand};
-use internal::{Bind, LdapConfig};
+use internal::{BindMethod, LdapConfig};
+use range::Threshold;
 
 #[derive(Copy, Clone, Debug, Default)]
 pub enum ReturnCode {
@@ -28,6 +31,8 @@ impl ReturnCode {
     }
 }
 
+pub const DEFAULT_FLOAT_PRECISION: usize = 3;
+
 #[derive(Clone, Debug, Copy, Default)]
 pub enum PerfDataValue {
     Int(u64),
@@ -72,40 +77,94 @@ impl std::fmt::Display for PerfDataValue {
     }
 }
 
+impl PerfDataValue {
+    /// Same as `to_string`, but floats are rounded to the `precision` decimal places (trailing
+    /// zeros are skipped)
+    pub fn to_nagios_str(&self, precision: usize) -> String {
+        match self {
+            PerfDataValue::Float(x) => {
+                let value = format!("{x:.precision$}");
+                if value.contains('.') {
+                    value
+                        .trim_end_matches('0')
+                        .trim_end_matches('.')
+                        .to_string()
+                } else {
+                    value
+                }
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct PerfData {
     pub val: Option<PerfDataValue>,
     pub min: Option<PerfDataValue>,
     pub max: Option<PerfDataValue>,
-    pub warn: Option<PerfDataValue>,
-    pub crit: Option<PerfDataValue>,
+    pub warn: Option<Threshold>,
+    pub crit: Option<Threshold>,
     pub unit: Option<String>,
 }
 
 impl PerfData {
-    pub fn to_nagios_str(&self) -> String {
+    pub fn to_nagios_str(&self, precision: usize) -> String {
+        let value = |v: Option<PerfDataValue>| v.unwrap_or_default().to_nagios_str(precision);
+        let threshold = |v: &Option<Threshold>| {
+            v.as_ref()
+                .map(|x| x.to_nagios_str(precision))
+                .unwrap_or_default()
+        };
+
         format!(
-            "{val}{unit};{warn};{crit};{min};{max} ",
-            val = self.val.unwrap_or_default(),
+            "{val}{unit};{warn};{crit};{min};{max}",
+            val = value(self.val),
             unit = self.unit.clone().unwrap_or_default(),
-            warn = self.warn.unwrap_or_default(),
-            crit = self.crit.unwrap_or_default(),
-            min = self.min.unwrap_or_default(),
-            max = self.max.unwrap_or_default()
+            warn = threshold(&self.warn),
+            crit = threshold(&self.crit),
+            min = value(self.min),
+            max = value(self.max)
         )
     }
 }
 
+/// Label of the perfdata, quoted. `=` is not allowed, so it is dropped. Quotes are escaped by
+/// doubling them, as the plugin guidelines define
+fn perfdata_label(label: &str) -> String {
+    format!("'{}'", label.replace('=', "").replace('\'', "''"))
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Nagios {
     pub return_code: ReturnCode,
     pub description: Option<String>,
-    pub perfdata: HashMap<String, PerfData>,
+    /// Detail lines (e.g. every failing item) printed after the description line
+    pub long_output: Vec<String>,
+    /// Sorted by name, so the perfdata order is stable between runs
+    pub perfdata: BTreeMap<String, PerfData>,
+    /// Decimal places of the float perfdata. Defaults to `DEFAULT_FLOAT_PRECISION`
+    pub precision: Option<usize>,
+    /// Skip the perfdata section (after `|`) of the output
+    pub hide_perfdata: bool,
+    /// Append perfdata values to the description, for frontends that do not parse perfdata
+    pub perfdata_in_description: bool,
+    /// Report `Unknown` (e.g. connection or config errors) as `Critical`
+    pub unknown_as_critical: bool,
 }
 
 impl Nagios {
-    pub fn exit_with_message(&self) {
-        let desc = match self.return_code {
+    /// Return code reported to the monitoring system (see `unknown_as_critical`)
+    fn reported_code(&self) -> ReturnCode {
+        match self.return_code {
+            ReturnCode::Unknown if self.unknown_as_critical => ReturnCode::Critical,
+            return_code => return_code,
+        }
+    }
+
+    /// Plugin output: status and description, long output lines and the perfdata
+    fn message(&self) -> String {
+        let desc = match self.reported_code() {
             ReturnCode::Ok => "OK",
             ReturnCode::Warning => "WARN",
             ReturnCode::Critical => "CRIT",
@@ -113,73 +172,177 @@ impl Nagios {
         }
         .to_string();
 
-        let perf_data = self.perfdata.iter().fold(String::new(), |acc, (k, v)| {
-            let k = k.replace("'", "").replace("=", "");
-            format!("{acc}'{k}'={}", v.to_nagios_str())
-        });
+        let precision = self.precision.unwrap_or(DEFAULT_FLOAT_PRECISION);
+        let mut description = self.description.clone().unwrap_or_default();
 
-        let desc = format!(
-            "{}: {} | {}",
-            desc,
-            self.description.as_ref().unwrap_or(&String::new()),
-            perf_data
-        );
+        if self.perfdata_in_description && !self.perfdata.is_empty() {
+            let values = self
+                .perfdata
+                .iter()
+                .map(|(k, v)| {
+                    format!(
+                        "{k}={}{}",
+                        v.val.unwrap_or_default().to_nagios_str(precision),
+                        v.unit.clone().unwrap_or_default()
+                    )
+                })
+                .collect::<Vec<_>>();
+            description = format!("{description} ({})", values.join(", "));
+        }
+
+        let mut desc = format!("{desc}: {description}");
+        for line in &self.long_output {
+            desc = format!("{desc}\n{line}");
+        }
+
+        // Perfdata goes after the long output, which is allowed by the plugin guidelines
+        if !self.hide_perfdata && !self.perfdata.is_empty() {
+            let perf_data = self
+                .perfdata
+                .iter()
+                .map(|(k, v)| format!("{}={}", perfdata_label(k), v.to_nagios_str(precision)))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            desc = format!("{desc} | {perf_data}");
+        }
+
+        desc
+    }
 
-        println!("{desc}");
-        std::process::exit(self.return_code as i32);
+    pub fn exit_with_message(&self) -> ! {
+        println!("{}", self.message());
+        std::process::exit(self.reported_code() as i32);
     }
 }
 
 #[derive(Args, Clone, Debug)]
 pub struct Diskspace {
     #[arg(short, long)]
-    pub warn_percent_used: Option<f64>,
+    pub warn_percent_used: Option<Threshold>,
 
     #[arg(short, long)]
-    pub crit_percent_used: Option<f64>,
+    pub crit_percent_used: Option<Threshold>,
 
     #[arg(short = 'W', long)]
-    pub warn_absolute_available: Option<u64>,
+    pub warn_absolute_available: Option<Threshold>,
 
     #[arg(short = 'C', long)]
-    pub crit_absolute_available: Option<u64>,
+    pub crit_absolute_available: Option<Threshold>,
 
     #[arg(short, long)]
     pub partitions: Vec<String>,
 }
 
+#[derive(Args, Clone, Debug)]
+pub struct DbCacheHitRatio {
+    /// Warn if the hit ratio (percentage) is less than or equal to
+    #[arg(short, long)]
+    pub warn: Option<Threshold>,
+
+    /// Crit if the hit ratio (percentage) is less than or equal to
+    #[arg(short, long)]
+    pub crit: Option<Threshold>,
+}
+
+#[derive(Args, Clone, Debug)]
+#[clap(group = ArgGroup::new("expected").required(true).multiple(true))]
+pub struct LoggingConfig {
+    /// Expected nsslapd-accesslog-logging-enabled (on/off)
+    #[clap(group = "expected")]
+    #[arg(short, long)]
+    pub access_logging: Option<String>,
+
+    /// Expected nsslapd-accesslog-level
+    #[clap(group = "expected")]
+    #[arg(short = 'A', long)]
+    pub access_level: Option<String>,
+
+    /// Expected nsslapd-errorlog-level
+    #[clap(group = "expected")]
+    #[arg(short, long)]
+    pub error_level: Option<String>,
+
+    /// Expected nsslapd-auditlog-logging-enabled (on/off)
+    #[clap(group = "expected")]
+    #[arg(short = 'u', long)]
+    pub audit_logging: Option<String>,
+
+    /// Expected nsslapd-auditfaillog-logging-enabled (on/off)
+    #[clap(group = "expected")]
+    #[arg(short = 'f', long)]
+    pub auditfail_logging: Option<String>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct WriteProbe {
+    /// Dedicated DN of the probe entry. WARNING: the entry is added, modified and deleted
+    #[arg(short, long)]
+    pub dn: String,
+
+    /// Warn if the total duration (ms) is greater than or equal to
+    #[arg(short, long)]
+    pub warn: Option<Threshold>,
+
+    /// Crit if the total duration (ms) is greater than or equal to
+    #[arg(short, long)]
+    pub crit: Option<Threshold>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct BackendCount {
+    /// Expected number of the backends. Crit if the number differs
+    #[arg(short, long)]
+    pub expected: u64,
+}
+
 #[derive(Args, Clone, Debug)]
 pub struct RecentRestart {
     #[arg(short, long)]
-    pub warn_if_less_than: Option<u64>,
+    pub warn_if_less_than: Option<Threshold>,
 }
 
 #[derive(Args, Clone, Debug)]
 pub struct Errors {
     #[arg(short = 'W', long)]
-    pub warn_sum: Option<u64>,
+    pub warn_sum: Option<Threshold>,
 
     #[arg(short = 'C', long)]
-    pub crit_sum: Option<u64>,
+    pub crit_sum: Option<Threshold>,
 
     #[arg(short, long)]
-    pub warn: Option<u64>,
+    pub warn: Option<Threshold>,
 
     #[arg(short, long)]
-    pub crit: Option<u64>,
+    pub crit: Option<Threshold>,
 
     /// Names of the error keys to include
     #[arg(short, long)]
     pub names: Vec<String>,
 }
 
+#[derive(Args, Clone, Debug)]
+pub struct BindFailures {
+    /// Warn if increase of the security errors since the previous run is greater than or equal to
+    #[arg(short, long)]
+    pub warn: Option<Threshold>,
+
+    #[arg(short, long)]
+    pub crit: Option<Threshold>,
+
+    /// File keeping counters between the runs. Defaults to the file in the user state dir
+    /// (`O11Y_389DS_STATE_DIR`, `$XDG_STATE_HOME/o11y-389ds-rs`), unique per host
+    #[arg(short, long)]
+    pub state_file: Option<std::path::PathBuf>,
+}
+
 #[derive(Args, Clone, Debug)]
 pub struct Connections {
     #[arg(short, long)]
-    pub warn: Option<u64>,
+    pub warn: Option<Threshold>,
 
     #[arg(short, long)]
-    pub crit: Option<u64>,
+    pub crit: Option<Threshold>,
 
     /// DNs of the connections to include
     #[arg(short, long)]
@@ -197,6 +360,20 @@ pub struct Connections {
     #[arg(short = 'I', long)]
     pub exclude_ip: Vec<String>,
 
+    /// Warn if a single DN holds at least this many (filtered) connections
+    #[arg(long)]
+    pub warn_per_dn: Option<Threshold>,
+
+    #[arg(long)]
+    pub crit_per_dn: Option<Threshold>,
+
+    /// Warn if a single IP address holds at least this many (filtered) connections
+    #[arg(long)]
+    pub warn_per_ip: Option<Threshold>,
+
+    #[arg(long)]
+    pub crit_per_ip: Option<Threshold>,
+
     /// By default check include integrity validation between snmp, monitor and counted connections
     /// numbers. This can be skipped by setting this flag
     #[arg(short, long, default_value_t = false)]
@@ -208,19 +385,59 @@ pub struct Connections {
     pub debug: bool,
 }
 
+#[derive(Args, Clone, Debug)]
+pub struct PrivilegedBinds {
+    /// Warn if number of the connections bound as privileged DNs is greater than or equal to
+    #[arg(short, long, default_value = "1")]
+    pub warn: Threshold,
+
+    #[arg(short, long)]
+    pub crit: Option<Threshold>,
+
+    /// Privileged DNs. Defaults to cn=directory manager
+    #[arg(short, long)]
+    pub dn: Vec<String>,
+}
+
 #[derive(Args, Clone, Debug)]
 pub struct MissingGids {
     #[arg(short, long)]
-    pub warn_groups: Option<u64>,
+    pub warn_groups: Option<Threshold>,
 
     #[arg(short, long)]
-    pub crit_groups: Option<u64>,
+    pub crit_groups: Option<Threshold>,
 
     #[arg(short = 'W', long)]
-    pub warn_users: Option<u64>,
+    pub warn_users: Option<Threshold>,
 
     #[arg(short = 'C', long)]
-    pub crit_users: Option<u64>,
+    pub crit_users: Option<Threshold>,
+
+    /// Search accounts and groups concurrently on two connections
+    #[arg(short = 'p', long, default_value_t = false)]
+    pub concurrent_connections: bool,
+
+    /// Report up to this number of uids (accounts without the group) in the description
+    #[arg(short, long, default_value_t = 0)]
+    pub list_uids: usize,
+    /// Filter of the accounts
+    #[arg(short, long, default_value = internal::gids::DEFAULT_ACCOUNTS_FILTER)]
+    pub accounts_filter: String,
+
+    /// Filter of the groups
+    #[arg(short = 'g', long, default_value = internal::gids::DEFAULT_GROUPS_FILTER)]
+    pub groups_filter: String,
+    /// Primary gids below this value are counted as low number (system range)
+    #[arg(long, default_value_t = internal::gids::DEFAULT_LOW_GID_THRESHOLD)]
+    pub low_gid_threshold: i64,
+
+    /// Warn if the number of accounts with low number primary gid is greater or equal
+    #[arg(long)]
+    pub warn_low_number: Option<Threshold>,
+
+    /// Crit if the number of accounts with low number primary gid is greater or equal
+    #[arg(long)]
+    pub crit_low_number: Option<Threshold>,
 }
 
 #[derive(Args, Clone, Debug)]
@@ -245,35 +462,143 @@ pub struct CheckIntMetric {
     pub metric: Option<String>,
 
     #[arg(short, long)]
-    pub warn: Option<u64>,
+    pub warn: Option<Threshold>,
+
+    #[arg(short, long)]
+    pub crit: Option<Threshold>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct AgreementHosts {
+    /// Expected agreement target hosts (nsDS5ReplicaHost). Crit if any is missing or unexpected
+    #[arg(short, long, required = true)]
+    pub expected: Vec<String>,
+
+    /// Check only agreements of this suffix (nsDS5ReplicaRoot)
+    #[arg(short, long)]
+    pub root: Option<String>,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct ReplicationConflicts {
+    #[arg(short, long)]
+    pub warn: Option<Threshold>,
 
     #[arg(short, long)]
-    pub crit: Option<u64>,
+    pub crit: Option<Threshold>,
+
+    /// Number of the conflict entry DNs reported in the description
+    #[arg(short, long, default_value_t = 5)]
+    pub list: usize,
+}
+
+/// Agreements to check. All of them by default
+#[derive(Args, Clone, Debug)]
+pub struct AgreementSelection {
+    /// Check only agreements of this suffix (nsDS5ReplicaRoot)
+    #[arg(long)]
+    pub root: Option<String>,
+
+    /// Check only agreements with this cn. `*` matches any characters, e.g. `to-ldap*`
+    #[arg(long)]
+    pub agreement: Option<String>,
+}
+
+impl AgreementSelection {
+    fn filter(&self) -> internal::replica::AgreementFilter {
+        internal::replica::AgreementFilter {
+            root: self.root.clone(),
+            cn: self.agreement.clone(),
+        }
+    }
 }
 
 #[derive(Args, Clone, Debug)]
 pub struct AgreementDuration {
     #[arg(short, long)]
-    pub warn: Option<u64>,
+    pub warn: Option<Threshold>,
 
     #[arg(short, long)]
-    pub crit: Option<u64>,
+    pub crit: Option<Threshold>,
+
+    #[command(flatten)]
+    pub selection: AgreementSelection,
 }
 
 #[derive(Args, Clone, Debug)]
 pub struct AgreementSkipped {
     #[arg(short, long)]
-    pub warn: Option<u64>,
+    pub warn: Option<Threshold>,
 
     #[arg(short, long)]
-    pub crit: Option<u64>,
+    pub crit: Option<Threshold>,
+
+    #[command(flatten)]
+    pub selection: AgreementSelection,
 }
 
 #[derive(Args, Clone, Debug)]
 pub struct AgreementStatus {
+    #[command(flatten)]
+    pub selection: AgreementSelection,
+
     /// By default RUV is also checked. Set this to true to skip this check
     #[arg(short = 'R', long, default_value_t = false)]
     pub no_ruv: bool,
+
+    /// Disabled (paused) agreements are skipped by default. Set this to warn about them
+    #[arg(short = 'W', long, default_value_t = false)]
+    pub warn_disabled: bool,
+
+    /// Agreements in transient state (e.g. busy replica) are scraped again this many times before
+    /// reporting them
+    #[arg(short, long, default_value_t = 1)]
+    pub retries: u32,
+
+    /// Seconds between the retries
+    #[arg(short = 'd', long, default_value_t = 5)]
+    pub retry_delay: u64,
+
+    /// Unhealthy agreements matching these states (e.g. amber), replication codes (e.g. 1) or
+    /// status texts (e.g. busy) are reported as warning instead of critical
+    #[arg(short = 'w', long)]
+    pub warn_state: Vec<String>,
+
+    /// Report agreements whose last total update (online init) is not green
+    #[arg(short = 'i', long, default_value_t = false)]
+    pub check_init: bool,
+
+    /// Warn if the RUV of an agreement is missing a replica id seen in other agreements of the same
+    /// suffix
+    #[arg(short = 'm', long, default_value_t = false)]
+    pub warn_missing_replica: bool,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct IndexesPendingReindex {
+    #[arg(short = 'T', long)]
+    pub timeout: Option<u64>,
+
+    #[arg(short, long, default_value=internal::cli::DEFAULT_INSTANCE)]
+    pub instance: String,
+
+    /// Command prepended to dsctl, split on whitespace (default: sudo). Empty runs dsctl directly
+    #[arg(long)]
+    pub command_prefix: Option<String>,
+
+    /// dsctl binary (default: dsctl from $PATH)
+    #[arg(long)]
+    pub dsctl_path: Option<String>,
+
+    /// Extra dsctl arg, passed before `--json <instance>`. Can be repeated
+    #[arg(long = "dsctl-arg", allow_hyphen_values = true)]
+    pub dsctl_args: Vec<String>,
+
+    #[arg(short, long)]
+    pub warn: Option<Threshold>,
+
+    #[arg(short, long)]
+    pub crit: Option<Threshold>,
 }
 
 #[derive(Args, Clone, Debug)]
@@ -282,40 +607,56 @@ pub struct CliHealthcheck {
     #[arg(short = 'T', long)]
     pub timeout: Option<u64>,
 
+    /// Run also `logs*` healthchecks (skipped by default, as the logs can be huge)
+    #[arg(long, default_value_t = false)]
+    pub include_log_checks: bool,
+
+    /// Command prepended to dsctl, split on whitespace (default: sudo). Empty runs dsctl directly
+    #[arg(long)]
+    pub command_prefix: Option<String>,
+
+    /// dsctl binary (default: dsctl from $PATH)
+    #[arg(long)]
+    pub dsctl_path: Option<String>,
+
+    /// Extra dsctl arg, passed before `--json <instance>`. Can be repeated
+    #[arg(long = "dsctl-arg", allow_hyphen_values = true)]
+    pub dsctl_args: Vec<String>,
+
     #[arg(short, long, default_value=internal::cli::DEFAULT_INSTANCE)]
     pub instance: String,
 
     /// Overall errors warn
     #[arg(short, long)]
-    pub warn: Option<u64>,
+    pub warn: Option<Threshold>,
 
     /// Overall errors crit
     #[arg(short, long)]
-    pub crit: Option<u64>,
+    pub crit: Option<Threshold>,
 
     /// LOW severity errors warn
     #[arg(short = 'l', long)]
-    pub warn_low: Option<u64>,
+    pub warn_low: Option<Threshold>,
 
     /// LOW severity errors crit
     #[arg(short = 'L', long)]
-    pub crit_low: Option<u64>,
+    pub crit_low: Option<Threshold>,
 
     /// MEDIUM severity errors warn
     #[arg(short = 'm', long)]
-    pub warn_medium: Option<u64>,
+    pub warn_medium: Option<Threshold>,
 
     /// MEDIUM severity errors crit
     #[arg(short = 'M', long)]
-    pub crit_medium: Option<u64>,
+    pub crit_medium: Option<Threshold>,
 
     /// HIGH severity errors warn
     #[arg(short = 'h', long)]
-    pub warn_high: Option<u64>,
+    pub warn_high: Option<Threshold>,
 
     /// HIGH severity errors crit
     #[arg(short = 'H', long)]
-    pub crit_high: Option<u64>,
+    pub crit_high: Option<Threshold>,
 
     #[clap(short='?', long, action = clap::ArgAction::Help, help = "Print help information")]
     help: Option<bool>,
@@ -332,17 +673,29 @@ pub struct CustomQueryTime {
     pub max_entries: Option<i32>,
 
     #[arg(short = 'w', long)]
-    pub warn: Option<u64>,
+    pub warn: Option<Threshold>,
 
     #[arg(short = 'c', long)]
-    pub crit: Option<u64>,
+    pub crit: Option<Threshold>,
+
+    /// Attributes to get
+    #[arg(short = 'a', long)]
+    pub attributes: Vec<String>,
+
+    /// Print the matched entries to stderr. Does not affect the check result
+    #[arg(short = 's', long, default_value_t = false)]
+    pub show_entries: bool,
+
+    /// Maximum number of the entries printed by --show-entries
+    #[arg(short = 'l', long, default_value_t = 20)]
+    pub show_entries_limit: usize,
 }
 
 #[derive(Args, Clone, Debug)]
 pub struct CustomQueryIntegrity {
-    /// An additional host to check against
+    /// Additional hosts to check against the primary one. Can be repeated
     #[arg(short = 'H', long = "host", required = true)]
-    pub host: String,
+    pub host: Vec<String>,
 
     /// LDAP filter (query)
     #[arg(short = 'f', long, required = true)]
@@ -371,6 +724,16 @@ pub struct CustomQueryIntegrity {
     /// Check integrity using number of ldap bytes in the returned attributes values
     #[arg(short = 'B', long, default_value_t = false)]
     pub bytes_size_integrity: bool,
+
+    /// Request the entries sorted by the server (entrydn), so they are hashed while streamed
+    /// instead of being sorted by the client. Both hosts must support it to get comparable
+    /// checksums
+    #[arg(short = 's', long, default_value_t = false)]
+    pub server_sort: bool,
+
+    /// Ignore case and whitespace differences of the DNs when ordering entries for the checksum
+    #[arg(short = 'n', long, default_value_t = false)]
+    pub normalize_dn: bool,
 }
 
 #[derive(Subcommand, Clone, Debug)]
@@ -383,25 +746,47 @@ pub enum CheckVariant {
     AgreementSkipped(AgreementSkipped),
     /// Check duration of the replication
     AgreementDuration(AgreementDuration),
+    /// Check if agreement target hosts match the expected ones
+    AgreementHosts(AgreementHosts),
+    /// Check number of replication conflict entries (nsds5ReplConflict) under the default base
+    ReplicationConflicts(ReplicationConflicts),
     /// Check if there are primary gids that are not present as posixGroup
     MissingGids(MissingGids),
     /// Check number of active connections
     Connections(Connections),
+    /// Check if any connection is bound as privileged DN (e.g. directory manager)
+    PrivilegedBinds(PrivilegedBinds),
     /// Check cumber of errors: Errors + SecurityErrors + BindSecurityErrors
     Errors(Errors),
+    /// Check increase of BindSecurityErrors + SecurityErrors since the previous run
+    BindFailures(BindFailures),
     /// Check if daemon has been recently restarted
     RecentRestart(RecentRestart),
     /// Check if disk space is low (declared by the daemon)
     Diskspace(Diskspace),
+    /// Check database cache hit ratio (BDB only). Requires privileged bind
+    DbCacheHitRatio(DbCacheHitRatio),
+    /// Check number of the configured backends. Requires privileged bind
+    BackendCount(BackendCount),
+    /// Check the write path by adding, modifying and deleting a dedicated entry
+    WriteProbe(WriteProbe),
+    /// Check if logging settings (cn=config) match the expected ones. Requires privileged bind
+    LoggingConfig(LoggingConfig),
     /// Check health using dsctl cli
     CliHealthcheck(CliHealthcheck),
+    /// Check number of indexes needing reindex (reported by dsctl healthcheck)
+    IndexesPendingReindex(IndexesPendingReindex),
     /// Check custom query times (config against specified host)
     CustomQueryTime(CustomQueryTime),
     /// Check custom query integrity (config against specified host)
     CustomQueryIntegrity(CustomQueryIntegrity),
+    /// Validate the config file and options, print the problems and exit without connecting.
+    /// Exit code is 1 if there are any problems
+    CheckConfig,
 }
 
 /// Perform nagios checks on the 389ds. All limits are using >= or <= comparsions, unless stated otherwise.
+/// Limits also accept the nagios range syntax (e.g. 10:20, @10:20, ~:10)
 /// NOTE: Most checks require config (at least host section)
 #[derive(Parser, Clone, Debug)]
 #[clap(group(ArgGroup::new("bind").requires_all(["binddn", "bindpass"]).multiple(true)))]
@@ -416,6 +801,10 @@ pub struct Cli {
     #[clap(short = 'C', long, default_value_t = false)]
     skip_cert_verification: bool,
 
+    /// Upgrade ldap:// connection with StartTLS before bind
+    #[clap(long, default_value_t = false)]
+    start_tls: bool,
+
     #[clap(short = 'H', long)]
     host: Option<String>,
 
@@ -432,6 +821,88 @@ pub struct Cli {
 
     #[clap(short = 'P', long)]
     page_size: Option<i32>,
+
+    /// Number of decimal places of the float perfdata values
+    #[clap(long, default_value_t = DEFAULT_FLOAT_PRECISION)]
+    precision: usize,
+
+    /// Do not print the perfdata section
+    #[clap(long, default_value_t = false)]
+    no_perfdata: bool,
+
+    /// Append perfdata values to the description
+    #[clap(long, default_value_t = false)]
+    perfdata_in_description: bool,
+
+    /// Give up the whole check (connecting included) after this many seconds and report UNKNOWN
+    #[clap(short = 't', long)]
+    timeout: Option<u64>,
+
+    /// Report UNKNOWN results (e.g. connection, config errors or timeouts) as CRITICAL
+    #[clap(long, default_value_t = false)]
+    unknown_as_critical: bool,
+}
+
+/// Hex characters of the sha256 checksum reported by `custom-query-integrity`
+const CHECKSUM_FINGERPRINT_LENGTH: usize = 12;
+
+/// Kinds of the `check-int-metric` sources. Disk and backend sources are named `disk:<partition>`
+/// and `backend:<backend>`
+const METRIC_SOURCE_KINDS: &[&str] = &["monitor", "snmp", "database", "disk", "backend"];
+
+/// Scrape integer metrics of the sources of the given kind: source -> metric -> value. Backend
+/// metrics (e.g. cache hit ratios) are truncated and require privileged bind
+async fn scrape_metric_sources(
+    ldap: &mut ldap3::Ldap,
+    kind: &str,
+) -> Result<BTreeMap<String, HashMap<String, i64>>> {
+    use internal::monitor::{
+        LdapBackendMonitor, LdapBackends, LdapDatabase, LdapDisk, LdapMonitor, LdapSNMP,
+    };
+
+    let to_i64 = |metrics: HashMap<String, u64>| {
+        metrics
+            .into_iter()
+            .map(|(k, v)| (k, v as i64))
+            .collect::<HashMap<_, _>>()
+    };
+
+    let sources = match kind {
+        "monitor" => BTreeMap::from([(
+            kind.to_string(),
+            to_i64(LdapMonitor::scrape(ldap).await?.int_metrics),
+        )]),
+        "snmp" => BTreeMap::from([(
+            kind.to_string(),
+            to_i64(LdapSNMP::scrape(ldap).await?.int_metrics),
+        )]),
+        "database" => BTreeMap::from([(
+            kind.to_string(),
+            to_i64(LdapDatabase::scrape(ldap).await?.int_metrics),
+        )]),
+        "disk" => LdapDisk::scrape(ldap)
+            .await?
+            .partitions
+            .into_iter()
+            .map(|(partition, x)| (format!("disk:{partition}"), to_i64(x.int_metrics)))
+            .collect(),
+        "backend" => {
+            let mut sources = BTreeMap::new();
+            for backend in LdapBackends::scrape(ldap).await?.backends.into_keys() {
+                let metrics = LdapBackendMonitor::scrape(ldap, &backend)
+                    .await?
+                    .metrics
+                    .into_iter()
+                    .map(|(k, v)| (k, v as i64))
+                    .collect();
+                sources.insert(format!("backend:{backend}"), metrics);
+            }
+            sources
+        }
+        _ => return Err(anyhow!("Unknown metric source kind {kind}")),
+    };
+
+    Ok(sources)
 }
 
 pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios) -> Result<()> {
@@ -439,41 +910,40 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
 
     match &args.subcommand {
         CheckVariant::CheckIntMetric(config) => {
-            let monitor = internal::monitor::LdapMonitor::scrape(&mut ldap)
-                .await?
-                .int_metrics;
-            let snmp = internal::monitor::LdapSNMP::scrape(&mut ldap)
-                .await?
-                .int_metrics;
-
-            let map: HashMap<String, HashMap<String, i64>> = HashMap::from([
-                (
-                    "monitor".to_string(),
-                    monitor.into_iter().map(|(k, v)| (k, v as i64)).collect(),
-                ),
-                (
-                    "snmp".to_string(),
-                    snmp.into_iter().map(|(k, v)| (k, v as i64)).collect(),
-                ),
-            ]);
-
             if config.debug {
+                let mut map = BTreeMap::new();
+                for kind in METRIC_SOURCE_KINDS {
+                    match scrape_metric_sources(&mut ldap, kind).await {
+                        Ok(sources) => map.extend(sources),
+                        Err(error) => eprintln!("Unable to scrape {kind} metrics: {error}"),
+                    }
+                }
                 println!("{map:#?}");
             } else {
                 let metric_source = config
                     .metric_source
                     .clone()
                     .ok_or(anyhow!("Missing metric source"))?;
-                let metric = config
-                    .metric
-                    .clone()
-                    .ok_or(anyhow!("Missing metric source"))?;
+                let metric = config.metric.clone().ok_or(anyhow!("Missing metric"))?;
+
+                let kind = metric_source
+                    .split_once(':')
+                    .map_or(metric_source.as_str(), |(kind, _)| kind);
+                if !METRIC_SOURCE_KINDS.contains(&kind) {
+                    return Err(anyhow!(
+                        "No such a metric source {metric_source}. Available: monitor, snmp, database, disk:<partition>, backend:<backend>"
+                    ));
+                }
 
+                let map = scrape_metric_sources(&mut ldap, kind).await?;
                 let metric_val = map
                     .get(&metric_source)
-                    .ok_or(anyhow!("No such a metric source"))?
+                    .ok_or(anyhow!(
+                        "No such a metric source {metric_source}. Available: {}",
+                        map.keys().cloned().collect::<Vec<_>>().join(", ")
+                    ))?
                     .get(&metric)
-                    .ok_or(anyhow!("No such a metric"))?;
+                    .ok_or(anyhow!("No such a metric {metric} in {metric_source}"))?;
 
                 let unit = match &metric {
                     x if x.contains("bytes") => Some("B".to_string()),
@@ -481,57 +951,138 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                 };
 
                 result.description = Some(format!("{}_{}", &metric_source, &metric));
-                result.perfdata = HashMap::from([(
+                result.perfdata = BTreeMap::from([(
                     String::from("value"),
                     PerfData {
                         val: PDV(*metric_val as f64),
-                        warn: config.warn.map(PDV).unwrap_or_default(),
-                        crit: config.crit.map(PDV).unwrap_or_default(),
+                        warn: config.warn.clone(),
+                        crit: config.crit.clone(),
                         unit,
                         ..Default::default()
                     },
                 )]);
 
-                if let Some(warn) = config.warn {
-                    if (config.revert_comparsion && *metric_val <= warn as i64)
-                        || (!config.revert_comparsion && *metric_val >= warn as i64)
-                    {
-                        result.return_code.warn();
+                let alerts = |threshold: &Threshold| {
+                    if config.revert_comparsion {
+                        threshold.alerts_below(*metric_val as f64)
+                    } else {
+                        threshold.alerts(*metric_val as f64)
                     }
+                };
+
+                if config.warn.as_ref().is_some_and(alerts) {
+                    result.return_code.warn();
                 }
 
-                if let Some(crit) = config.crit {
-                    if (config.revert_comparsion && *metric_val <= crit as i64)
-                        || (!config.revert_comparsion && *metric_val >= crit as i64)
-                    {
-                        result.return_code.crit();
-                    }
+                if config.crit.as_ref().is_some_and(alerts) {
+                    result.return_code.crit();
                 }
             }
         }
         CheckVariant::AgreementStatus(config) => {
-            result.description = Some("agreement status".to_string());
+            let mut problems = Vec::new();
+
+            let filter = config.selection.filter();
+            let mut agreements =
+                internal::replica::Agreement::scrape_filtered(&mut ldap, &filter).await?;
+            for _ in 0..config.retries {
+                if !agreements
+                    .iter()
+                    .any(|x| x.enabled && x.status.is_transient())
+                {
+                    break;
+                }
+                tokio::time::sleep(tokio::time::Duration::from_secs(config.retry_delay)).await;
+                agreements =
+                    internal::replica::Agreement::scrape_filtered(&mut ldap, &filter).await?;
+            }
 
-            for agreement in internal::replica::Agreement::scrape(&mut ldap).await? {
-                let status = agreement.status;
+            let known_replica_ids = internal::replica::Agreement::known_replica_ids(&agreements);
 
-                if status.ldap_rc != 0 {
-                    result.return_code.crit();
+            for agreement in agreements {
+                if config.warn_missing_replica {
+                    let present = agreement.replica_ids();
+                    let missing: Vec<String> = known_replica_ids
+                        .get(&agreement.root)
+                        .into_iter()
+                        .flatten()
+                        .filter(|x| !present.contains(x))
+                        .map(|x| x.to_string())
+                        .collect();
+
+                    if !missing.is_empty() {
+                        result.return_code.warn();
+                        problems.push(format!(
+                            "agreement {} RUV is missing replica ids: {}",
+                            agreement.cn,
+                            missing.join(", ")
+                        ));
+                    }
                 }
 
-                if status.state != "green" {
-                    result.return_code.crit();
+                if !agreement.enabled {
+                    result.perfdata.insert(
+                        format!("{} disabled", agreement.cn),
+                        PerfData {
+                            val: PDV(1_u64),
+                            min: PDV(0_u64),
+                            ..Default::default()
+                        },
+                    );
+
+                    if config.warn_disabled {
+                        result.return_code.warn();
+                        problems.push(format!("agreement {} is disabled", agreement.cn));
+                    }
+                    continue;
                 }
 
-                if status.repl_rc != 0 {
-                    result.return_code.crit();
+                let status = agreement.status;
+
+                if !status.is_healthy() {
+                    if config.warn_state.iter().any(|x| status.matches(x)) {
+                        result.return_code.warn();
+                    } else {
+                        result.return_code.crit();
+                    }
+
+                    problems.push(format!(
+                        "agreement {} is {}: {}",
+                        agreement.cn,
+                        status.state,
+                        status.reason()
+                    ));
+                }
+
+                if config.check_init {
+                    if let Some(init_status) = &agreement.init_status {
+                        if !init_status.is_healthy() {
+                            result.return_code.crit();
+                            problems.push(format!(
+                                "agreement {} init is {}: {}",
+                                agreement.cn,
+                                init_status.state,
+                                init_status.reason()
+                            ));
+                        }
+
+                        result.perfdata.insert(
+                            format!("{} init", agreement.cn),
+                            PerfData {
+                                val: PDV(!init_status.is_healthy() as u64),
+                                crit: Some(Threshold::Limit(1.0)),
+                                min: PDV(0_u64),
+                                ..Default::default()
+                            },
+                        );
+                    }
                 }
 
                 result.perfdata.insert(
                     agreement.cn.clone(),
                     PerfData {
-                        val: PDV(0_u64),
-                        crit: PDV(1_u64),
+                        val: PDV(!status.is_healthy() as u64),
+                        crit: Some(Threshold::Limit(1.0)),
                         min: PDV(0_u64),
                         ..Default::default()
                     },
@@ -551,13 +1102,17 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                                     ),
                                     PerfData {
                                         val: PDV(1_u64),
-                                        crit: PDV(1_u64),
+                                        crit: Some(Threshold::Limit(1.0)),
                                         min: PDV(0_u64),
                                         ..Default::default()
                                     },
                                 );
 
                                 result.return_code.crit();
+                                problems.push(format!(
+                                    "agreement {} has broken RUV: server({}) replica({})",
+                                    &agreement.cn, server, replica_id
+                                ));
                             }
                             Ruv::Info {
                                 replica_id, server, ..
@@ -569,7 +1124,7 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                                     ),
                                     PerfData {
                                         val: PDV(0_u64),
-                                        crit: PDV(1_u64),
+                                        crit: Some(Threshold::Limit(1.0)),
                                         min: PDV(0_u64),
                                         ..Default::default()
                                     },
@@ -579,31 +1134,41 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                     }
                 }
             }
+
+            result.description = Some(if problems.is_empty() {
+                "all agreements are healthy".to_string()
+            } else {
+                format!("{} agreement problems found", problems.len())
+            });
+            result.long_output = problems;
         }
         CheckVariant::AgreementSkipped(config) => {
             result.description = Some("agreement objects skipped".to_string());
-            for agreement in internal::replica::Agreement::scrape(&mut ldap).await? {
+            for agreement in
+                internal::replica::Agreement::scrape_filtered(&mut ldap, &config.selection.filter())
+                    .await?
+            {
                 for changes_sent in agreement.changes_sent {
                     result.perfdata.insert(
                         format!("{} replica_{}", agreement.cn, changes_sent.replica_id),
                         PerfData {
                             val: PDV(changes_sent.changes_skipped),
-                            warn: config.warn.map(PDV).unwrap_or_default(),
-                            crit: config.crit.map(PDV).unwrap_or_default(),
+                            warn: config.warn.clone(),
+                            crit: config.crit.clone(),
                             unit: None,
                             min: PDV(0_u64),
                             ..Default::default()
                         },
                     );
 
-                    if let Some(warn) = config.warn {
-                        if changes_sent.changes_skipped >= warn {
+                    if let Some(warn) = &config.warn {
+                        if warn.alerts(changes_sent.changes_skipped as f64) {
                             result.return_code.warn();
                         }
                     }
 
-                    if let Some(crit) = config.crit {
-                        if changes_sent.changes_skipped >= crit {
+                    if let Some(crit) = &config.crit {
+                        if crit.alerts(changes_sent.changes_skipped as f64) {
                             result.return_code.crit();
                         }
                     }
@@ -612,68 +1177,212 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
         }
         CheckVariant::AgreementDuration(config) => {
             result.description = Some("agreements duration (seconds)".to_string());
-            for agreement in internal::replica::Agreement::scrape(&mut ldap).await? {
+            for agreement in
+                internal::replica::Agreement::scrape_filtered(&mut ldap, &config.selection.filter())
+                    .await?
+            {
+                // Agreement never ran or the update is in progress
+                let Some(duration) = agreement.last_update_duration_seconds else {
+                    continue;
+                };
+                let duration = duration as u64;
+
                 result.perfdata.insert(
                     agreement.cn,
                     PerfData {
-                        val: PDV(agreement.last_update_duration_seconds as u64),
-                        warn: config.warn.map(PDV).unwrap_or_default(),
-                        crit: config.crit.map(PDV).unwrap_or_default(),
+                        val: PDV(duration),
+                        warn: config.warn.clone(),
+                        crit: config.crit.clone(),
                         unit: Some("s".to_string()),
                         min: PDV(0_u64),
                         ..Default::default()
                     },
                 );
 
-                if let Some(warn) = config.warn {
-                    if agreement.last_update_duration_seconds as u64 >= warn {
+                if let Some(warn) = &config.warn {
+                    if warn.alerts(duration as f64) {
                         result.return_code.warn();
                     }
                 }
 
-                if let Some(crit) = config.crit {
-                    if agreement.last_update_duration_seconds as u64 >= crit {
+                if let Some(crit) = &config.crit {
+                    if crit.alerts(duration as f64) {
                         result.return_code.crit();
                     }
                 }
             }
         }
-        CheckVariant::MissingGids(mg_config) => {
-            let gids = internal::gids::missing_gids_to_uid_mapping(&config).await?;
-            let config = mg_config;
+        CheckVariant::AgreementHosts(config) => {
+            let filter = internal::replica::AgreementFilter {
+                root: config.root.clone(),
+                cn: None,
+            };
+            let actual: BTreeSet<String> =
+                internal::replica::Agreement::scrape_filtered(&mut ldap, &filter)
+                    .await?
+                    .into_iter()
+                    .map(|x| x.host.to_lowercase())
+                    .collect();
+            let expected: BTreeSet<String> =
+                config.expected.iter().map(|x| x.to_lowercase()).collect();
 
-            result.description = Some("Missing gids".to_string());
+            let missing = expected.difference(&actual).cloned().collect::<Vec<_>>();
+            let unexpected = actual.difference(&expected).cloned().collect::<Vec<_>>();
 
-            result.perfdata.insert(
-                "total_gids".to_string(),
-                PerfData {
-                    val: PDV(gids.len() as u64),
-                    warn: config.warn_groups.map(PDV).unwrap_or_default(),
-                    crit: config.crit_groups.map(PDV).unwrap_or_default(),
-                    ..Default::default()
-                },
-            );
+            result.description = Some(if missing.is_empty() && unexpected.is_empty() {
+                "Agreement hosts match the expected ones".to_string()
+            } else {
+                format!(
+                    "Agreement hosts differ. Missing: [{}], unexpected: [{}]",
+                    missing.join(", "),
+                    unexpected.join(", ")
+                )
+            });
 
-            result.perfdata.extend(gids.iter().map(|(gid, uids)| {
+            result.perfdata = BTreeMap::from([
                 (
-                    format!("gid[{gid}]"),
+                    "hosts".to_string(),
                     PerfData {
-                        val: PDV(*uids),
+                        min: PDV(0_u64),
+                        val: PDV(actual.len() as u64),
                         ..Default::default()
                     },
-                )
-            }));
+                ),
+                (
+                    "missing".to_string(),
+                    PerfData {
+                        min: PDV(0_u64),
+                        val: PDV(missing.len() as u64),
+                        crit: Some(Threshold::Limit(1.0)),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "unexpected".to_string(),
+                    PerfData {
+                        min: PDV(0_u64),
+                        val: PDV(unexpected.len() as u64),
+                        crit: Some(Threshold::Limit(1.0)),
+                        ..Default::default()
+                    },
+                ),
+            ]);
 
-            let total = gids.len() as u64;
-            if let Some(warn) = config.warn_groups {
-                if total >= warn {
-                    result.return_code = ReturnCode::Warning;
-                }
-            }
-            if let Some(crit) = config.crit_groups {
-                if total >= crit {
-                    result.return_code = ReturnCode::Critical;
-                }
+            if !missing.is_empty() || !unexpected.is_empty() {
+                result.return_code.crit();
+            }
+        }
+        CheckVariant::ReplicationConflicts(rc_config) => {
+            let conflicts =
+                internal::replica::conflict_entries(&mut ldap, &config.default_base).await?;
+            let config = rc_config;
+            let total = conflicts.len() as u64;
+
+            let mut listed: Vec<&str> = conflicts
+                .iter()
+                .take(config.list)
+                .map(|x| x.as_str())
+                .collect();
+            let more = format!("{} more", conflicts.len() - listed.len());
+            if listed.len() < conflicts.len() {
+                listed.push(&more);
+            }
+            result.description = Some(if conflicts.is_empty() {
+                "no replication conflict entries".to_string()
+            } else {
+                format!("replication conflict entries: {}", listed.join("; "))
+            });
+            result.perfdata.insert(
+                "conflict_entries".to_string(),
+                PerfData {
+                    val: PDV(total),
+                    warn: config.warn.clone(),
+                    crit: config.crit.clone(),
+                    min: PDV(0_u64),
+                    ..Default::default()
+                },
+            );
+
+            if let Some(warn) = &config.warn {
+                if warn.alerts(total as f64) {
+                    result.return_code.warn();
+                }
+            }
+            if let Some(crit) = &config.crit {
+                if crit.alerts(total as f64) {
+                    result.return_code.crit();
+                }
+            }
+        }
+        CheckVariant::MissingGids(mg_config) => {
+            let gids_config = internal::gids::GidsConfig {
+                accounts_filter: mg_config.accounts_filter.clone(),
+                groups_filter: mg_config.groups_filter.clone(),
+                concurrent_connections: mg_config.concurrent_connections,
+                low_gid_threshold: mg_config.low_gid_threshold,
+                ..Default::default()
+            };
+            let report = internal::gids::gids_report_counted(
+                &config,
+                &gids_config,
+                &Default::default(),
+                mg_config.list_uids > 0,
+            )
+            .await?;
+
+            let mut listed: Vec<String> = report
+                .missing_uids
+                .iter()
+                .flat_map(|(gid, uids)| uids.iter().map(move |uid| format!("{uid}({gid})")))
+                .collect();
+            listed.sort();
+            let more = listed.len().saturating_sub(mg_config.list_uids);
+            listed.truncate(mg_config.list_uids);
+            if more > 0 {
+                listed.push(format!("{more} more"));
+            }
+            let description = if listed.is_empty() {
+                "Missing gids".to_string()
+            } else {
+                format!("Missing gids: {}", listed.join(", "))
+            };
+            let gids = report.missing;
+            let low_number_count = report.low_number_count;
+
+            let config = mg_config;
+
+            result.description = Some(description);
+
+            result.perfdata.insert(
+                "total_gids".to_string(),
+                PerfData {
+                    val: PDV(gids.len() as u64),
+                    warn: config.warn_groups.clone(),
+                    crit: config.crit_groups.clone(),
+                    ..Default::default()
+                },
+            );
+
+            result.perfdata.extend(gids.iter().map(|(gid, uids)| {
+                (
+                    format!("gid[{gid}]"),
+                    PerfData {
+                        val: PDV(*uids),
+                        ..Default::default()
+                    },
+                )
+            }));
+
+            let total = gids.len() as u64;
+            if let Some(warn) = &config.warn_groups {
+                if warn.alerts(total as f64) {
+                    result.return_code = ReturnCode::Warning;
+                }
+            }
+            if let Some(crit) = &config.crit_groups {
+                if crit.alerts(total as f64) {
+                    result.return_code = ReturnCode::Critical;
+                }
             }
 
             let total_users = gids.values().sum::<u64>();
@@ -681,23 +1390,44 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                 "total_users".to_string(),
                 PerfData {
                     val: PDV(total_users),
-                    warn: config.warn_users.map(PDV).unwrap_or_default(),
-                    crit: config.crit_users.map(PDV).unwrap_or_default(),
+                    warn: config.warn_users.clone(),
+                    crit: config.crit_users.clone(),
                     ..Default::default()
                 },
             );
 
-            if let Some(warn) = config.warn_users {
-                if total_users >= warn {
+            if let Some(warn) = &config.warn_users {
+                if warn.alerts(total_users as f64) {
                     result.return_code = ReturnCode::Warning;
                 }
             }
 
-            if let Some(crit) = config.crit_users {
-                if total_users >= crit {
+            if let Some(crit) = &config.crit_users {
+                if crit.alerts(total_users as f64) {
                     result.return_code = ReturnCode::Critical;
                 }
             }
+
+            result.perfdata.insert(
+                "low_number_gids".to_string(),
+                PerfData {
+                    val: PDV(low_number_count),
+                    warn: config.warn_low_number.clone(),
+                    crit: config.crit_low_number.clone(),
+                    min: PDV(0_u64),
+                    ..Default::default()
+                },
+            );
+            if let Some(warn) = &config.warn_low_number {
+                if warn.alerts(low_number_count as f64) {
+                    result.return_code.warn();
+                }
+            }
+            if let Some(crit) = &config.crit_low_number {
+                if crit.alerts(low_number_count as f64) {
+                    result.return_code.crit();
+                }
+            }
         }
         CheckVariant::Connections(config) => {
             let (connections, monitor_connections) = {
@@ -717,7 +1447,7 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                     .int_metrics
                     .get("connections")
                     .copied()
-                    .unwrap_or(0_i32) as u64;
+                    .unwrap_or(0_u64);
 
                 let counted = connections.vec().len();
 
@@ -729,7 +1459,7 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
 
                 if counted as u64 != monitor_connections || monitor_connections != snmp_connections
                 {
-                    result.perfdata = HashMap::from([
+                    result.perfdata = BTreeMap::from([
                         (
                             "reported_connections".to_string(),
                             PerfData {
@@ -771,7 +1501,7 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                 }
             }
 
-            let connections: Vec<internal::monitor::LdapConnection> = connections
+            let connections: internal::monitor::LdapConnections = connections
                 .consume_vec()
                 .into_iter()
                 .filter(|x| {
@@ -781,40 +1511,136 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                         && !(config_exclude_dn_lowercase.contains(&x.dn.to_lowercase()))
                         && !(config.exclude_ip.contains(&x.ip))
                 })
-                .collect();
+                .collect::<Vec<_>>()
+                .into();
 
             if config.debug {
                 println!("------------------------------");
                 println!("Connections after filtering: ");
-                for c in &connections {
+                for c in connections.vec() {
                     println!("{:?}", c);
                 }
             }
 
             result.description = Some("389ds reported connections".to_string());
 
-            result.perfdata = HashMap::from([(
+            let count = connections.count() as u64;
+            result.perfdata = BTreeMap::from([(
                 "connections".to_string(),
                 PerfData {
                     min: PDV(0_u64),
-                    val: PDV(connections.len() as u64),
-                    warn: config.warn.map(PDV).unwrap_or_default(),
-                    crit: config.crit.map(PDV).unwrap_or_default(),
+                    val: PDV(count),
+                    warn: config.warn.clone(),
+                    crit: config.crit.clone(),
                     ..Default::default()
                 },
             )]);
 
-            if let Some(warn) = config.warn {
-                if connections.len() as u64 >= warn {
+            if let Some(warn) = &config.warn {
+                if warn.alerts(count as f64) {
                     result.return_code.warn()
                 }
             }
 
-            if let Some(crit) = config.crit {
-                if connections.len() as u64 >= crit {
+            if let Some(crit) = &config.crit {
+                if crit.alerts(count as f64) {
                     result.return_code.crit()
                 }
             }
+
+            // Single client holding many connections (e.g. a connection leak)
+            for (group, groups, warn, crit) in [
+                (
+                    "dn",
+                    connections.group_by_dn(),
+                    &config.warn_per_dn,
+                    &config.crit_per_dn,
+                ),
+                (
+                    "ip",
+                    connections.group_by_ip(),
+                    &config.warn_per_ip,
+                    &config.crit_per_ip,
+                ),
+            ] {
+                if warn.is_none() && crit.is_none() {
+                    continue;
+                }
+
+                for (name, group_count) in groups {
+                    result.perfdata.insert(
+                        format!("connections {group}({name})"),
+                        PerfData {
+                            min: PDV(0_u64),
+                            val: PDV(group_count),
+                            warn: warn.clone(),
+                            crit: crit.clone(),
+                            ..Default::default()
+                        },
+                    );
+
+                    let is_crit = crit.as_ref().is_some_and(|x| x.alerts(group_count as f64));
+                    let is_warn = warn.as_ref().is_some_and(|x| x.alerts(group_count as f64));
+                    if is_crit {
+                        result.return_code.crit();
+                    } else if is_warn {
+                        result.return_code.warn();
+                    }
+
+                    if is_crit || is_warn {
+                        result
+                            .long_output
+                            .push(format!("{group} {name} holds {group_count} connections"));
+                    }
+                }
+            }
+        }
+        CheckVariant::PrivilegedBinds(config) => {
+            let dns = if config.dn.is_empty() {
+                internal::monitor::DEFAULT_PRIVILEGED_DNS
+                    .iter()
+                    .map(|x| x.to_string())
+                    .collect()
+            } else {
+                config.dn.clone()
+            };
+
+            let by_dn = internal::monitor::LdapMonitor::scrape(&mut ldap)
+                .await?
+                .connections
+                .group_by_privileged_dn(&dns);
+            let total = by_dn.values().sum::<u64>();
+
+            result.description = Some("Connections bound as privileged DNs".to_string());
+            result.perfdata = BTreeMap::from([(
+                "privileged_connections".to_string(),
+                PerfData {
+                    min: PDV(0_u64),
+                    val: PDV(total),
+                    warn: Some(config.warn.clone()),
+                    crit: config.crit.clone(),
+                    ..Default::default()
+                },
+            )]);
+            result.perfdata.extend(by_dn.into_iter().map(|(dn, count)| {
+                (
+                    dn,
+                    PerfData {
+                        val: PDV(count),
+                        ..Default::default()
+                    },
+                )
+            }));
+
+            if config.warn.alerts(total as f64) {
+                result.return_code.warn();
+            }
+
+            if let Some(crit) = &config.crit {
+                if crit.alerts(total as f64) {
+                    result.return_code.crit();
+                }
+            }
         }
         CheckVariant::Errors(config) => {
             let snmp = internal::monitor::LdapSNMP::scrape(&mut ldap).await?;
@@ -829,8 +1655,7 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                         *snmp
                             .int_metrics
                             .get(key)
-                            .expect("These keys should be checked before!")
-                            as u64,
+                            .expect("These keys should be checked before!"),
                     )
                 })
                 .collect();
@@ -839,13 +1664,13 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
 
             result.description = Some("389ds errors in the SNMP monitor".to_string());
 
-            result.perfdata = HashMap::from([(
+            result.perfdata = BTreeMap::from([(
                 "errors_sum".to_string(),
                 PerfData {
                     min: PDV(0_u64),
                     val: PDV(errors_sum),
-                    warn: config.warn_sum.map(PDV).unwrap_or_default(),
-                    crit: config.crit_sum.map(PDV).unwrap_or_default(),
+                    warn: config.warn_sum.clone(),
+                    crit: config.crit_sum.clone(),
                     ..Default::default()
                 },
             )]);
@@ -856,38 +1681,114 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                     PerfData {
                         min: PDV(0_u64),
                         val: PDV(*value),
-                        warn: config.warn.map(PDV).unwrap_or_default(),
-                        crit: config.crit.map(PDV).unwrap_or_default(),
+                        warn: config.warn.clone(),
+                        crit: config.crit.clone(),
                         ..Default::default()
                     },
                 )
             }));
 
-            if let Some(warn_sum) = config.warn_sum {
-                if errors_sum >= warn_sum {
+            if let Some(warn_sum) = &config.warn_sum {
+                if warn_sum.alerts(errors_sum as f64) {
                     result.return_code.warn()
                 }
             }
 
-            if let Some(crit_sum) = config.crit_sum {
-                if errors_sum >= crit_sum {
+            if let Some(crit_sum) = &config.crit_sum {
+                if crit_sum.alerts(errors_sum as f64) {
                     result.return_code.crit()
                 }
             }
 
             errors.iter().for_each(|(_, value)| {
-                if let Some(warn) = config.warn {
-                    if *value >= warn {
+                if let Some(warn) = &config.warn {
+                    if warn.alerts(*value as f64) {
                         result.return_code.warn()
                     }
                 }
-                if let Some(crit) = config.crit {
-                    if *value >= crit {
+                if let Some(crit) = &config.crit {
+                    if crit.alerts(*value as f64) {
                         result.return_code.crit()
                     }
                 }
             });
         }
+        CheckVariant::BindFailures(bf_config) => {
+            const COUNTERS: &[&str] = &["bindsecurityerrors", "securityerrors"];
+
+            let snmp = internal::monitor::LdapSNMP::scrape(&mut ldap).await?;
+            let current: HashMap<String, u64> = COUNTERS
+                .iter()
+                .map(|key| {
+                    snmp.int_metrics
+                        .get(*key)
+                        .map(|value| (key.to_string(), *value))
+                        .ok_or(anyhow!("Missing {key} in snmp monitor"))
+                })
+                .collect::<Result<_>>()?;
+
+            let state_path = bf_config.state_file.clone().unwrap_or_else(|| {
+                internal::state::default_state_path("bind-failures", &config.uri)
+            });
+            let previous = internal::state::CounterState::load(&state_path)?;
+            internal::state::CounterState::new(current.clone()).save(&state_path)?;
+
+            let Some(previous) = previous else {
+                result.description =
+                    Some("No previous state of the security errors. Baseline saved".to_string());
+                return Ok(());
+            };
+
+            let deltas: HashMap<&str, u64> = COUNTERS
+                .iter()
+                .map(|key| {
+                    let value = current[*key];
+                    (*key, previous.delta(key, value).unwrap_or(0))
+                })
+                .collect();
+            let delta_sum: u64 = deltas.values().sum();
+            let interval = previous.age();
+
+            result.description = Some(format!(
+                "{delta_sum} new security errors in the last {interval}s"
+            ));
+
+            result.perfdata = BTreeMap::from([(
+                "security_errors_delta".to_string(),
+                PerfData {
+                    min: PDV(0_u64),
+                    val: PDV(delta_sum),
+                    warn: bf_config.warn.clone(),
+                    crit: bf_config.crit.clone(),
+                    ..Default::default()
+                },
+            )]);
+            result.perfdata.extend(deltas.iter().map(|(key, value)| {
+                (
+                    format!("{key}_delta"),
+                    PerfData {
+                        min: PDV(0_u64),
+                        val: PDV(*value),
+                        ..Default::default()
+                    },
+                )
+            }));
+
+            if bf_config
+                .warn
+                .as_ref()
+                .is_some_and(|x| x.alerts(delta_sum as f64))
+            {
+                result.return_code.warn()
+            }
+            if bf_config
+                .crit
+                .as_ref()
+                .is_some_and(|x| x.alerts(delta_sum as f64))
+            {
+                result.return_code.crit()
+            }
+        }
         CheckVariant::RecentRestart(config) => {
             const STARTTIME: &str = "starttime";
             const CURRTIME: &str = "currenttime";
@@ -906,11 +1807,11 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
             let difference_seconds =
                 currenttime.and_utc().timestamp() - starttime.and_utc().timestamp();
 
-            result.perfdata = HashMap::from([(
+            result.perfdata = BTreeMap::from([(
                 "seconds_since_last_restart".to_string(),
                 PerfData {
                     min: PDV(0.0),
-                    warn: config.warn_if_less_than.and_then(PDV),
+                    warn: config.warn_if_less_than.clone(),
                     val: PDV(difference_seconds as f64),
                     unit: Some("s".to_string()),
                     ..Default::default()
@@ -919,8 +1820,8 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
 
             result.description = Some("Seconds since last restart of the 390ds".to_string());
 
-            if let Some(limit) = config.warn_if_less_than {
-                if (difference_seconds as u64) <= limit {
+            if let Some(limit) = &config.warn_if_less_than {
+                if limit.alerts_below(difference_seconds as f64) {
                     result.return_code.warn()
                 }
             }
@@ -945,8 +1846,8 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                         PerfData {
                             min: PDV(1.0),
                             max: PDV(100.0),
-                            warn: config.warn_percent_used.and_then(PDV),
-                            crit: config.crit_percent_used.and_then(PDV),
+                            warn: config.warn_percent_used.clone(),
+                            crit: config.crit_percent_used.clone(),
                             unit: Some("%".to_string()),
                             val: partition
                                 .1
@@ -961,8 +1862,8 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                         PerfData {
                             min: PDV(0_u64),
                             max: None,
-                            warn: config.warn_absolute_available.and_then(PDV),
-                            crit: config.crit_absolute_available.and_then(PDV),
+                            warn: config.warn_absolute_available.clone(),
+                            crit: config.crit_absolute_available.clone(),
                             unit: Some("B".to_string()),
                             val: partition
                                 .1
@@ -984,35 +1885,249 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                 let available_absolute =
                     partition.1.int_metrics.get(AVAILABLE).copied().unwrap_or(0);
 
-                if let Some(limit) = config.warn_percent_used {
-                    if use_percentage >= limit {
+                if let Some(limit) = &config.warn_percent_used {
+                    if limit.alerts(use_percentage) {
                         result.return_code.warn();
                     }
                 }
 
-                if let Some(limit) = config.warn_absolute_available {
-                    if available_absolute <= limit {
+                if let Some(limit) = &config.warn_absolute_available {
+                    if limit.alerts_below(available_absolute as f64) {
                         result.return_code.warn();
                     }
                 }
 
-                if let Some(limit) = config.crit_percent_used {
-                    if use_percentage >= limit {
+                if let Some(limit) = &config.crit_percent_used {
+                    if limit.alerts(use_percentage) {
                         result.return_code.crit();
                     }
                 }
 
-                if let Some(limit) = config.crit_absolute_available {
-                    if available_absolute <= limit {
+                if let Some(limit) = &config.crit_absolute_available {
+                    if limit.alerts_below(available_absolute as f64) {
                         result.return_code.crit();
                     }
                 }
             }
         }
+        CheckVariant::DbCacheHitRatio(config) => {
+            let database = internal::monitor::LdapDatabase::scrape(&mut ldap).await?;
+            let ratio = database.cache_hit_ratio().ok_or(anyhow!(
+                "No database cache statistics. LMDB backend does not provide them"
+            ))?;
+
+            result.description = Some("database cache hit ratio".to_string());
+            result.perfdata = BTreeMap::from([(
+                "cache_hit_ratio".to_string(),
+                PerfData {
+                    min: PDV(0.0),
+                    max: PDV(100.0),
+                    val: PDV(ratio),
+                    warn: config.warn.clone(),
+                    crit: config.crit.clone(),
+                    unit: Some("%".to_string()),
+                },
+            )]);
+
+            if let Some(warn) = &config.warn {
+                if warn.alerts_below(ratio as f64) {
+                    result.return_code.warn();
+                }
+            }
+
+            if let Some(crit) = &config.crit {
+                if crit.alerts_below(ratio as f64) {
+                    result.return_code.crit();
+                }
+            }
+        }
+        CheckVariant::BackendCount(config) => {
+            let backends = internal::monitor::LdapBackends::scrape(&mut ldap)
+                .await?
+                .backends;
+
+            let mut suffixes = backends.values().cloned().collect::<Vec<_>>();
+            suffixes.sort();
+
+            result.description = Some(format!("Backends: {}", suffixes.join(", ")));
+            result.perfdata = BTreeMap::from([(
+                "backends".to_string(),
+                PerfData {
+                    min: PDV(0_u64),
+                    val: PDV(backends.len() as u64),
+                    ..Default::default()
+                },
+            )]);
+
+            if backends.len() as u64 != config.expected {
+                result.return_code.crit();
+            }
+        }
+        CheckVariant::WriteProbe(config) => {
+            let probe = internal::probe::WriteProbe::new(config.dn.clone())
+                .run(&mut ldap)
+                .await?;
+            let total = probe.total().as_millis() as u64;
+
+            result.description = Some(format!("Write probe of {}", config.dn));
+            result.perfdata = BTreeMap::from([
+                (
+                    "total".to_string(),
+                    PerfData {
+                        min: PDV(0_u64),
+                        val: PDV(total),
+                        warn: config.warn.clone(),
+                        crit: config.crit.clone(),
+                        unit: Some("ms".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "add".to_string(),
+                    PerfData {
+                        val: PDV(probe.add.as_millis() as u64),
+                        unit: Some("ms".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "modify".to_string(),
+                    PerfData {
+                        val: PDV(probe.modify.as_millis() as u64),
+                        unit: Some("ms".to_string()),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "delete".to_string(),
+                    PerfData {
+                        val: PDV(probe.delete.as_millis() as u64),
+                        unit: Some("ms".to_string()),
+                        ..Default::default()
+                    },
+                ),
+            ]);
+
+            if let Some(warn) = &config.warn {
+                if warn.alerts(total as f64) {
+                    result.return_code.warn();
+                }
+            }
+
+            if let Some(crit) = &config.crit {
+                if crit.alerts(total as f64) {
+                    result.return_code.crit();
+                }
+            }
+        }
+        CheckVariant::LoggingConfig(config) => {
+            use internal::logging::*;
+
+            let expected = [
+                (ACCESS_LOG_ENABLED, &config.access_logging),
+                (ACCESS_LOG_LEVEL, &config.access_level),
+                (ERROR_LOG_LEVEL, &config.error_level),
+                (AUDIT_LOG_ENABLED, &config.audit_logging),
+                (AUDITFAIL_LOG_ENABLED, &config.auditfail_logging),
+            ]
+            .into_iter()
+            .filter_map(|(attr, value)| value.clone().map(|value| (attr, value)))
+            .collect::<Vec<_>>();
+
+            let logging = LdapLoggingConfig::scrape(&mut ldap).await?;
+            let drift = logging.drift(&expected);
+
+            result.perfdata = BTreeMap::from([(
+                "drifted".to_string(),
+                PerfData {
+                    min: PDV(0_u64),
+                    val: PDV(drift.len() as u64),
+                    crit: Some(Threshold::Limit(1.0)),
+                    max: PDV(expected.len() as u64),
+                    ..Default::default()
+                },
+            )]);
+
+            if drift.is_empty() {
+                result.description = Some("Logging settings match the expected ones".to_string());
+            } else {
+                let drift = drift
+                    .iter()
+                    .map(|(attr, expected, actual)| {
+                        format!(
+                            "{attr}: expected {expected}, got {}",
+                            actual.unwrap_or("(not readable)")
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                result.description = Some(format!("Logging settings drift: {}", drift.join("; ")));
+                result.return_code.crit();
+            }
+        }
+        CheckVariant::IndexesPendingReindex(config) => {
+            let cli_conf = internal::cli::CommandConfig {
+                timeout_seconds: config.timeout,
+                instance_name: config.instance.clone(),
+                command_prefix: config
+                    .command_prefix
+                    .as_ref()
+                    .map(|x| x.split_whitespace().map(String::from).collect()),
+                dsctl_path: config.dsctl_path.clone(),
+                extra_args: config.dsctl_args.clone(),
+                ..Default::default()
+            };
+
+            let healthchecks = cli_conf.healthcheck(internal::cli::BACKENDS_CHECK).await?;
+            let pending = internal::cli::pending_reindex(&healthchecks);
+            let total = pending.values().sum::<u64>();
+
+            result.description = Some("Indexes pending reindex".to_string());
+            result.perfdata = BTreeMap::from([(
+                "total".to_string(),
+                PerfData {
+                    min: PDV(0_u64),
+                    val: PDV(total),
+                    warn: config.warn.clone(),
+                    crit: config.crit.clone(),
+                    ..Default::default()
+                },
+            )]);
+            result
+                .perfdata
+                .extend(pending.into_iter().map(|(backend, count)| {
+                    (
+                        backend,
+                        PerfData {
+                            val: PDV(count),
+                            ..Default::default()
+                        },
+                    )
+                }));
+
+            if let Some(warn) = &config.warn {
+                if warn.alerts(total as f64) {
+                    result.return_code.warn();
+                }
+            }
+
+            if let Some(crit) = &config.crit {
+                if crit.alerts(total as f64) {
+                    result.return_code.crit();
+                }
+            }
+        }
         CheckVariant::CliHealthcheck(config) => {
             let cli_conf = internal::cli::CommandConfig {
                 timeout_seconds: config.timeout,
                 instance_name: config.instance.clone(),
+                include_log_checks: config.include_log_checks,
+                command_prefix: config
+                    .command_prefix
+                    .as_ref()
+                    .map(|x| x.split_whitespace().map(String::from).collect()),
+                dsctl_path: config.dsctl_path.clone(),
+                extra_args: config.dsctl_args.clone(),
+                ..Default::default()
             };
 
             let healthchecks = cli_conf.healthchecks().await?;
@@ -1035,14 +2150,18 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
             let all_severity = low_severity + high_severity + medium_severity;
 
             result.description = Some(String::from("CLI healthcheck"));
+            result.long_output = healthchecks
+                .iter()
+                .map(|x| format!("{} {}: {}", x.severity, x.dsle, x.short_description()))
+                .collect();
             result.perfdata.extend([
                 (
                     "all_severity".to_string(),
                     PerfData {
                         min: PDV(0_u64),
                         val: PDV(all_severity),
-                        warn: config.warn.map(PDV).unwrap_or_default(),
-                        crit: config.crit.map(PDV).unwrap_or_default(),
+                        warn: config.warn.clone(),
+                        crit: config.crit.clone(),
                         ..Default::default()
                     },
                 ),
@@ -1051,8 +2170,8 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                     PerfData {
                         min: PDV(0_u64),
                         val: PDV(low_severity),
-                        warn: config.warn_low.map(PDV).unwrap_or_default(),
-                        crit: config.crit_low.map(PDV).unwrap_or_default(),
+                        warn: config.warn_low.clone(),
+                        crit: config.crit_low.clone(),
                         ..Default::default()
                     },
                 ),
@@ -1061,8 +2180,8 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                     PerfData {
                         min: PDV(0_u64),
                         val: PDV(medium_severity),
-                        warn: config.warn_medium.map(PDV).unwrap_or_default(),
-                        crit: config.crit_medium.map(PDV).unwrap_or_default(),
+                        warn: config.warn_medium.clone(),
+                        crit: config.crit_medium.clone(),
                         ..Default::default()
                     },
                 ),
@@ -1071,57 +2190,57 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                     PerfData {
                         min: PDV(0_u64),
                         val: PDV(high_severity),
-                        warn: config.warn_high.map(PDV).unwrap_or_default(),
-                        crit: config.crit_high.map(PDV).unwrap_or_default(),
+                        warn: config.warn_high.clone(),
+                        crit: config.crit_high.clone(),
                         ..Default::default()
                     },
                 ),
             ]);
 
-            if let Some(warn) = config.warn {
-                if all_severity >= warn {
+            if let Some(warn) = &config.warn {
+                if warn.alerts(all_severity as f64) {
                     result.return_code = ReturnCode::Warning;
                 }
             }
 
-            if let Some(warn) = config.warn_low {
-                if low_severity >= warn {
+            if let Some(warn) = &config.warn_low {
+                if warn.alerts(low_severity as f64) {
                     result.return_code = ReturnCode::Warning;
                 }
             }
 
-            if let Some(warn) = config.warn_medium {
-                if medium_severity >= warn {
+            if let Some(warn) = &config.warn_medium {
+                if warn.alerts(medium_severity as f64) {
                     result.return_code = ReturnCode::Warning;
                 }
             }
 
-            if let Some(warn) = config.warn_high {
-                if high_severity >= warn {
+            if let Some(warn) = &config.warn_high {
+                if warn.alerts(high_severity as f64) {
                     result.return_code = ReturnCode::Warning;
                 }
             }
 
-            if let Some(crit) = config.crit {
-                if all_severity >= crit {
+            if let Some(crit) = &config.crit {
+                if crit.alerts(all_severity as f64) {
                     result.return_code = ReturnCode::Critical;
                 }
             }
 
-            if let Some(crit) = config.crit_low {
-                if low_severity >= crit {
+            if let Some(crit) = &config.crit_low {
+                if crit.alerts(low_severity as f64) {
                     result.return_code = ReturnCode::Critical;
                 }
             }
 
-            if let Some(crit) = config.crit_medium {
-                if medium_severity >= crit {
+            if let Some(crit) = &config.crit_medium {
+                if crit.alerts(medium_severity as f64) {
                     result.return_code = ReturnCode::Critical;
                 }
             }
 
-            if let Some(crit) = config.crit_high {
-                if high_severity >= crit {
+            if let Some(crit) = &config.crit_high {
+                if crit.alerts(high_severity as f64) {
                     result.return_code = ReturnCode::Critical;
                 }
             }
@@ -1134,53 +2253,60 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
             );
 
             cq.max_entries = cqt_config.max_entries;
+            cq.attrs = cqt_config.attributes.clone();
+
+            if cqt_config.show_entries {
+                cq.keep_entries = cqt_config.show_entries_limit;
+            }
 
             let metrics = cq.get_metrics().await?;
 
-            result.description = Some("query time".to_string());
+            for entry in &metrics.entries {
+                eprintln!("dn: {}", entry.dn);
+                for (attr, values) in &entry.attrs {
+                    for value in values {
+                        eprintln!("{attr}: {value}");
+                    }
+                }
+                eprintln!();
+            }
+
+            if metrics.object_count > metrics.entries.len() as u64 && cqt_config.show_entries {
+                eprintln!(
+                    "# {} more entries not shown",
+                    metrics.object_count - metrics.entries.len() as u64
+                );
+            }
+
+            result.description = Some(if metrics.ldap_code == 0 {
+                "query time".to_string()
+            } else {
+                format!("query time ({})", metrics.ldap_code_text)
+            });
             result.perfdata.extend([(
                 "query_time".to_string(),
                 PerfData {
                     min: PDV(0_u64),
                     val: PDV(metrics.query_time.as_millis() as u64),
-                    warn: cqt_config.warn.map(PDV).unwrap_or_default(),
-                    crit: cqt_config.crit.map(PDV).unwrap_or_default(),
+                    warn: cqt_config.warn.clone(),
+                    crit: cqt_config.crit.clone(),
                     ..Default::default()
                 },
             )]);
 
-            if let Some(warn) = cqt_config.warn {
-                if metrics.query_time.as_millis() as u64 >= warn {
+            if let Some(warn) = &cqt_config.warn {
+                if warn.alerts(metrics.query_time.as_millis() as f64) {
                     result.return_code = ReturnCode::Warning;
                 }
             }
 
-            if let Some(crit) = cqt_config.crit {
-                if metrics.query_time.as_millis() as u64 >= crit {
+            if let Some(crit) = &cqt_config.crit {
+                if crit.alerts(metrics.query_time.as_millis() as f64) {
                     result.return_code = ReturnCode::Critical;
                 }
             }
         }
         CheckVariant::CustomQueryIntegrity(cqi_config) => {
-            let (object_number, bytes_size, attr_number, checksum) = {
-                let mut custom_query = internal::query::CustomQuery::new(
-                    "query".to_string(),
-                    cqi_config.filter.clone(),
-                    config.clone(),
-                );
-                custom_query.attrs = cqi_config.attributes.clone();
-                custom_query.max_entries = cqi_config.max_entries;
-
-                let metrics = custom_query.get_metrics().await?;
-
-                (
-                    metrics.object_count,
-                    metrics.bytes,
-                    metrics.attrs_count,
-                    metrics.sha256_checksum,
-                )
-            };
-
             struct Integrity {
                 on: bool,
                 bs: bool,
@@ -1215,51 +2341,128 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                         cs_val_reported: cs_val.to_string(),
                     }
                 }
-                fn compare(&mut self, on_num: u64, bs_num: u64, an_num: u64, cs_val: String) {
-                    if self.on_num != on_num {
+
+                /// Returns (entries, bytes, attributes, checksum) equality of the compared host
+                fn compare(
+                    &mut self,
+                    on_num: u64,
+                    bs_num: u64,
+                    an_num: u64,
+                    cs_val: String,
+                ) -> (bool, bool, bool, bool) {
+                    let same = (
+                        self.on_num == on_num,
+                        self.bs_num == bs_num,
+                        self.an_num == an_num,
+                        self.cs_val == cs_val,
+                    );
+                    if !same.0 {
                         self.on = false;
                         self.on_num_compared = on_num;
                     }
-                    if self.bs_num != bs_num {
+                    if !same.1 {
                         self.bs = false;
                         self.bs_num_compared = bs_num;
                     }
-                    if self.an_num != an_num {
+                    if !same.2 {
                         self.an = false;
                         self.an_num_compared = an_num;
                     }
-                    if self.cs_val != cs_val {
+                    if !same.3 {
                         self.cs = false;
                         self.cs_val_reported = cs_val;
                     }
+                    same
                 }
             }
 
-            let mut integrity =
-                Integrity::new(object_number, bytes_size, attr_number, checksum.clone());
+            let mut truncated_hosts = Vec::new();
+            let mut diverged_hosts = Vec::new();
+            let mut checksums = Vec::new();
+            let mut integrity: Option<Integrity> = None;
 
-            let mut config = config.clone();
-            config.uri = cqi_config.host.clone();
+            // The first host is the primary one, the others are compared against it
+            for uri in std::iter::once(&config.uri).chain(&cqi_config.host) {
+                let mut host_config = config.clone();
+                host_config.uri = uri.clone();
 
-            let mut custom_query = internal::query::CustomQuery::new(
-                "query".to_string(),
-                cqi_config.filter.clone(),
-                config.clone(),
-            );
-            custom_query.attrs = cqi_config.attributes.clone();
-            custom_query.max_entries = cqi_config.max_entries;
+                let mut custom_query = internal::query::CustomQuery::new(
+                    "query".to_string(),
+                    cqi_config.filter.clone(),
+                    host_config,
+                );
+                custom_query.attrs = cqi_config.attributes.clone();
+                custom_query.max_entries = cqi_config.max_entries;
+                custom_query.server_sort = cqi_config.server_sort;
+                custom_query.normalize_dn = cqi_config.normalize_dn;
 
-            let metrics = custom_query.get_metrics().await?;
+                let metrics = custom_query.get_metrics().await?;
+                if cqi_config.server_sort && !metrics.server_sorted {
+                    result.long_output.push(format!(
+                        "{uri} does not support server side sorting. Using client sorting"
+                    ));
+                }
+                if metrics.truncated {
+                    truncated_hosts.push(format!("{} ({})", uri, metrics.ldap_code_text));
+                }
+                checksums.push((uri, metrics.sha256_checksum.clone()));
+
+                let Some(integrity) = &mut integrity else {
+                    integrity = Some(Integrity::new(
+                        metrics.object_count,
+                        metrics.bytes,
+                        metrics.attrs_count,
+                        metrics.sha256_checksum,
+                    ));
+                    continue;
+                };
 
-            integrity.compare(
-                metrics.object_count,
-                metrics.bytes,
-                metrics.attrs_count,
-                metrics.sha256_checksum,
-            );
+                let (on, bs, an, cs) = integrity.compare(
+                    metrics.object_count,
+                    metrics.bytes,
+                    metrics.attrs_count,
+                    metrics.sha256_checksum,
+                );
+                let differences = [
+                    ("entries", on, cqi_config.entries_count_integrity),
+                    ("bytes", bs, cqi_config.bytes_size_integrity),
+                    ("attributes", an, cqi_config.attributes_count_integrity),
+                    ("checksum", cs, cqi_config.sha256_integrity),
+                ]
+                .into_iter()
+                .filter(|(_, same, checked)| *checked && !same)
+                .map(|(name, _, _)| name)
+                .collect::<Vec<_>>();
+
+                result.perfdata.insert(
+                    format!("diverged host({uri})"),
+                    PerfData {
+                        val: PDV(!differences.is_empty() as u64),
+                        crit: Some(Threshold::Limit(1.0)),
+                        min: PDV(0_u64),
+                        ..Default::default()
+                    },
+                );
+                if !differences.is_empty() {
+                    diverged_hosts.push(format!("{uri} ({})", differences.join(", ")));
+                }
+            }
+
+            let integrity = integrity.ok_or(anyhow!("No host to query"))?;
 
             if !integrity.cs && cqi_config.sha256_integrity {
                 result.return_code.crit();
+
+                // Fingerprints are enough to tell which hosts agree with each other
+                for (idx, (uri, checksum)) in checksums.iter().enumerate() {
+                    let fingerprint = checksum
+                        .get(..CHECKSUM_FINGERPRINT_LENGTH)
+                        .unwrap_or(checksum);
+                    let role = if idx == 0 { "primary" } else { "compared" };
+                    result
+                        .long_output
+                        .push(format!("{role} {uri}: checksum {fingerprint}"));
+                }
             }
 
             if !integrity.on && cqi_config.entries_count_integrity {
@@ -1274,7 +2477,20 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                 result.return_code.crit();
             }
 
-            result.description = Some("query integrity across hosts".to_string());
+            // Partial results cannot be compared, whatever the counts and checksums say
+            result.description = if truncated_hosts.is_empty() {
+                Some(if diverged_hosts.is_empty() {
+                    "query integrity across hosts".to_string()
+                } else {
+                    format!("query results diverged on: {}", diverged_hosts.join(", "))
+                })
+            } else {
+                result.return_code.crit();
+                Some(format!(
+                    "query results truncated by the size limit on: {}",
+                    truncated_hosts.join(", ")
+                ))
+            };
             result.perfdata.extend([
                 (
                     "object_number".to_string(),
@@ -1327,21 +2543,19 @@ pub async fn command_select(config: LdapConfig, args: Cli, result: &mut Nagios)
                 ),
             ])
         }
+        CheckVariant::CheckConfig => unreachable!("Config is checked before connecting"),
     }
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Cli::parse();
-
-    let mut config = if let Some(config) = &args.config {
-        let config = String::from_utf8(std::fs::read(config)?)?;
-        toml::from_str(&config).unwrap()
-    } else {
-        LdapConfig::default()
-    };
+fn load_config(path: &std::path::Path) -> Result<LdapConfig> {
+    let config = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read config file {}", path.display()))?;
+    toml::from_str(&config).with_context(|| format!("Invalid config file {}", path.display()))
+}
 
+/// Override the config (file and environment) with the CLI flags
+fn apply_args(config: &mut LdapConfig, args: &Cli) {
     if let Some(basedn) = &args.basedn {
         config.default_base = basedn.clone();
     }
@@ -1357,29 +2571,210 @@ async fn main() -> Result<()> {
     if args.skip_cert_verification {
         config.verify_certs = false;
     }
+
+    if args.start_tls {
+        config.start_tls = true;
+    }
     if let Some(bind) = if let Some(binddn) = &args.binddn {
         let bindpass = args.bindpass.clone().unwrap();
 
-        Some(Bind {
+        Some(BindMethod::Simple {
             dn: binddn.clone(),
-            pass: bindpass,
+            pass: bindpass.into(),
         })
     } else {
         None
     } {
         config.bind = Some(bind);
     }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Cli::parse();
+
+    let mut result = Nagios {
+        precision: Some(args.precision),
+        hide_perfdata: args.no_perfdata,
+        perfdata_in_description: args.perfdata_in_description,
+        unknown_as_critical: args.unknown_as_critical,
+        ..Default::default()
+    };
+
+    let mut config = if let Some(path) = &args.config {
+        match load_config(path) {
+            Ok(config) => config,
+            Err(error) => {
+                result.return_code = ReturnCode::Unknown;
+                result.description = Some(format!("{error:#}"));
+                result.exit_with_message();
+            }
+        }
+    } else {
+        LdapConfig::default()
+    };
+
+    if let Err(error) = config.apply_env() {
+        result.return_code = ReturnCode::Unknown;
+        result.description = Some(error.to_string());
+        result.exit_with_message();
+    }
 
-    let mut result = Nagios::default();
+    apply_args(&mut config, &args);
 
-    let response = command_select(config, args, &mut result).await;
+    if let CheckVariant::CheckConfig = args.subcommand {
+        internal::config::exit_with_problems(&config.validate());
+    }
+
+    // Check fails after -t or the operation timeout of the config, whichever is shorter
+    let timeout = args
+        .timeout
+        .into_iter()
+        .chain(config.operation_timeout_seconds)
+        .min();
+    let response = if let Some(timeout) = timeout {
+        let check = command_select(config, args, &mut result);
+        match tokio::time::timeout(tokio::time::Duration::from_secs(timeout), check).await {
+            Ok(response) => response,
+            Err(_) => {
+                // Partial results of the interrupted check are not reported
+                result.perfdata.clear();
+                result.long_output.clear();
+                Err(anyhow!("Check timed out after {timeout}s"))
+            }
+        }
+    } else {
+        command_select(config, args, &mut result).await
+    };
 
     if let Err(error) = response {
         result.return_code = ReturnCode::Unknown;
         result.description = Some(error.to_string());
     }
 
-    result.exit_with_message();
+    result.exit_with_message()
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perfdata_is_ordered_by_name() {
+        let perfdata = |val: u64| PerfData {
+            val: PDV(val),
+            ..Default::default()
+        };
+        let mut result = Nagios::default();
+        result.perfdata.insert("zeta".to_string(), perfdata(3));
+        result.perfdata.insert("alpha".to_string(), perfdata(1));
+        result.perfdata.insert("mid".to_string(), perfdata(2));
+
+        assert_eq!(
+            result.message(),
+            "OK:  | 'alpha'=1;;;; 'mid'=2;;;; 'zeta'=3;;;;"
+        );
+    }
+
+    #[test]
+    fn perfdata_label_is_quoted() {
+        assert_eq!(perfdata_label("connections"), "'connections'");
+        assert_eq!(
+            perfdata_label("connections dn(cn=admin)"),
+            "'connections dn(cnadmin)'"
+        );
+        assert_eq!(perfdata_label("o'brien"), "'o''brien'");
+    }
+
+    #[test]
+    fn message_format() {
+        let mut result = Nagios {
+            return_code: ReturnCode::Warning,
+            description: Some("2 agreement problems found".to_string()),
+            long_output: vec![
+                "agreement a is red".to_string(),
+                "agreement b is red".to_string(),
+            ],
+            ..Default::default()
+        };
+        result.perfdata.insert(
+            "ratio".to_string(),
+            PerfData {
+                val: PDV(0.5),
+                unit: Some("%".to_string()),
+                warn: Some(Threshold::Limit(1.0)),
+                crit: Some("@0:0.1".parse().unwrap()),
+                min: PDV(0_u64),
+                max: PDV(100_u64),
+            },
+        );
+
+        assert_eq!(
+            result.message(),
+            "WARN: 2 agreement problems found\nagreement a is red\nagreement b is red | 'ratio'=0.5%;1;@0:0.1;0;100"
+        );
+
+        result.perfdata_in_description = true;
+        result.hide_perfdata = true;
+        result.long_output.clear();
+        assert_eq!(
+            result.message(),
+            "WARN: 2 agreement problems found (ratio=0.5%)"
+        );
+    }
+
+    #[test]
+    fn unknown_as_critical() {
+        let mut result = Nagios {
+            return_code: ReturnCode::Unknown,
+            description: Some("Connection refused".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(result.message(), "UNKNOWN: Connection refused");
+        assert_eq!(result.reported_code() as i32, 3);
+
+        result.unknown_as_critical = true;
+        assert_eq!(result.message(), "CRIT: Connection refused");
+        assert_eq!(result.reported_code() as i32, 2);
+    }
+
+    #[test]
+    fn float_is_rounded_to_the_precision() {
+        assert_eq!(PerfDataValue::Float(1.0 / 3.0).to_nagios_str(3), "0.333");
+        assert_eq!(PerfDataValue::Float(2.0 / 3.0).to_nagios_str(2), "0.67");
+        assert_eq!(PerfDataValue::Float(0.5).to_nagios_str(3), "0.5");
+        assert_eq!(PerfDataValue::Float(10.0).to_nagios_str(3), "10");
+        assert_eq!(PerfDataValue::Float(1.0 / 3.0).to_nagios_str(0), "0");
+    }
+
+    #[test]
+    fn config_precedence_is_file_env_cli() {
+        let file = r#"
+            ldap_uri = "ldap://file"
+            default_query_base = "dc=file"
+            page_size = 100
+        "#;
+        let mut config: LdapConfig = toml::from_str(file).unwrap();
+
+        let env = HashMap::from([
+            (internal::ENV_URI, "ldap://env"),
+            (internal::ENV_PAGE_SIZE, "200"),
+            (internal::ENV_BASEDN, ""),
+        ]);
+        config
+            .apply_env_from(|name| env.get(name).map(|x| x.to_string()))
+            .unwrap();
+
+        assert_eq!(config.uri, "ldap://env");
+        assert_eq!(config.page_size, 200);
+        // Empty variables do not override the file
+        assert_eq!(config.default_base, "dc=file");
+
+        let args = Cli::parse_from(["nagios-389ds-rs", "-H", "ldap://cli", "check-config"]);
+        apply_args(&mut config, &args);
+
+        assert_eq!(config.uri, "ldap://cli");
+        assert_eq!(config.page_size, 200);
+        assert_eq!(config.default_base, "dc=file");
+    }
 }
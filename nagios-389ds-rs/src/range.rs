@@ -0,0 +1,231 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+use crate::PerfDataValue;
+
+/// Threshold in the nagios range syntax (monitoring plugins guidelines):
+///
+/// * `10` - alert if outside 0..10
+/// * `10:` - alert if less than 10
+/// * `~:10` - alert if greater than 10
+/// * `10:20` - alert if outside 10..20
+/// * `@10:20` - alert if inside 10..20
+///
+/// Bounds are inclusive
+#[derive(Clone, Debug, PartialEq)]
+pub struct NagiosRange {
+    /// None means negative infinity
+    pub start: Option<f64>,
+    /// None means infinity
+    pub end: Option<f64>,
+    /// Alert if the value is inside the range (`@` prefix)
+    pub inside: bool,
+    /// Range as given by the user, reported in the perfdata
+    pub raw: String,
+}
+
+impl NagiosRange {
+    pub fn alerts(&self, value: f64) -> bool {
+        let in_range = self.start.is_none_or(|start| value >= start)
+            && self.end.is_none_or(|end| value <= end);
+
+        in_range == self.inside
+    }
+}
+
+/// Finite number of the range or threshold. `NaN` and `inf` are accepted by `f64::from_str`, but
+/// they are not valid bounds
+fn parse_number(number: &str, raw: &str) -> Result<f64> {
+    number
+        .parse::<f64>()
+        .ok()
+        .filter(|x| x.is_finite())
+        .ok_or(anyhow!("Invalid number {number:?} in {raw:?}"))
+}
+
+impl FromStr for NagiosRange {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        let number = |x: &str| parse_number(x, raw);
+
+        let (inside, range) = match raw.strip_prefix('@') {
+            Some(range) => (true, range),
+            None => (false, raw),
+        };
+
+        let (start, end) = match range.split_once(':') {
+            None => (Some(0.0), Some(number(range)?)),
+            Some((start, end)) => {
+                let start = match start {
+                    "~" => None,
+                    "" => Some(0.0),
+                    x => Some(number(x)?),
+                };
+                let end = match end {
+                    "" => None,
+                    x => Some(number(x)?),
+                };
+                (start, end)
+            }
+        };
+
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return Err(anyhow!("Range {raw:?} starts after its end"));
+            }
+        }
+
+        Ok(Self {
+            start,
+            end,
+            inside,
+            raw: raw.to_string(),
+        })
+    }
+}
+
+/// Warn or crit threshold of a check. Plain number is a limit compared as the check describes
+/// (e.g. `>=`), anything else is a `NagiosRange`. Use `0:10` for the range of the plain `10`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Threshold {
+    Limit(f64),
+    Range(NagiosRange),
+}
+
+impl Threshold {
+    /// Limit alerts if the value is greater than or equal to it
+    pub fn alerts(&self, value: f64) -> bool {
+        match self {
+            Threshold::Limit(limit) => value >= *limit,
+            Threshold::Range(range) => range.alerts(value),
+        }
+    }
+
+    /// Limit alerts if the value is less than or equal to it
+    pub fn alerts_below(&self, value: f64) -> bool {
+        match self {
+            Threshold::Limit(limit) => value <= *limit,
+            Threshold::Range(range) => range.alerts(value),
+        }
+    }
+
+    /// Warn or crit field of the perfdata: the limit or the range as given by the user
+    pub fn to_nagios_str(&self, precision: usize) -> String {
+        match self {
+            Threshold::Limit(limit) => PerfDataValue::Float(*limit).to_nagios_str(precision),
+            Threshold::Range(range) => range.raw.clone(),
+        }
+    }
+}
+
+impl FromStr for Threshold {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        if raw.contains([':', '@', '~']) {
+            Ok(Threshold::Range(raw.parse()?))
+        } else {
+            Ok(Threshold::Limit(parse_number(raw, raw)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(raw: &str) -> NagiosRange {
+        raw.parse().unwrap()
+    }
+
+    #[test]
+    fn range_end_only() {
+        let range = range("10");
+        assert!(range.alerts(-1.0));
+        assert!(!range.alerts(0.0));
+        assert!(!range.alerts(10.0));
+        assert!(range.alerts(10.5));
+    }
+
+    #[test]
+    fn range_start_only() {
+        let range = range("10:");
+        assert!(range.alerts(9.9));
+        assert!(!range.alerts(10.0));
+        assert!(!range.alerts(1e12));
+    }
+
+    #[test]
+    fn range_negative_infinity() {
+        let range = range("~:10");
+        assert!(!range.alerts(-1e12));
+        assert!(!range.alerts(10.0));
+        assert!(range.alerts(11.0));
+    }
+
+    #[test]
+    fn range_outside() {
+        let range = range("10:20");
+        assert!(range.alerts(9.0));
+        assert!(!range.alerts(10.0));
+        assert!(!range.alerts(20.0));
+        assert!(range.alerts(21.0));
+    }
+
+    #[test]
+    fn range_inside() {
+        let range = range("@10:20");
+        assert!(!range.alerts(9.0));
+        assert!(range.alerts(10.0));
+        assert!(range.alerts(15.0));
+        assert!(range.alerts(20.0));
+        assert!(!range.alerts(21.0));
+    }
+
+    #[test]
+    fn range_invalid() {
+        assert!("20:10".parse::<NagiosRange>().is_err());
+        assert!("10:x".parse::<NagiosRange>().is_err());
+        assert!("abc".parse::<NagiosRange>().is_err());
+        assert!("".parse::<NagiosRange>().is_err());
+        assert!("NaN".parse::<NagiosRange>().is_err());
+        assert!("inf".parse::<NagiosRange>().is_err());
+        assert!("~:inf".parse::<NagiosRange>().is_err());
+        assert!("-inf:10".parse::<NagiosRange>().is_err());
+    }
+
+    #[test]
+    fn range_keeps_raw_value() {
+        assert_eq!(range("@10:20").raw, "@10:20");
+    }
+
+    #[test]
+    fn threshold_limit() {
+        let threshold = "10".parse::<Threshold>().unwrap();
+        assert_eq!(threshold, Threshold::Limit(10.0));
+        assert!(threshold.alerts(10.0));
+        assert!(!threshold.alerts(9.0));
+        assert!(threshold.alerts_below(10.0));
+        assert!(!threshold.alerts_below(11.0));
+        assert_eq!(threshold.to_nagios_str(3), "10");
+    }
+
+    #[test]
+    fn threshold_range() {
+        let threshold = "@10:20".parse::<Threshold>().unwrap();
+        assert!(threshold.alerts(15.0));
+        assert!(threshold.alerts_below(15.0));
+        assert!(!threshold.alerts(25.0));
+        assert_eq!(threshold.to_nagios_str(3), "@10:20");
+    }
+
+    #[test]
+    fn threshold_invalid() {
+        assert!("NaN".parse::<Threshold>().is_err());
+        assert!("inf".parse::<Threshold>().is_err());
+        assert!("x".parse::<Threshold>().is_err());
+        assert!("20:10".parse::<Threshold>().is_err());
+    }
+}